@@ -0,0 +1,2 @@
+-- IF NOT EXISTS keeps this safe to run against databases created before migrations were introduced
+CREATE TABLE IF NOT EXISTS user_table (id INTEGER PRIMARY KEY, username TEXT NOT NULL, last_online TEXT NOT NULL, created TEXT NOT NULL, role INTEGER NOT NULL, country_code TEXT, password_hash TEXT, bio TEXT, email TEXT);
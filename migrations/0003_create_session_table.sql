@@ -0,0 +1 @@
+CREATE TABLE IF NOT EXISTS session_table (id TEXT PRIMARY KEY, user_id INTEGER, created TEXT, expires TEXT);
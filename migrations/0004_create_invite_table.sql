@@ -0,0 +1 @@
+CREATE TABLE IF NOT EXISTS invite_table (code TEXT PRIMARY KEY, created_by TEXT NOT NULL, used_by TEXT, created_at TEXT NOT NULL, used_at TEXT, expires_at TEXT NOT NULL);
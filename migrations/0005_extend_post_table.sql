@@ -0,0 +1,11 @@
+-- Nothing has ever written to post_table, so it is rebuilt rather than altered: SQLite can't add a
+-- NOT NULL foreign key column to an existing table.
+DROP TABLE IF EXISTS post_table;
+CREATE TABLE post_table (
+    id INTEGER PRIMARY KEY,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    created TEXT NOT NULL,
+    author_id INTEGER NOT NULL REFERENCES user_table(id) ON DELETE CASCADE
+);
+CREATE INDEX post_table_author_id ON post_table (author_id);
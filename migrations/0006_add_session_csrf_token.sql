@@ -0,0 +1,2 @@
+-- generated on first use by 'csrf::generate_csrf_token', so existing sessions simply start without one
+ALTER TABLE session_table ADD COLUMN csrf_token TEXT;
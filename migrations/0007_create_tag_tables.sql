@@ -0,0 +1,11 @@
+CREATE TABLE tag_table (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+CREATE TABLE post_tag_table (
+    post_id INTEGER NOT NULL REFERENCES post_table(id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tag_table(id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, tag_id)
+);
+-- the primary key already covers lookups by post, filtering by tag needs its own index
+CREATE INDEX post_tag_table_tag_id ON post_tag_table (tag_id);
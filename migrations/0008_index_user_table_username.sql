@@ -0,0 +1,3 @@
+-- lets GET /api/users seek straight to a cursor instead of scanning every row before it.
+-- Not UNIQUE: the app keeps usernames distinct, but databases from before migrations aren't guaranteed to be.
+CREATE INDEX IF NOT EXISTS user_table_username ON user_table (username);
@@ -0,0 +1 @@
+ALTER TABLE user_table ADD COLUMN website TEXT;
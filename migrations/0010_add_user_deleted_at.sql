@@ -0,0 +1,2 @@
+-- users are soft deleted so their posts and other rows keep pointing at something; NULL means not deleted
+ALTER TABLE user_table ADD COLUMN deleted_at TEXT;
@@ -0,0 +1,4 @@
+-- posts start out as drafts and only become public once published; NULL means still a draft.
+-- Posts from before drafts existed were already public, so they count as published when written.
+ALTER TABLE post_table ADD COLUMN published_at TEXT;
+UPDATE post_table SET published_at = created;
@@ -0,0 +1,5 @@
+-- how often each post has been fetched; a post's row is created on its first view
+CREATE TABLE post_view_table (
+    post_id INTEGER NOT NULL UNIQUE REFERENCES post_table(id) ON DELETE CASCADE,
+    view_count INTEGER NOT NULL DEFAULT 0
+);
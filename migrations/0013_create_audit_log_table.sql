@@ -0,0 +1,11 @@
+-- append only record of account activity, read back by admins through GET /api/admin/audit
+CREATE TABLE audit_log_table (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table(id),
+    action TEXT NOT NULL,
+    -- NULL when nobody was logged in, e.g. a sign-up
+    performed_by INTEGER REFERENCES user_table(id),
+    timestamp TEXT NOT NULL,
+    detail TEXT
+);
+CREATE INDEX audit_log_table_user_id ON audit_log_table (user_id);
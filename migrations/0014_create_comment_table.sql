@@ -0,0 +1,12 @@
+-- comments on posts. parent_id is set on replies; a reply can't itself be replied to.
+CREATE TABLE comment_table (
+    id INTEGER PRIMARY KEY,
+    post_id INTEGER NOT NULL REFERENCES post_table(id) ON DELETE CASCADE,
+    parent_id INTEGER REFERENCES comment_table(id) ON DELETE CASCADE,
+    author_id INTEGER NOT NULL REFERENCES user_table(id),
+    body TEXT NOT NULL,
+    created TEXT NOT NULL,
+    -- set by moderators, hides the comment and its replies from the public
+    flagged INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX comment_table_post_id ON comment_table (post_id);
@@ -0,0 +1,9 @@
+-- who follows whom; GET /api/feed shows a user the posts of everyone they follow
+CREATE TABLE follow_table (
+    follower_id INTEGER NOT NULL REFERENCES user_table(id),
+    following_id INTEGER NOT NULL REFERENCES user_table(id),
+    created TEXT NOT NULL,
+    PRIMARY KEY (follower_id, following_id)
+);
+-- the primary key already covers listing who a user follows, listing their followers needs its own index
+CREATE INDEX follow_table_following_id ON follow_table (following_id);
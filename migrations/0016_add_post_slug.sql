@@ -0,0 +1,4 @@
+-- URL safe post names for /posts/{slug}. Existing posts get one from their id, new ones from their title.
+ALTER TABLE post_table ADD COLUMN slug TEXT;
+UPDATE post_table SET slug = 'post-' || id;
+CREATE UNIQUE INDEX post_table_slug ON post_table (slug);
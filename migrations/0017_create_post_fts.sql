@@ -0,0 +1,14 @@
+-- Full-text index of post titles and bodies for GET /api/posts/search. It stores no text of its own,
+-- reading it from post_table instead, and the triggers keep it in step with every write there.
+CREATE VIRTUAL TABLE post_fts USING fts5(title, body, content=post_table, content_rowid=id);
+INSERT INTO post_fts (post_fts) VALUES ('rebuild');
+CREATE TRIGGER post_fts_insert AFTER INSERT ON post_table BEGIN
+    INSERT INTO post_fts (rowid, title, body) VALUES (new.id, new.title, new.body);
+END;
+CREATE TRIGGER post_fts_delete AFTER DELETE ON post_table BEGIN
+    INSERT INTO post_fts (post_fts, rowid, title, body) VALUES ('delete', old.id, old.title, old.body);
+END;
+CREATE TRIGGER post_fts_update AFTER UPDATE OF title, body ON post_table BEGIN
+    INSERT INTO post_fts (post_fts, rowid, title, body) VALUES ('delete', old.id, old.title, old.body);
+    INSERT INTO post_fts (rowid, title, body) VALUES (new.id, new.title, new.body);
+END;
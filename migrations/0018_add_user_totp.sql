@@ -0,0 +1,4 @@
+-- Two-factor authentication. A secret is stored as soon as a user starts enabling it, but it is only
+-- asked for at login once they have confirmed it with a code, which sets totp_enabled.
+ALTER TABLE user_table ADD COLUMN totp_secret TEXT;
+ALTER TABLE user_table ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Single use tokens for resetting a forgotten password, each good until expires_at.
+CREATE TABLE password_reset_table (
+    token TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table(id),
+    expires_at TEXT NOT NULL,
+    used INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX password_reset_table_user_id ON password_reset_table (user_id);
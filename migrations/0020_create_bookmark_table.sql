@@ -0,0 +1,7 @@
+-- posts users have saved to read later, listed back to them by GET /api/users/{username}/bookmarks
+CREATE TABLE bookmark_table (
+    user_id INTEGER NOT NULL REFERENCES user_table(id),
+    post_id INTEGER NOT NULL REFERENCES post_table(id) ON DELETE CASCADE,
+    created TEXT NOT NULL,
+    PRIMARY KEY (user_id, post_id)
+);
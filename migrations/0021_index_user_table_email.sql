@@ -0,0 +1,6 @@
+-- one account per email address, ignoring case the same way password resets look addresses up.
+-- Older duplicates can't be told apart, so every account but the first to have an address loses it.
+UPDATE user_table SET email = NULL WHERE email IS NOT NULL AND id NOT IN (
+    SELECT MIN(id) FROM user_table WHERE email IS NOT NULL GROUP BY email COLLATE NOCASE
+);
+CREATE UNIQUE INDEX user_table_email ON user_table (email COLLATE NOCASE);
@@ -0,0 +1,3 @@
+-- set on sessions an admin started as another user through POST /api/admin/impersonate/{username}
+ALTER TABLE session_table ADD COLUMN impersonated_by INTEGER REFERENCES user_table(id);
+CREATE INDEX session_table_impersonated_by ON session_table (impersonated_by) WHERE impersonated_by IS NOT NULL;
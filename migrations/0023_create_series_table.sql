@@ -0,0 +1,10 @@
+-- multi-part series of posts. A post belongs to at most one series, at position series_order within it.
+CREATE TABLE series_table (
+    id INTEGER PRIMARY KEY,
+    title TEXT NOT NULL,
+    slug TEXT NOT NULL UNIQUE,
+    description TEXT
+);
+ALTER TABLE post_table ADD COLUMN series_id INTEGER REFERENCES series_table(id);
+ALTER TABLE post_table ADD COLUMN series_order INTEGER;
+CREATE INDEX post_table_series_id ON post_table (series_id, series_order);
@@ -0,0 +1,14 @@
+-- responses to POST requests that carried an Idempotency-Key, replayed to retries of them for a day. A response is
+-- kept against the request it answered as well as the key, so reusing a key on another endpoint, or as someone
+-- else, doesn't replay it.
+CREATE TABLE idempotency_table (
+    key TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    -- 'user <id>' for requests made with a bearer token or session, '' for requests made without either
+    caller TEXT NOT NULL,
+    response_status INTEGER NOT NULL,
+    response_body TEXT NOT NULL,
+    created TEXT NOT NULL,
+    PRIMARY KEY (key, method, path, caller)
+);
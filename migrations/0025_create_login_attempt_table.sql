@@ -0,0 +1,6 @@
+-- failed logins per username, counted over a window starting at the first failure
+CREATE TABLE login_attempt_table (
+    username TEXT PRIMARY KEY,
+    attempts INTEGER NOT NULL,
+    window_start TEXT NOT NULL
+);
@@ -0,0 +1,11 @@
+-- long lived tokens users make for scripts, sent as 'Authorization: Token <token>'. Only a SHA-256 hash of each is kept.
+CREATE TABLE api_token_table (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table(id),
+    token_hash TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created TEXT NOT NULL,
+    last_used TEXT,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX api_token_table_user_id ON api_token_table (user_id);
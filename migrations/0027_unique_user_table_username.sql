@@ -0,0 +1,8 @@
+-- no two accounts that aren't deleted may share a name, so sign-ups racing for one can't both get it.
+-- Deleted accounts are left out of it: the app already refuses their names, and they have to stay restorable.
+-- Older duplicates can't be told apart, so every account but the first to have a name is soft deleted.
+UPDATE user_table SET deleted_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now') WHERE deleted_at IS NULL AND id NOT IN (
+    SELECT MIN(id) FROM user_table WHERE deleted_at IS NULL GROUP BY username
+);
+-- user_table_username from 0008 stays for lookups that include deleted accounts
+CREATE UNIQUE INDEX user_table_username_undeleted ON user_table (username) WHERE deleted_at IS NULL;
@@ -0,0 +1,2 @@
+// The server lives in a library so integration tests under tests/ can build and drive it.
+pub mod server;
@@ -0,0 +1,288 @@
+// Personal API tokens, for users calling the API from scripts rather than a browser. Unlike bearer tokens they
+// don't expire, so they can be revoked instead, and only a SHA-256 hash of each is stored: the token itself
+// is shown once, when it's made.
+use super::{acquire_with_timeout, csrf::ValidCsrf, error::{AppError, ErrorBody}, page_offset, page_param, pagination::Page,
+            responses::json_response, session::{to_hex, CurrentUser}, AppState};
+use anyhow::Error;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{rejection::JsonRejection, FromRequestParts, Path, Query, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::Connection;
+use std::{collections::HashMap, sync::Arc};
+use utoipa::ToSchema;
+
+const API_TOKEN_BYTES: usize = 32;
+const MAX_TOKEN_NAME_CHARS: usize = 64;
+
+/// A row of api_token_table, without the hash.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub(super) struct ApiToken {
+    pub(super) id: i64,
+    /// What the user called it, to tell their tokens apart.
+    pub(super) name: String,
+    pub(super) created: String,
+    /// When it last authenticated a request. None if it never has.
+    pub(super) last_used: Option<String>,
+    pub(super) revoked: bool
+}
+
+/// Extractor for a valid `Authorization: Token` header. Missing, unknown and revoked tokens are rejected with 401,
+/// as are the tokens of deleted users.
+#[derive(Debug)]
+pub(super) struct AuthApiToken {
+    pub(super) user_id: i64,
+    pub(super) username: String
+}
+
+impl FromRequestParts<Arc<AppState>> for AuthApiToken {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Token "))
+            .ok_or(AppError::Unauthorized)?;
+        use_token(token, state).await?.ok_or_else(|| {
+            tracing::info!("Rejected unknown or revoked API token");
+            AppError::Unauthorized
+        })
+    }
+}
+
+/// POST request handler making a new API token for the caller, from `{"name": ...}`. The token is in the response
+/// and can't be retrieved again.
+#[utoipa::path(post, path = "/api/users/{username}/tokens", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "`name`, up to 64 characters"),
+    responses(
+        (status = 201, description = "`id`, `name`, `created` and the `token` itself", body = Object),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user, result))]
+pub(super) async fn create_token(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                                 Path(username): Path<String>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let user = own_account(caller(&current_user), &username)?;
+    let Json(json_map) = result?;
+    let name = json_map.get("name").and_then(Value::as_str).map(str::trim)
+        .filter(|name| !name.is_empty() && name.chars().count() <= MAX_TOKEN_NAME_CHARS)
+        .ok_or(AppError::BadRequest(format!("'name' must be 1 to {MAX_TOKEN_NAME_CHARS} characters.")))?;
+    let token = new_api_token();
+    let (id, created) = insert_token(user, name, &token, &state).await?;
+    tracing::info!(username, id, "Created API token");
+    Ok(json_response(StatusCode::CREATED, serde_json::json!({"id": id, "name": name, "created": created, "token": token})))
+}
+
+/// API endpoint returning a page of the caller's API tokens, newest first. Authenticates with a session or an API token.
+#[utoipa::path(get, path = "/api/users/{username}/tokens", tag = "users", security(("session" = []), ("api_token" = [])),
+    params(("username" = String, Path), ("page" = Option<u32>, Query)),
+    responses(
+        (status = 200, body = Page<ApiToken>),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user, api_token))]
+pub(super) async fn list_tokens(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>,
+                                api_token: Result<AuthApiToken, AppError>, Path(username): Path<String>,
+                                Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, AppError> {
+    // a session wins over a token, so a bad Authorization header doesn't lock out a logged in browser
+    let user = match (caller(&current_user), api_token) {
+        (Some(caller), _) => own_account(Some(caller), &username)?,
+        (None, Ok(token)) => own_account(Some((token.user_id, &token.username)), &username)?,
+        (None, Err(e)) => return Err(e)
+    };
+    Ok(json_response(StatusCode::OK, select_tokens(user, page_param(&params), &state).await?))
+}
+
+/// DELETE request handler revoking one of the caller's API tokens. The row stays, marked revoked.
+#[utoipa::path(delete, path = "/api/users/{username}/tokens/{id}", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("id" = i64, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, description = "No such token, or it was already revoked", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn revoke_token(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                                 Path((username, id)): Path<(String, i64)>) -> Result<impl IntoResponse, AppError> {
+    let user = own_account(caller(&current_user), &username)?;
+    if !revoke_token_db(user, id, &state).await? {
+        return Err(AppError::NotFound(format!("No API token {id} to revoke.")));
+    }
+    tracing::info!(username, id, "Revoked API token");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The id and name of the session's user, if there is one.
+fn caller(current_user: &Option<Extension<CurrentUser>>) -> Option<(i64, &str)> {
+    current_user.as_ref().map(|Extension(user)| (user.id, user.username.as_str()))
+}
+
+/// Only lets users manage tokens on their own account, not even admins on someone else's. Returns the caller's id.
+fn own_account(caller: Option<(i64, &str)>, username: &str) -> Result<i64, AppError> {
+    match caller {
+        None => Err(AppError::Unauthorized),
+        Some((_, caller)) if caller != username => Err(AppError::Forbidden),
+        Some((id, _)) => Ok(id)
+    }
+}
+
+/// API_TOKEN_BYTES random bytes from the OS, hex encoded.
+fn new_api_token() -> String {
+    let mut bytes = [0u8; API_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// What api_token_table.token_hash holds for `token`.
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Stores a new token for `user_id`, returning its id and creation time.
+async fn insert_token(user_id: i64, name: &str, token: &str, state: &AppState) -> Result<(i64, String), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let token_hash = hash_token(token);
+    let created = Utc::now().to_rfc3339();
+    let id = sqlx::query_scalar!(r#"INSERT INTO api_token_table (user_id, token_hash, name, created) VALUES ($1, $2, $3, $4) RETURNING id AS "id!""#,
+        user_id,
+        token_hash,
+        name,
+        created)
+        .fetch_one(&mut *write_conn).await?;
+    Ok((id, created))
+}
+
+/// Looks up the user an unrevoked `token` belongs to, stamping the token as used. None if it's unknown or revoked.
+async fn use_token(token: &str, state: &AppState) -> Result<Option<AuthApiToken>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let token_hash = hash_token(token);
+    let now = Utc::now().to_rfc3339();
+    let mut transaction = write_conn.begin().await?;
+    let Some(row) = sqlx::query!(r#"SELECT api_token_table.id AS "id!", user_table.id AS "user_id!", username
+        FROM api_token_table JOIN user_table ON user_table.id = api_token_table.user_id
+        WHERE token_hash = $1 AND revoked = 0 AND user_table.deleted_at IS NULL"#,
+        token_hash)
+        .fetch_optional(&mut *transaction).await? else {
+        return Ok(None);
+    };
+    sqlx::query!("UPDATE api_token_table SET last_used = $1 WHERE id = $2", now, row.id)
+        .execute(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Some(AuthApiToken { user_id: row.user_id, username: row.username }))
+}
+
+/// The n=state.per_page tokens of user `user_id` on the given page, newest first.
+async fn select_tokens(user_id: i64, page: u32, state: &AppState) -> Result<Page<ApiToken>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut transaction = read_conn.begin().await?;
+    let offset = page_offset(page, state.per_page);
+    let tokens = sqlx::query_as!(ApiToken, r#"SELECT id AS "id!", name, created, last_used, revoked AS "revoked: bool" FROM api_token_table
+        WHERE user_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3"#,
+        user_id,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *transaction).await?;
+    let total = sqlx::query_scalar!("SELECT COUNT(*) FROM api_token_table WHERE user_id = $1", user_id)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Page::new(tokens, page, state.per_page, total))
+}
+
+/// Revokes token `id` of user `user_id`. Returns false if they have no such unrevoked token.
+async fn revoke_token_db(user_id: i64, id: i64, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let result = sqlx::query!("UPDATE api_token_table SET revoked = 1 WHERE id = $1 AND user_id = $2 AND revoked = 0", id, user_id)
+        .execute(&mut *write_conn).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{csrf::CSRF_HEADER, tests::{call, csrf_token, send, session_cookie, test_state}, Role};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::header::{CONTENT_TYPE, COOKIE}};
+
+    async fn create(state: Arc<AppState>, username: &str, cookie: &str, name: &str) -> (StatusCode, Value) {
+        let request = Request::post(format!("/api/users/{username}/tokens"))
+            .header(CONTENT_TYPE, "application/json")
+            .header(COOKIE, cookie)
+            .header(CSRF_HEADER, csrf_token(&state, cookie).await)
+            .body(Body::from(serde_json::json!({"name": name}).to_string()))
+            .unwrap();
+        let response = call(state, request).await;
+        let status = response.status();
+        (status, serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap_or_default())
+    }
+
+    async fn list_with_token(state: Arc<AppState>, username: &str, token: &str) -> (StatusCode, Value) {
+        let request = Request::get(format!("/api/users/{username}/tokens"))
+            .header(AUTHORIZATION, format!("Token {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = call(state, request).await;
+        let status = response.status();
+        (status, serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_create_list_and_revoke() {
+        let state = test_state().await;
+        let cookie = session_cookie(&state, "script_user", Role::User).await;
+        let (status, created) = create(state.clone(), "script_user", &cookie, "backup script").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let token = created["token"].as_str().unwrap().to_string();
+        assert_eq!(token.len(), 2 * API_TOKEN_BYTES);
+        // only the hash is kept
+        let stored = sqlx::query_scalar!("SELECT token_hash FROM api_token_table")
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, hash_token(&token));
+
+        let (status, page) = list_with_token(state.clone(), "script_user", &token).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&page["total"], &page["items"][0]["name"], &page["items"][0]["revoked"]),
+                   (&Value::from(1), &Value::from("backup script"), &Value::from(false)));
+        assert!(page["items"][0]["last_used"].is_string());
+        assert!(page["items"][0].get("token").is_none() && page["items"][0].get("token_hash").is_none());
+        assert_eq!(list_with_token(state.clone(), "script_user", "not_a_token").await.0, StatusCode::UNAUTHORIZED);
+
+        let uri = format!("/api/users/script_user/tokens/{}", created["id"]);
+        assert_eq!(send(state.clone(), "DELETE", &uri, Some(&cookie)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(list_with_token(state.clone(), "script_user", &token).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(state.clone(), "DELETE", &uri, Some(&cookie)).await.0, StatusCode::NOT_FOUND);
+        // the revoked token is still listed to its owner
+        let (status, body) = send(state, "GET", "/api/users/script_user/tokens", Some(&cookie)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["items"][0]["revoked"], true);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_per_account() {
+        let state = test_state().await;
+        let owner = session_cookie(&state, "script_user", Role::User).await;
+        let other = session_cookie(&state, "other_user", Role::User).await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        assert_eq!(create(state.clone(), "script_user", &other, "mine now").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(create(state.clone(), "script_user", &admin, "mine now").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(create(state.clone(), "script_user", &owner, "").await.0, StatusCode::BAD_REQUEST);
+
+        let (_, created) = create(state.clone(), "script_user", &owner, "backup script").await;
+        let token = created["token"].as_str().unwrap();
+        assert_eq!(list_with_token(state.clone(), "other_user", token).await.0, StatusCode::FORBIDDEN);
+        let uri = format!("/api/users/other_user/tokens/{}", created["id"]);
+        assert_eq!(send(state.clone(), "DELETE", &uri, Some(&other)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(state, "GET", "/api/users/script_user/tokens", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,192 @@
+// Audit trail of account activity. Rows are only ever appended, and only admins can read them back.
+use super::{acquire_with_timeout, error::{AppError, ErrorBody}, guard::AdminGuard, jobs::{Job, JobFuture}, page_offset, responses::json_response, AppState};
+use anyhow::Error;
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+/// Something that happened to an account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum AuditAction {
+    Created,
+    Login,
+    Updated,
+    Deleted,
+    Impersonate
+}
+
+impl AuditAction {
+    const ALL: [AuditAction; 5] = [AuditAction::Created, AuditAction::Login, AuditAction::Updated, AuditAction::Deleted, AuditAction::Impersonate];
+
+    /// How the action is stored in audit_log_table.action.
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Created => "created",
+            AuditAction::Login => "login",
+            AuditAction::Updated => "updated",
+            AuditAction::Deleted => "deleted",
+            AuditAction::Impersonate => "impersonate"
+        }
+    }
+}
+
+/// A row of audit_log_table.
+#[derive(Serialize, Debug, PartialEq, sqlx::FromRow, ToSchema)]
+pub(super) struct AuditEntry {
+    pub(super) id: i64,
+    // the account the action happened to
+    pub(super) user_id: i64,
+    pub(super) action: String,
+    // who did it. None when nobody was logged in, as with sign-ups and accounts made from the command line.
+    pub(super) performed_by: Option<i64>,
+    pub(super) timestamp: String,
+    pub(super) detail: Option<String>
+}
+
+/// Query string of GET /api/admin/audit.
+#[derive(Debug, Deserialize, IntoParams)]
+pub(super) struct AuditParams {
+    /// Only entries about this user.
+    user_id: Option<i64>,
+    /// Only entries with this action: `created`, `login`, `updated`, `deleted` or `impersonate`.
+    action: Option<String>,
+    page: Option<u32>
+}
+
+/// Records `action` on `user_id` through `conn`, so callers can make it part of their own transaction.
+pub(super) async fn append_audit(conn: &mut SqliteConnection, user_id: i64, action: AuditAction, performed_by: Option<i64>,
+                                 detail: Option<&str>) -> Result<(), Error> {
+    let timestamp = Utc::now().to_rfc3339();
+    let action = action.as_str();
+    sqlx::query!("INSERT INTO audit_log_table (user_id, action, performed_by, timestamp, detail) VALUES ($1, $2, $3, $4, $5)",
+        user_id,
+        action,
+        performed_by,
+        timestamp,
+        detail)
+        .execute(conn).await?;
+    Ok(())
+}
+
+/// Background job appending an audit entry, for actions that aren't part of a transaction of their own.
+/// Actions that are should keep calling `append_audit` inside it, so the entry can't outlive a rollback.
+#[derive(Debug)]
+pub(super) struct AuditJob {
+    pub(super) user_id: i64,
+    pub(super) action: AuditAction,
+    pub(super) performed_by: Option<i64>,
+    pub(super) detail: Option<String>
+}
+
+impl Job for AuditJob {
+    fn execute(&self, state: Arc<AppState>) -> JobFuture<'_> {
+        Box::pin(async move {
+            let result = async {
+                let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+                append_audit(&mut write_conn, self.user_id, self.action, self.performed_by, self.detail.as_deref()).await
+            }.await;
+            if let Err(e) = result {
+                tracing::error!(user_id = self.user_id, action = self.action.as_str(), "Failed to write audit entry: {:#}", e);
+            }
+        })
+    }
+}
+
+/// API endpoint returning a page of the audit log, newest first, optionally filtered by user and action. Admin only.
+#[utoipa::path(get, path = "/api/admin/audit", tag = "users", security(("session" = [])), params(AuditParams),
+    responses(
+        (status = 200, body = Vec<AuditEntry>),
+        (status = 400, description = "Unknown action or invalid query", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, _admin))]
+pub(super) async fn get_audit_log(State(state): State<Arc<AppState>>, _admin: AdminGuard, params: Result<Query<AuditParams>, QueryRejection>)
+                                  -> Result<impl IntoResponse, AppError> {
+    let Query(params) = params?;
+    let action = params.action.as_deref()
+        .map(|action| AuditAction::ALL.into_iter().find(|known| known.as_str() == action)
+            .ok_or(AppError::BadRequest(format!("Unknown audit action '{action}'."))))
+        .transpose()?;
+    let entries = select_audit_entries(params.user_id, action, params.page.unwrap_or(1).max(1), &state).await?;
+    Ok(json_response(StatusCode::OK, entries))
+}
+
+/// One page of state.per_page audit entries, newest first.
+async fn select_audit_entries(user_id: Option<i64>, action: Option<AuditAction>, page: u32, state: &AppState) -> Result<Vec<AuditEntry>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let action = action.map(AuditAction::as_str);
+    let offset = page_offset(page, state.per_page);
+    Ok(sqlx::query_as!(AuditEntry, r#"SELECT id AS "id!", user_id, action, performed_by, timestamp, detail FROM audit_log_table
+        WHERE ($1 IS NULL OR user_id = $1) AND ($2 IS NULL OR action = $2)
+        ORDER BY id DESC LIMIT $3 OFFSET $4"#,
+        user_id,
+        action,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{insert_user, jobs::tests::finish_jobs, tests::{post_json, send, session_cookie, test_state}, update_user_db, Role, User, UserUpdate};
+    use serde_json::Value;
+
+    async fn audit_log(state: Arc<AppState>, query: &str, cookie: &str) -> (StatusCode, Value) {
+        let (status, body) = send(state, "GET", &format!("/api/admin/audit{query}"), Some(cookie)).await;
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_creating_a_user_is_audited() {
+        let state = test_state().await;
+        insert_user(&User::new("audited_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        let entries = select_audit_entries(None, None, 1, &state).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = 'audited_user'"#)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!((entries[0].user_id, entries[0].action.as_str(), entries[0].performed_by), (id, "created", None));
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_endpoint() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "audited_user", "password": "correct horse"})).await;
+        post_json(state.clone(), "/api/login", serde_json::json!({"username": "audited_user", "password": "correct horse"})).await;
+        // the login is audited in the background, and has to land before the update to keep the order below
+        finish_jobs(&state).await;
+        let update = UserUpdate { bio: Some("hello".to_string()), website: Some("https://example.com".to_string()), ..UserUpdate::default() };
+        update_user_db("audited_user", &update, None, &State(state.clone())).await.unwrap();
+        send(state.clone(), "DELETE", "/api/users/audited_user", Some(&admin)).await;
+
+        let (status, entries) = audit_log(state.clone(), "", &admin).await;
+        assert_eq!(status, StatusCode::OK);
+        let actions: Vec<&str> = entries.as_array().unwrap().iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+        // newest first, with the admin's own sign-up at the bottom
+        assert_eq!(actions, ["deleted", "updated", "login", "created", "created"]);
+        let user_id = entries[0]["user_id"].as_i64().unwrap();
+        assert_eq!(entries[0]["performed_by"], entries[4]["user_id"]);
+        assert_eq!(entries[1]["detail"], "bio, website");
+        assert_eq!(entries[2]["performed_by"].as_i64(), Some(user_id));
+
+        let (_, entries) = audit_log(state.clone(), &format!("?user_id={user_id}&action=created"), &admin).await;
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["performed_by"], Value::Null);
+        assert_eq!(audit_log(state.clone(), "?action=renamed", &admin).await.0, StatusCode::BAD_REQUEST);
+
+        let user = session_cookie(&state, "plain_user", Role::User).await;
+        assert_eq!(audit_log(state.clone(), "", &user).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state, "GET", "/api/admin/audit", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,173 @@
+// Bookmarks, for logged in users saving published posts to read later. A user's bookmarks are private:
+// only they and admins can list them.
+use super::{acquire_with_timeout, comments::post_is_published, csrf::ValidCsrf, error::{AppError, ErrorBody}, follows::select_user_id,
+            page_offset, page_param, pagination::Page, posts::Post, responses::json_response, session::CurrentUser, AppState, Role};
+use anyhow::Error;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use chrono::Utc;
+use sqlx::Connection;
+use std::{collections::HashMap, sync::Arc};
+
+/// POST request handler bookmarking a published post for the caller.
+#[utoipa::path(post, path = "/api/posts/{id}/bookmark", tag = "posts", security(("session" = [])), params(("id" = i64, Path)),
+    responses(
+        (status = 201, description = "Bookmarked"),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Already bookmarked", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn bookmark(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                             Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    let Extension(user) = current_user.ok_or(AppError::Unauthorized)?;
+    if !post_is_published(id, &state).await? {
+        return Err(AppError::NotFound(format!("Post {id} does not exist.")));
+    }
+    if !insert_bookmark(user.id, id, &state).await? {
+        return Err(AppError::Conflict(format!("Post {id} is already bookmarked.")));
+    }
+    tracing::info!(username = user.username, "Bookmarked post");
+    Ok(StatusCode::CREATED)
+}
+
+/// DELETE request handler removing one of the caller's bookmarks.
+#[utoipa::path(delete, path = "/api/posts/{id}/bookmark", tag = "posts", security(("session" = [])), params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "Bookmark removed"),
+        (status = 401, body = ErrorBody),
+        (status = 404, description = "The post isn't bookmarked", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn remove_bookmark(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                                    Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    let Extension(user) = current_user.ok_or(AppError::Unauthorized)?;
+    if !delete_bookmark(user.id, id, &state).await? {
+        return Err(AppError::NotFound(format!("Post {id} is not bookmarked.")));
+    }
+    tracing::info!(username = user.username, "Removed bookmark");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// API endpoint returning a page of the published posts `username` has bookmarked, most recently bookmarked first.
+/// Only that user and admins can see it.
+#[utoipa::path(get, path = "/api/users/{username}/bookmarks", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("page" = Option<u32>, Query)),
+    responses(
+        (status = 200, body = Page<Post>),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn bookmarks(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, Path(username): Path<String>,
+                              Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, AppError> {
+    let Extension(user) = current_user.ok_or(AppError::Unauthorized)?;
+    if user.username != username && user.role != Role::Admin {
+        return Err(AppError::Forbidden);
+    }
+    let id = select_user_id(&username, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    Ok(json_response(StatusCode::OK, select_bookmarks(id, page_param(&params), &state).await?))
+}
+
+/// Bookmarks post `post_id` for `user_id`. Returns false if it already was.
+async fn insert_bookmark(user_id: i64, post_id: i64, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let created = Utc::now().to_rfc3339();
+    let result = sqlx::query!("INSERT OR IGNORE INTO bookmark_table (user_id, post_id, created) VALUES ($1, $2, $3)",
+        user_id,
+        post_id,
+        created)
+        .execute(&mut *write_conn).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes `user_id`'s bookmark of post `post_id`. Returns false if there wasn't one.
+async fn delete_bookmark(user_id: i64, post_id: i64, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let result = sqlx::query!("DELETE FROM bookmark_table WHERE user_id = $1 AND post_id = $2", user_id, post_id)
+        .execute(&mut *write_conn).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// The n=state.per_page published posts on the given page that user `id` has bookmarked, most recently bookmarked first.
+async fn select_bookmarks(id: i64, page: u32, state: &AppState) -> Result<Page<Post>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut transaction = read_conn.begin().await?;
+    let offset = page_offset(page, state.per_page);
+    let posts = sqlx::query_as!(Post, r#"SELECT post_table.id AS "id!", title, body, post_table.created, author_id, published_at, slug AS "slug!"
+        FROM bookmark_table JOIN post_table ON post_table.id = bookmark_table.post_id
+        WHERE user_id = $1 AND published_at IS NOT NULL
+        ORDER BY bookmark_table.created DESC, post_table.id DESC LIMIT $2 OFFSET $3"#,
+        id,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *transaction).await?;
+    let total = sqlx::query_scalar!("SELECT COUNT(*) FROM bookmark_table JOIN post_table ON post_table.id = bookmark_table.post_id
+        WHERE user_id = $1 AND published_at IS NOT NULL",
+        id)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Page::new(posts, page, state.per_page, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{posts::tests::published_post, tests::{bearer, send, session_cookie, test_state}};
+    use serde_json::Value;
+
+    async fn bookmarked_titles(state: Arc<AppState>, username: &str, cookie: &str) -> (StatusCode, Vec<String>) {
+        let (status, body) = send(state, "GET", &format!("/api/users/{username}/bookmarks"), Some(cookie)).await;
+        let titles = serde_json::from_slice::<Value>(&body).ok()
+            .and_then(|page| page["items"].as_array().map(|posts| posts.iter().map(|post| post["title"].as_str().unwrap().to_string()).collect()))
+            .unwrap_or_default();
+        (status, titles)
+    }
+
+    #[tokio::test]
+    async fn test_bookmark_and_remove() {
+        let state = test_state().await;
+        let reader = session_cookie(&state, "reader_user", Role::User).await;
+        let author = bearer(&state, "bookmarked_author", Role::User).await;
+        let first = published_post(&state, &author, serde_json::json!({"title": "First", "body": "text"})).await["id"].as_i64().unwrap();
+        let second = published_post(&state, &author, serde_json::json!({"title": "Second", "body": "text"})).await["id"].as_i64().unwrap();
+        let uri = |id: i64| format!("/api/posts/{id}/bookmark");
+
+        assert_eq!(send(state.clone(), "POST", &uri(first), None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(state.clone(), "POST", &uri(first + second), Some(&reader)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(state.clone(), "POST", &uri(first), Some(&reader)).await.0, StatusCode::CREATED);
+        assert_eq!(send(state.clone(), "POST", &uri(first), Some(&reader)).await.0, StatusCode::CONFLICT);
+        assert_eq!(send(state.clone(), "POST", &uri(second), Some(&reader)).await.0, StatusCode::CREATED);
+        let (status, titles) = bookmarked_titles(state.clone(), "reader_user", &reader).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(titles.len(), 2);
+
+        assert_eq!(send(state.clone(), "DELETE", &uri(first), Some(&reader)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(state.clone(), "DELETE", &uri(first), Some(&reader)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(bookmarked_titles(state, "reader_user", &reader).await.1, ["Second"]);
+    }
+
+    #[tokio::test]
+    async fn test_bookmarks_are_private() {
+        let state = test_state().await;
+        let reader = session_cookie(&state, "reader_user", Role::User).await;
+        let author = bearer(&state, "bookmarked_author", Role::User).await;
+        let id = published_post(&state, &author, serde_json::json!({"title": "Saved", "body": "text"})).await["id"].as_i64().unwrap();
+        send(state.clone(), "POST", &format!("/api/posts/{id}/bookmark"), Some(&reader)).await;
+
+        let other = session_cookie(&state, "nosy_user", Role::User).await;
+        assert_eq!(bookmarked_titles(state.clone(), "reader_user", &other).await.0, StatusCode::FORBIDDEN);
+        let moderator = session_cookie(&state, "nosy_moderator", Role::Mod).await;
+        assert_eq!(bookmarked_titles(state.clone(), "reader_user", &moderator).await.0, StatusCode::FORBIDDEN);
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        assert_eq!(bookmarked_titles(state.clone(), "reader_user", &admin).await, (StatusCode::OK, vec!["Saved".to_string()]));
+        assert_eq!(bookmarked_titles(state.clone(), "nobody_here", &admin).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(state, "GET", "/api/users/reader_user/bookmarks", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,92 @@
+// Command line interface. Running the binary without a subcommand serves the site, so existing
+// deployments that start it bare keep working.
+use clap::{Args, Parser, Subcommand};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 3000;
+
+#[derive(Parser, Debug, PartialEq)]
+#[command(version, about = "Personal site web server")]
+pub(super) struct Cli {
+    #[command(subcommand)]
+    pub(super) command: Option<Command>
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub(super) enum Command {
+    /// Serve the site. This is the default.
+    Serve(ServeArgs),
+    /// Create an admin account, then exit.
+    CreateAdmin {
+        #[arg(long)]
+        username: String,
+        #[arg(long)]
+        password: String
+    },
+    /// Run any pending database migrations, then exit.
+    Migrate
+}
+
+/// Overrides for how and where the server runs.
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long, default_value_t = DEFAULT_HOST)]
+    pub host: IpAddr,
+    /// Port to listen on.
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    pub port: u16,
+    /// Log filter such as 'debug' or 'checkout_webserver=trace'. Takes precedence over RUST_LOG.
+    #[arg(long)]
+    pub log_level: Option<String>
+}
+
+impl Default for ServeArgs {
+    fn default() -> Self {
+        ServeArgs { host: DEFAULT_HOST, port: DEFAULT_PORT, log_level: None }
+    }
+}
+
+impl ServeArgs {
+    /// The socket address to bind.
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("Checkout_Webserver").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_parse_serve() {
+        assert_eq!(parse(&[]).unwrap().command, None);
+        assert_eq!(parse(&["serve"]).unwrap().command, Some(Command::Serve(ServeArgs::default())));
+        let cli = parse(&["serve", "--host", "127.0.0.1", "--port", "8080", "--log-level", "debug"]).unwrap();
+        let Some(Command::Serve(args)) = cli.command else {
+            panic!("expected the serve subcommand, got {:?}", cli.command);
+        };
+        assert_eq!(args.addr(), SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(args.log_level.as_deref(), Some("debug"));
+        assert!(parse(&["serve", "--port", "not_a_port"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_create_admin() {
+        let cli = parse(&["create-admin", "--username", "site_admin", "--password", "hunter2_hunter2"]).unwrap();
+        assert_eq!(cli.command, Some(Command::CreateAdmin { username: "site_admin".to_string(), password: "hunter2_hunter2".to_string() }));
+        assert!(parse(&["create-admin", "--username", "site_admin"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_migrate() {
+        assert_eq!(parse(&["migrate"]).unwrap().command, Some(Command::Migrate));
+        assert!(parse(&["migrate", "--port", "1"]).is_err());
+        assert!(parse(&["unknown"]).is_err());
+    }
+}
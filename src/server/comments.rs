@@ -0,0 +1,314 @@
+// Comments on published posts. Bearer token holders can comment and reply, a comment's author or an
+// admin can remove it, and moderators can flag it to hide it from the public. Threads are two levels
+// deep: a comment and its replies.
+use super::{acquire_with_timeout, csrf::ValidCsrf, error::{AppError, ErrorBody}, guard::ModGuard, jwt::AuthBearer,
+            responses::json_response, AppState, Role};
+use anyhow::Error;
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+const MAX_COMMENT_CHARS: usize = 2_000;
+
+/// A row of comment_table.
+#[derive(Serialize, Debug, PartialEq, sqlx::FromRow, ToSchema)]
+pub(super) struct Comment {
+    pub(super) id: i64,
+    pub(super) post_id: i64,
+    // None for top level comments, the replied to comment's id for replies
+    pub(super) parent_id: Option<i64>,
+    pub(super) author_id: i64,
+    pub(super) body: Box<str>,
+    pub(super) created: Box<str>,
+    pub(super) flagged: bool
+}
+
+/// A top level comment and the replies to it, oldest first.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub(super) struct CommentThread {
+    #[serde(flatten)]
+    pub(super) comment: Comment,
+    pub(super) replies: Vec<Comment>
+}
+
+/// API endpoint returning a published post's unflagged comments as threads, oldest first.
+#[utoipa::path(get, path = "/api/posts/{id}/comments", tag = "comments", params(("id" = i64, Path)),
+    responses(
+        (status = 200, body = Vec<CommentThread>),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state))]
+pub(super) async fn list_comments(State(state): State<Arc<AppState>>, Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    if !post_is_published(id, &state).await? {
+        return Err(AppError::NotFound(format!("Post {id} does not exist.")));
+    }
+    Ok(json_response(StatusCode::OK, build_threads(select_visible_comments(id, &state).await?)))
+}
+
+/// POST request handler commenting on a published post as the caller, or replying to one of its top level comments.
+#[utoipa::path(post, path = "/api/posts/{id}/comments", tag = "comments", security(("bearer" = [])), params(("id" = i64, Path)),
+    request_body(content = Object, description = "`body`, plus `parent_id` when replying"),
+    responses(
+        (status = 201, body = Comment),
+        (status = 400, description = "Invalid body, or the parent is missing or is itself a reply", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth, result))]
+pub(super) async fn create_comment(State(state): State<Arc<AppState>>, auth: AuthBearer, Path(id): Path<i64>,
+                                   result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let (body, parent_id) = comment_fields_check(&json_map)?;
+    if !post_is_published(id, &state).await? {
+        return Err(AppError::NotFound(format!("Post {id} does not exist.")));
+    }
+    if let Some(parent_id) = parent_id {
+        match select_comment(parent_id, &state).await? {
+            Some(parent) if parent.post_id != id => return Err(AppError::BadRequest(format!("Comment {parent_id} is not on post {id}."))),
+            Some(parent) if parent.parent_id.is_some() => return Err(AppError::BadRequest("Replies can't be replied to.".to_string())),
+            Some(_) => {}
+            None => return Err(AppError::BadRequest(format!("Comment {parent_id} does not exist.")))
+        }
+    }
+    let comment = insert_comment(id, parent_id, auth.user_id, &body, &state).await?;
+    tracing::info!(comment_id = comment.id, author_id = auth.user_id, "Created comment");
+    Ok(json_response(StatusCode::CREATED, comment))
+}
+
+/// DELETE request handler removing a comment along with its replies. Author or admin only.
+#[utoipa::path(delete, path = "/api/comments/{id}", tag = "comments", security(("bearer" = [])), params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth))]
+pub(super) async fn delete_comment(State(state): State<Arc<AppState>>, auth: AuthBearer, Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    match select_comment(id, &state).await? {
+        None => return Err(AppError::NotFound(format!("Comment {id} does not exist."))),
+        Some(comment) if comment.author_id != auth.user_id && auth.claims.role != Role::Admin => return Err(AppError::Forbidden),
+        Some(_) => {}
+    }
+    if !delete_comment_db(id, &state).await? {
+        return Err(AppError::NotFound(format!("Comment {id} does not exist.")));
+    }
+    tracing::info!("Deleted comment");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST request handler flagging a comment, which hides it and its replies from the public. Mods and admins only.
+#[utoipa::path(post, path = "/api/admin/comments/{id}/flag", tag = "comments", security(("session" = [])), params(("id" = i64, Path)),
+    responses(
+        (status = 200, body = Comment),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, moderator))]
+pub(super) async fn flag_comment(State(state): State<Arc<AppState>>, ModGuard(moderator): ModGuard, _csrf: ValidCsrf,
+                                 Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    let comment = flag_comment_db(id, &state).await?
+        .ok_or(AppError::NotFound(format!("Comment {id} does not exist.")))?;
+    tracing::info!(moderator = moderator.username, "Flagged comment");
+    Ok(json_response(StatusCode::OK, comment))
+}
+
+/// Validates a comment payload, returning its trimmed `body` and optional `parent_id`.
+fn comment_fields_check(json_map: &Value) -> Result<(String, Option<i64>), AppError> {
+    let body = match json_map.get("body") {
+        Some(Value::String(body)) if (1..=MAX_COMMENT_CHARS).contains(&body.trim().chars().count()) => body.trim().to_string(),
+        _ => return Err(AppError::BadRequest(format!("'body' must be a string of 1 to {MAX_COMMENT_CHARS} characters.")))
+    };
+    let parent_id = match json_map.get("parent_id") {
+        None | Some(Value::Null) => None,
+        Some(parent_id) => Some(parent_id.as_i64().ok_or(AppError::BadRequest("'parent_id' must be an integer.".to_string()))?)
+    };
+    Ok((body, parent_id))
+}
+
+/// Groups `comments`, ordered oldest first, into threads. Replies whose parent isn't among them are dropped,
+/// so a flagged comment takes its replies with it.
+fn build_threads(comments: Vec<Comment>) -> Vec<CommentThread> {
+    let (top_level, replies): (Vec<_>, Vec<_>) = comments.into_iter().partition(|comment| comment.parent_id.is_none());
+    let mut threads: Vec<CommentThread> = top_level.into_iter().map(|comment| CommentThread { comment, replies: Vec::new() }).collect();
+    for reply in replies {
+        if let Some(thread) = threads.iter_mut().find(|thread| Some(thread.comment.id) == reply.parent_id) {
+            thread.replies.push(reply);
+        }
+    }
+    threads
+}
+
+/// Whether post `id` exists and has been published.
+pub(super) async fn post_is_published(id: i64, state: &AppState) -> Result<bool, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_scalar!(r#"SELECT COUNT(*) > 0 AS "published!: bool" FROM post_table WHERE id = $1 AND published_at IS NOT NULL"#, id)
+        .fetch_one(&mut *read_conn).await?)
+}
+
+/// Find a given Comment in the database by id, flagged or not.
+async fn select_comment(id: i64, state: &AppState) -> Result<Option<Comment>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Comment, r#"SELECT id, post_id, parent_id, author_id, body, created, flagged AS "flagged: bool" FROM comment_table
+        WHERE id = $1"#, id)
+        .fetch_optional(&mut *read_conn).await?)
+}
+
+/// Every unflagged comment on post `post_id`, oldest first.
+async fn select_visible_comments(post_id: i64, state: &AppState) -> Result<Vec<Comment>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Comment, r#"SELECT id AS "id!", post_id, parent_id, author_id, body, created, flagged AS "flagged: bool" FROM comment_table
+        WHERE post_id = $1 AND flagged = 0 ORDER BY id"#, post_id)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// Inserts a comment and returns it as stored.
+async fn insert_comment(post_id: i64, parent_id: Option<i64>, author_id: i64, body: &str, state: &AppState) -> Result<Comment, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let created = Utc::now().to_rfc3339();
+    Ok(sqlx::query_as!(Comment, r#"INSERT INTO comment_table (post_id, parent_id, author_id, body, created) VALUES ($1, $2, $3, $4, $5)
+        RETURNING id AS "id!", post_id, parent_id, author_id, body, created, flagged AS "flagged: bool""#,
+        post_id,
+        parent_id,
+        author_id,
+        body,
+        created)
+        .fetch_one(&mut *write_conn).await?)
+}
+
+/// Deletes a comment, and through the foreign key its replies. Returns false if there was no such comment.
+async fn delete_comment_db(id: i64, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let delete_statement = sqlx::query!("DELETE FROM comment_table WHERE id = $1", id)
+        .execute(&mut *write_conn).await?;
+    Ok(delete_statement.rows_affected() == 1)
+}
+
+/// Flags a comment and returns it. None if there was no such comment.
+async fn flag_comment_db(id: i64, state: &AppState) -> Result<Option<Comment>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Comment, r#"UPDATE comment_table SET flagged = 1 WHERE id = $1
+        RETURNING id AS "id!", post_id, parent_id, author_id, body, created, flagged AS "flagged: bool""#, id)
+        .fetch_optional(&mut *write_conn).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{posts::tests::{published_post, send_json}, tests::{bearer, get_request, send, session_cookie, test_state}};
+
+    fn comment(id: i64, parent_id: Option<i64>) -> Comment {
+        Comment { id, post_id: 1, parent_id, author_id: 1, body: "text".into(), created: "2025-06-01T12:00:00+00:00".into(), flagged: false }
+    }
+
+    #[test]
+    fn test_comment_fields_check() {
+        assert_eq!(comment_fields_check(&serde_json::json!({"body": " hi "})).unwrap(), ("hi".to_string(), None));
+        assert_eq!(comment_fields_check(&serde_json::json!({"body": "hi", "parent_id": 3})).unwrap(), ("hi".to_string(), Some(3)));
+        assert!(comment_fields_check(&serde_json::json!({"body": "   "})).is_err());
+        assert!(comment_fields_check(&serde_json::json!({"body": "c".repeat(MAX_COMMENT_CHARS + 1)})).is_err());
+        assert!(comment_fields_check(&serde_json::json!({"body": "hi", "parent_id": "3"})).is_err());
+        assert!(comment_fields_check(&serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn test_build_threads() {
+        let threads = build_threads(vec![comment(1, None), comment(2, None), comment(3, Some(1)), comment(4, Some(9)), comment(5, Some(1))]);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].replies.iter().map(|reply| reply.id).collect::<Vec<_>>(), [3, 5]);
+        assert!(threads[1].replies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_comment_threads() {
+        let state = test_state().await;
+        let author = bearer(&state, "comment_author", Role::User).await;
+        // drafts can't be commented on or have their comments read
+        let (_, draft) = send_json(state.clone(), "POST", "/api/posts", &author, serde_json::json!({"title": "Draft", "body": "text"})).await;
+        let draft_comments_uri = format!("/api/posts/{}/comments", draft["id"]);
+        let (status, _) = send_json(state.clone(), "POST", &draft_comments_uri, &author, serde_json::json!({"body": "first"})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(get_request(state.clone(), &draft_comments_uri).await.0, StatusCode::NOT_FOUND);
+        let post = published_post(&state, &author, serde_json::json!({"title": "Title", "body": "text"})).await;
+        let comments_uri = format!("/api/posts/{}/comments", post["id"]);
+
+        let (status, top) = send_json(state.clone(), "POST", &comments_uri, &author, serde_json::json!({"body": "first"})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, reply) = send_json(state.clone(), "POST", &comments_uri, &author,
+                                        serde_json::json!({"body": "reply", "parent_id": top["id"]})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = send_json(state.clone(), "POST", &comments_uri, &author,
+                                    serde_json::json!({"body": "too deep", "parent_id": reply["id"]})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send_json(state.clone(), "POST", &comments_uri, &author, serde_json::json!({"body": "orphan", "parent_id": 999})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send_json(state.clone(), "POST", &comments_uri, "Bearer nonsense", serde_json::json!({"body": "anonymous"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = get_request(state.clone(), &comments_uri).await;
+        assert_eq!(status, StatusCode::OK);
+        let threads: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(threads.as_array().unwrap().len(), 1);
+        assert_eq!((&threads[0]["body"], &threads[0]["replies"][0]["body"]), (&serde_json::json!("first"), &serde_json::json!("reply")));
+    }
+
+    #[tokio::test]
+    async fn test_flagged_comments_are_hidden() {
+        let state = test_state().await;
+        let author = bearer(&state, "comment_author", Role::User).await;
+        let moderator = session_cookie(&state, "mod_user", Role::Mod).await;
+        let user = session_cookie(&state, "plain_user", Role::User).await;
+        let post = published_post(&state, &author, serde_json::json!({"title": "Title", "body": "text"})).await;
+        let comments_uri = format!("/api/posts/{}/comments", post["id"]);
+        let (_, kept) = send_json(state.clone(), "POST", &comments_uri, &author, serde_json::json!({"body": "kept"})).await;
+        let (_, hidden) = send_json(state.clone(), "POST", &comments_uri, &author, serde_json::json!({"body": "hidden"})).await;
+        send_json(state.clone(), "POST", &comments_uri, &author, serde_json::json!({"body": "reply to hidden", "parent_id": hidden["id"]})).await;
+
+        let flag_uri = format!("/api/admin/comments/{}/flag", hidden["id"]);
+        assert_eq!(send(state.clone(), "POST", &flag_uri, Some(&user)).await.0, StatusCode::FORBIDDEN);
+        let (status, body) = send(state.clone(), "POST", &flag_uri, Some(&moderator)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["flagged"], true);
+        assert_eq!(send(state.clone(), "POST", "/api/admin/comments/999/flag", Some(&moderator)).await.0, StatusCode::NOT_FOUND);
+
+        let (_, body) = get_request(state.clone(), &comments_uri).await;
+        let threads: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(threads, serde_json::json!([{
+            "id": kept["id"], "post_id": post["id"], "parent_id": null, "author_id": kept["author_id"], "body": "kept",
+            "created": kept["created"], "flagged": false, "replies": []
+        }]));
+    }
+
+    #[tokio::test]
+    async fn test_delete_comment() {
+        let state = test_state().await;
+        let author = bearer(&state, "comment_author", Role::User).await;
+        let other = bearer(&state, "other_user", Role::User).await;
+        let admin = bearer(&state, "admin_user", Role::Admin).await;
+        let post = published_post(&state, &author, serde_json::json!({"title": "Title", "body": "text"})).await;
+        let comments_uri = format!("/api/posts/{}/comments", post["id"]);
+        let (_, first) = send_json(state.clone(), "POST", &comments_uri, &author, serde_json::json!({"body": "first"})).await;
+        send_json(state.clone(), "POST", &comments_uri, &other, serde_json::json!({"body": "reply", "parent_id": first["id"]})).await;
+        let (_, second) = send_json(state.clone(), "POST", &comments_uri, &other, serde_json::json!({"body": "second"})).await;
+
+        let first_uri = format!("/api/comments/{}", first["id"]);
+        assert_eq!(send_json(state.clone(), "DELETE", &first_uri, &other, Value::Null).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send_json(state.clone(), "DELETE", &first_uri, &author, Value::Null).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send_json(state.clone(), "DELETE", &first_uri, &author, Value::Null).await.0, StatusCode::NOT_FOUND);
+        // the reply went with its parent
+        let (_, body) = get_request(state.clone(), &comments_uri).await;
+        let threads: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(threads.as_array().unwrap().iter().map(|thread| &thread["id"]).collect::<Vec<_>>(), [&second["id"]]);
+        let second_uri = format!("/api/comments/{}", second["id"]);
+        assert_eq!(send_json(state, "DELETE", &second_uri, &admin, Value::Null).await.0, StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,78 @@
+// Conditional GET support. JSON responses carry an ETag derived from their body, and a client sending
+// that ETag back in If-None-Match gets an empty 304 instead of the same body again.
+use super::{error::AppError, responses::JSON, session::to_hex};
+use anyhow::Error;
+use axum::{
+    http::{header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH}, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Serializes `value` as a JSON response with a strong ETag (the SHA-256 hex of the body),
+/// or returns 304 Not Modified if the request's If-None-Match already names that ETag.
+pub(super) fn conditional_response<T: Serialize>(headers: &HeaderMap, value: &T) -> Result<Response, AppError> {
+    let body = serde_json::to_vec(value).map_err(Error::from)?;
+    let etag = format!("\"{}\"", to_hex(&Sha256::digest(&body)));
+    if if_none_match(headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+    Ok((StatusCode::OK, [(ETAG, etag), (CONTENT_TYPE, JSON.to_string())], body).into_response())
+}
+
+/// Whether any If-None-Match header lists `etag`, or is '*'.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        // GET requests compare weakly, so a W/ prefix doesn't matter
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == etag || candidate == "*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{insert_user, tests::{call, test_state}, User, Role};
+    use axum::{body::{to_bytes, Body}, extract::{Request, State}};
+
+    #[test]
+    fn test_if_none_match() {
+        let with = |value: &str| HeaderMap::from_iter([(IF_NONE_MATCH, value.parse().unwrap())]);
+        assert!(if_none_match(&with("\"abc\""), "\"abc\""));
+        assert!(if_none_match(&with("\"x\", W/\"abc\""), "\"abc\""));
+        assert!(if_none_match(&with("*"), "\"abc\""));
+        assert!(!if_none_match(&with("\"abd\""), "\"abc\""));
+        assert!(!if_none_match(&HeaderMap::new(), "\"abc\""));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get() {
+        let state = test_state().await;
+        insert_user(&User::new("etag_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        let get = |etag: Option<&str>| {
+            let mut request = Request::get("/api/users");
+            if let Some(etag) = etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = call(state.clone(), get(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(etag, format!("\"{}\"", to_hex(&Sha256::digest(&body))));
+
+        let response = call(state.clone(), get(Some(&etag))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+        assert!(to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+
+        // once the data changes the old ETag is stale
+        insert_user(&User::new("another_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        let response = call(state, get(Some(&etag))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+    }
+}
@@ -0,0 +1,127 @@
+// Cross-origin access for browser front ends served from somewhere else. Origins come from CORS_ALLOWED_ORIGINS;
+// listed ones may send credentials (the session cookie), while '*' opens the API to any origin without them.
+use super::{csrf::CSRF_HEADER, error::error_response};
+use anyhow::Error;
+use axum::{
+    extract::{Request, State},
+    http::{header::{ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION, CONTENT_TYPE, ORIGIN}, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Origins other than the site's own that browsers may call it from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) enum CorsOrigins {
+    /// No cross-origin access, and no CORS headers at all.
+    #[default]
+    None,
+    /// Only these origins, which may send credentials.
+    List(Vec<HeaderValue>),
+    /// Any origin, never with credentials.
+    Any
+}
+
+impl CorsOrigins {
+    /// Whether a request from `origin` may go ahead.
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        match self {
+            CorsOrigins::None => false,
+            CorsOrigins::List(origins) => origins.contains(origin),
+            CorsOrigins::Any => true
+        }
+    }
+}
+
+/// Parses CORS_ALLOWED_ORIGINS: `*`, or comma separated origins like `https://example.com`.
+pub(super) fn parse_allowed_origins(value: &str) -> Result<CorsOrigins, Error> {
+    if value.trim() == "*" {
+        return Ok(CorsOrigins::Any);
+    }
+    let origins = value.split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(HeaderValue::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(if origins.is_empty() { CorsOrigins::None } else { CorsOrigins::List(origins) })
+}
+
+/// The layer answering preflights and adding CORS headers for `origins`. None when cross-origin access is off.
+pub(super) fn cors_layer(origins: &CorsOrigins) -> Option<CorsLayer> {
+    match origins {
+        CorsOrigins::None => None,
+        // credentials and a wildcard origin can't go together, browsers would refuse every response
+        CorsOrigins::Any => Some(CorsLayer::permissive()),
+        CorsOrigins::List(origins) => Some(CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins.iter().cloned()))
+            // PUT as well, for the routes setting roles and series posts
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+            // cookie authenticated requests that change anything need their CSRF token
+            .allow_headers([CONTENT_TYPE, AUTHORIZATION, HeaderName::from_static(CSRF_HEADER)])
+            .allow_credentials(true))
+    }
+}
+
+/// Middleware refusing preflights from origins that aren't allowed with a 403. CorsLayer on its own would
+/// answer them successfully, just without the headers that let the browser go ahead.
+pub(super) async fn reject_disallowed_preflight(State(origins): State<CorsOrigins>, request: Request, next: Next) -> Response {
+    let is_preflight = request.method() == Method::OPTIONS && request.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    if let Some(origin) = request.headers().get(ORIGIN).filter(|origin| is_preflight && !origins.allows(origin)) {
+        tracing::info!(?origin, "Refused CORS preflight");
+        return error_response(StatusCode::FORBIDDEN, "origin not allowed");
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{tests::{call, test_app_state}, AppState};
+    use axum::{body::Body, http::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN}};
+    use std::sync::Arc;
+
+    /// A preflight for a POST to /api/posts from `origin`.
+    async fn preflight(origins: CorsOrigins, origin: &str) -> Response {
+        let state = Arc::new(AppState { cors_origins: origins, ..test_app_state().await });
+        let request = Request::options("/api/posts")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        call(state, request).await
+    }
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        assert_eq!(parse_allowed_origins("*").unwrap(), CorsOrigins::Any);
+        assert_eq!(parse_allowed_origins(" ").unwrap(), CorsOrigins::None);
+        assert_eq!(parse_allowed_origins("https://a.example, https://b.example,").unwrap(),
+                   CorsOrigins::List(vec![HeaderValue::from_static("https://a.example"), HeaderValue::from_static("https://b.example")]));
+        assert!(parse_allowed_origins("https://a.exa\u{7f}mple").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preflight_from_allowed_origin() {
+        let origins = parse_allowed_origins("https://app.example").unwrap();
+        let response = preflight(origins, "https://app.example").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example");
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+
+    #[tokio::test]
+    async fn test_preflight_from_disallowed_origin() {
+        let origins = parse_allowed_origins("https://app.example").unwrap();
+        let response = preflight(origins, "https://evil.example").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_preflight_with_any_origin() {
+        let response = preflight(CorsOrigins::Any, "https://anywhere.example").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(response.headers().get(ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+    }
+}
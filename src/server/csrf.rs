@@ -0,0 +1,86 @@
+// CSRF protection for cookie authenticated requests. Each session gets one random token, handed to
+// pages as 'csrf_token' and expected back in the X-CSRF-Token header of every state changing request.
+// Bearer token requests don't need this: browsers never attach those on their own.
+use super::{acquire_with_timeout, error::AppError, session::{to_hex, CurrentUser}, AppState};
+use anyhow::Error;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use rand::RngCore;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+pub(super) const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Extractor accepting only requests from a logged in user that carry their session's CSRF token.
+/// No session is rejected with 401, a missing or wrong token with 403.
+#[derive(Debug)]
+pub(super) struct ValidCsrf;
+
+/// Returns the CSRF token of a session, generating and storing one if it doesn't have one yet.
+pub(super) async fn generate_csrf_token(session_id: &str, state: &AppState) -> Result<String, Error> {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let candidate = to_hex(&bytes);
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    // keeping an existing token means pages open in other tabs stay valid
+    let row = sqlx::query!("UPDATE session_table SET csrf_token = COALESCE(csrf_token, $1) WHERE id = $2 RETURNING csrf_token",
+        candidate,
+        session_id)
+        .fetch_one(&mut *write_conn).await?;
+    row.csrf_token.ok_or_else(|| anyhow::anyhow!("Session {session_id} has no CSRF token after generating one."))
+}
+
+impl FromRequestParts<Arc<AppState>> for ValidCsrf {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let user = parts.extensions.get::<CurrentUser>().ok_or(AppError::Unauthorized)?;
+        let sent = parts.headers.get(CSRF_HEADER).map(|value| value.as_bytes()).unwrap_or_default();
+        match user.csrf_token.as_deref() {
+            // compared in constant time so the token can't be guessed a byte at a time from response timings
+            Some(expected) if !sent.is_empty() && bool::from(expected.as_bytes().ct_eq(sent)) => Ok(ValidCsrf),
+            _ => {
+                tracing::warn!(username = user.username, "Rejected request with a missing or invalid CSRF token");
+                Err(AppError::Forbidden)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{session::lookup_session, Role};
+    use crate::server::tests::{call, session_cookie, test_state};
+    use axum::{body::Body, extract::Request, http::{header::COOKIE, StatusCode}};
+
+    #[tokio::test]
+    async fn test_generate_csrf_token_is_stable_per_session() {
+        let state = test_state().await;
+        let cookie = session_cookie(&state, "csrf_user", Role::User).await;
+        let session_id = cookie.split_once('=').unwrap().1;
+        let token = generate_csrf_token(session_id, &state).await.unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(generate_csrf_token(session_id, &state).await.unwrap(), token);
+        let user = lookup_session(session_id, &state).await.unwrap().unwrap();
+        assert_eq!(user.csrf_token.as_deref(), Some(token.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_mutations_require_csrf_token() {
+        let state = test_state().await;
+        let cookie = session_cookie(&state, "csrf_user", Role::User).await;
+        let token = generate_csrf_token(cookie.split_once('=').unwrap().1, &state).await.unwrap();
+        let logout = |csrf: Option<&str>| {
+            let mut request = Request::post("/api/logout").header(COOKIE, &cookie);
+            if let Some(csrf) = csrf {
+                request = request.header(CSRF_HEADER, csrf);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        assert_eq!(call(state.clone(), logout(None)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call(state.clone(), logout(Some(""))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call(state.clone(), logout(Some(&"0".repeat(64)))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call(state.clone(), logout(Some(&token))).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,172 @@
+// Error type for the API handlers. Every variant renders as a JSON body of the form {"error": "...", "code": ...},
+// so handlers can just `?` their way through and leave the response shape to this module.
+use super::responses::{json_response, HTML, JSON};
+use axum::{
+    body::{to_bytes, Body},
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER}, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use anyhow::anyhow;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug)]
+pub(super) enum AppError {
+    NotFound(String),
+    BadRequest(String),
+    Unauthorized,
+    Forbidden,
+    Conflict(String),
+    NotAcceptable,
+    PayloadTooLarge,
+    // the number of seconds to wait, sent as Retry-After
+    TooManyRequests(u64),
+    Internal(anyhow::Error),
+    DatabaseError(sqlx::Error)
+}
+
+// longest plain text error body 'errors_as_json' carries over into the JSON one
+const MAX_PLAIN_ERROR_BYTES: usize = 1024;
+
+/// The body of every error response.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct ErrorBody {
+    #[schema(example = "Invalid or missing credentials.")]
+    pub(super) error: String,
+    /// The response's status code, repeated for clients that only look at the body.
+    #[schema(example = 401)]
+    pub(super) code: u16
+}
+
+/// A JSON error response with `status` and `message`.
+pub(super) fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    json_response(status, ErrorBody { error: message.into(), code: status.as_u16() })
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Invalid or missing credentials.".to_string()),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "forbidden".to_string()),
+            AppError::Conflict(message) => (StatusCode::CONFLICT, message),
+            AppError::NotAcceptable => (StatusCode::NOT_ACCEPTABLE, "None of the accepted content types are available.".to_string()),
+            AppError::PayloadTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "request body too large".to_string()),
+            AppError::TooManyRequests(retry_after) => {
+                let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, "Too many attempts, try again later.");
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return response;
+            }
+            // server side details are logged but never sent to the client
+            AppError::Internal(e) => {
+                tracing::error!("Internal error: {e:#}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error. Contact site administrator for assistance.".to_string())
+            }
+            AppError::DatabaseError(e) => {
+                tracing::error!("Database error: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error. Contact site administrator for assistance.".to_string())
+            }
+        };
+        error_response(status, message)
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        // the query helpers return anyhow errors, so recover the database errors they wrap
+        match err.downcast::<sqlx::Error>() {
+            Ok(e) => AppError::DatabaseError(e),
+            Err(e) => AppError::Internal(e)
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::DatabaseError(err)
+    }
+}
+
+// more specific JSON error handling for response as per the axum::extract docs
+impl From<JsonRejection> for AppError {
+    fn from(err: JsonRejection) -> Self {
+        match err {
+            JsonRejection::JsonSyntaxError(_) => AppError::BadRequest("Invalid JSON syntax.".to_string()),
+            JsonRejection::JsonDataError(_) => AppError::BadRequest("Given JSON data structure does not match expected parsed result.".to_string()),
+            JsonRejection::MissingJsonContentType(_) => AppError::BadRequest("Missing JSON content type in request header.".to_string()),
+            JsonRejection::BytesRejection(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge,
+            JsonRejection::BytesRejection(_) => AppError::Internal(anyhow!("Failed to buffer request body.")),
+            _ => AppError::Internal(anyhow!("Unknown error"))
+        }
+    }
+}
+
+/// Gives error responses that didn't come from AppError the usual JSON body: axum's own plain text extractor
+/// rejections, the empty 404s and 405s of the router, and RequestBodyLimitLayer's 413s sent before any handler
+/// runs. Their text, if any, becomes the message. HTML error pages are left alone for the browsers that asked for them.
+pub(super) async fn errors_as_json(response: Response) -> Response {
+    let status = response.status();
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !(status.is_client_error() || status.is_server_error()) || content_type == JSON || content_type == HTML {
+        return response;
+    }
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        return AppError::PayloadTooLarge.into_response();
+    }
+    let (mut parts, body) = response.into_parts();
+    let text = to_bytes(body, MAX_PLAIN_ERROR_BYTES).await.ok()
+        .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+        .filter(|text| !text.trim().is_empty());
+    let message = text.unwrap_or_else(|| status.canonical_reason().unwrap_or("Error").to_string());
+    let error = error_response(status, message);
+    // anything else the original response said, like a 405's Allow header, still holds
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static(JSON));
+    Response::from_parts(parts, Body::new(error.into_body()))
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(_err: QueryRejection) -> Self {
+        AppError::BadRequest("Missing or invalid query parameters.".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use serde_json::Value;
+
+    async fn render(err: AppError) -> (StatusCode, Value) {
+        let response = err.into_response();
+        let status = response.status();
+        assert_eq!(response.headers()["content-type"], "application/json");
+        (status, serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_app_error_responses() {
+        let (status, body) = render(AppError::NotFound("No such user.".to_string())).await;
+        assert_eq!((status, body), (StatusCode::NOT_FOUND, serde_json::json!({"error": "No such user.", "code": 404})));
+        assert_eq!(render(AppError::BadRequest("Bad.".to_string())).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(render(AppError::Unauthorized).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(render(AppError::Forbidden).await, (StatusCode::FORBIDDEN, serde_json::json!({"error": "forbidden", "code": 403})));
+        assert_eq!(render(AppError::PayloadTooLarge).await,
+                   (StatusCode::PAYLOAD_TOO_LARGE, serde_json::json!({"error": "request body too large", "code": 413})));
+        assert_eq!(AppError::TooManyRequests(600).into_response().headers()[RETRY_AFTER], "600");
+        assert_eq!(render(AppError::TooManyRequests(600)).await.0, StatusCode::TOO_MANY_REQUESTS);
+
+        // internal details stay out of the response body
+        let (status, body) = render(AppError::from(anyhow!("secret detail"))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body["error"].as_str().unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn test_anyhow_wrapped_sqlx_error_is_a_database_error() {
+        assert!(matches!(AppError::from(anyhow::Error::from(sqlx::Error::RowNotFound)), AppError::DatabaseError(_)));
+        assert!(matches!(AppError::from(anyhow!("not a database problem")), AppError::Internal(_)));
+    }
+}
@@ -0,0 +1,238 @@
+// RSS 2.0 and Atom 1.0 feeds of the most recent posts, for feed readers. Both carry the same posts and
+// are built with quick-xml's writer so every title and body is escaped properly.
+use super::{acquire_with_timeout, error::AppError, posts::{Post, RenderedPost}, AppState};
+use anyhow::Error;
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+use chrono::{DateTime, FixedOffset, Utc};
+use quick_xml::{events::{BytesDecl, BytesText, Event}, Writer};
+use std::{io, sync::Arc};
+
+// how many of the newest posts the feeds carry
+const FEED_ITEMS: i64 = 20;
+const FEED_TITLE: &str = "Posts";
+const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+/// A post in a feed, with the name of whoever wrote it.
+struct FeedPost {
+    rendered: RenderedPost,
+    author: String
+}
+
+/// GET request handler serving the RSS feed.
+#[tracing::instrument(skip(state))]
+pub(super) async fn feed(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    let posts = get_recent_posts(&state).await?;
+    let xml = build_feed(&state.base_url, &posts).map_err(Error::from)?;
+    Ok(([(CONTENT_TYPE, "application/rss+xml; charset=utf-8")], xml))
+}
+
+/// GET request handler serving the Atom feed.
+#[tracing::instrument(skip(state))]
+pub(super) async fn atom_feed(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    let posts = get_recent_posts(&state).await?;
+    let xml = build_atom_feed(&state.base_url, &posts, Utc::now().fixed_offset()).map_err(Error::from)?;
+    Ok(([(CONTENT_TYPE, "application/atom+xml; charset=utf-8")], xml))
+}
+
+/// Renders `posts` as an RSS 2.0 document whose channel points at `base_url`.
+fn build_feed(base_url: &str, posts: &[FeedPost]) -> io::Result<String> {
+    let mut writer = Writer::new(Vec::new());
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer.create_element("rss").with_attribute(("version", "2.0")).write_inner_content(|writer| {
+        writer.create_element("channel").write_inner_content(|writer| {
+            writer.create_element("title").write_text_content(BytesText::new(FEED_TITLE))?;
+            writer.create_element("link").write_text_content(BytesText::new(base_url))?;
+            writer.create_element("description").write_text_content(BytesText::new("Recent posts"))?;
+            for FeedPost { rendered, .. } in posts {
+                let post = &rendered.post;
+                let link = format!("{base_url}api/posts/{}", post.id);
+                writer.create_element("item").write_inner_content(|writer| {
+                    writer.create_element("title").write_text_content(BytesText::new(&post.title))?;
+                    writer.create_element("link").write_text_content(BytesText::new(&link))?;
+                    // HTML in a description is fine as long as it's escaped, which BytesText::new does
+                    writer.create_element("description").write_text_content(BytesText::new(&rendered.rendered_body))?;
+                    // RSS wants RFC 822 dates; a post whose date doesn't parse just goes without one
+                    if let Some(Ok(published)) = post.published_at.as_deref().map(DateTime::parse_from_rfc3339) {
+                        writer.create_element("pubDate").write_text_content(BytesText::new(&published.to_rfc2822()))?;
+                    }
+                    writer.create_element("guid").with_attribute(("isPermaLink", "true")).write_text_content(BytesText::new(&link))?;
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    })?;
+    String::from_utf8(writer.into_inner()).map_err(io::Error::other)
+}
+
+/// Renders `posts` as an Atom 1.0 document about `base_url`. The feed counts as updated when its newest post was,
+/// or at `now` while there are no posts.
+fn build_atom_feed(base_url: &str, posts: &[FeedPost], now: DateTime<FixedOffset>) -> io::Result<String> {
+    let feed_updated = posts.iter().filter_map(|post| updated(&post.rendered.post)).max().unwrap_or(now);
+    let self_link = format!("{base_url}feed.atom");
+    let mut writer = Writer::new(Vec::new());
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer.create_element("feed").with_attribute(("xmlns", ATOM_NAMESPACE)).write_inner_content(|writer| {
+        writer.create_element("title").write_text_content(BytesText::new(FEED_TITLE))?;
+        writer.create_element("id").write_text_content(BytesText::new(base_url))?;
+        writer.create_element("link").with_attribute(("href", base_url)).write_empty()?;
+        writer.create_element("link").with_attributes([("rel", "self"), ("href", self_link.as_str())]).write_empty()?;
+        writer.create_element("updated").write_text_content(BytesText::new(&feed_updated.to_rfc3339()))?;
+        for FeedPost { rendered, author } in posts {
+            let post = &rendered.post;
+            let link = format!("{base_url}posts/{}", post.slug);
+            writer.create_element("entry").write_inner_content(|writer| {
+                writer.create_element("title").write_text_content(BytesText::new(&post.title))?;
+                writer.create_element("id").write_text_content(BytesText::new(&link))?;
+                writer.create_element("link").with_attribute(("href", link.as_str())).write_empty()?;
+                // every entry needs one, so a post whose dates don't parse borrows the feed's
+                let entry_updated = updated(post).unwrap_or(feed_updated);
+                writer.create_element("updated").write_text_content(BytesText::new(&entry_updated.to_rfc3339()))?;
+                writer.create_element("author").write_inner_content(|writer| {
+                    writer.create_element("name").write_text_content(BytesText::new(author))?;
+                    Ok(())
+                })?;
+                writer.create_element("content").with_attribute(("type", "html")).write_text_content(BytesText::new(&rendered.rendered_body))?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    })?;
+    String::from_utf8(writer.into_inner()).map_err(io::Error::other)
+}
+
+/// When a post last changed as far as feeds are concerned: when it was published, or failing that created.
+fn updated(post: &Post) -> Option<DateTime<FixedOffset>> {
+    post.published_at.as_deref().into_iter().chain([&*post.created]).find_map(|date| DateTime::parse_from_rfc3339(date).ok())
+}
+
+/// Returns the FEED_ITEMS newest published posts with their authors' names.
+async fn get_recent_posts(state: &AppState) -> Result<Vec<FeedPost>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let rows = sqlx::query!(r#"SELECT post_table.id AS "id!", title, body, post_table.created, author_id, published_at, slug AS "slug!", username AS author
+        FROM post_table JOIN user_table ON user_table.id = post_table.author_id
+        WHERE published_at IS NOT NULL ORDER BY post_table.id DESC LIMIT $1"#,
+        FEED_ITEMS)
+        .fetch_all(&mut *read_conn).await?;
+    Ok(rows.into_iter().map(|row| FeedPost {
+        rendered: RenderedPost::from(Post {
+            id: row.id,
+            title: row.title.into(),
+            body: row.body.into(),
+            created: row.created.into(),
+            author_id: row.author_id,
+            published_at: row.published_at,
+            slug: row.slug
+        }),
+        author: row.author
+    }).collect())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::server::{insert_user, tests::{call, test_state}, User, Role};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::StatusCode};
+    use quick_xml::{escape::resolve_predefined_entity, Reader};
+
+    /// Parses `xml` fully, failing on malformed markup, and returns the unescaped text of every element named `tag`.
+    pub(in crate::server) fn texts_of(xml: &str, tag: &[u8]) -> Vec<String> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().check_end_names = true;
+        let (mut texts, mut current) = (Vec::new(), None::<String>);
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(start) if start.name().as_ref() == tag => current = Some(String::new()),
+                Event::Text(text) => if let Some(current) = current.as_mut() {
+                    current.push_str(&text.decode().unwrap());
+                },
+                // entities like &amp; arrive as events of their own
+                Event::GeneralRef(entity) => if let Some(current) = current.as_mut() {
+                    current.push_str(resolve_predefined_entity(&entity.decode().unwrap()).unwrap());
+                },
+                Event::End(_) => texts.extend(current.take()),
+                Event::Eof => return texts,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_feed() {
+        let state = test_state().await;
+        insert_user(&User::new("feed_author".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        for i in 0..FEED_ITEMS + 2 {
+            let (title, slug) = (format!("Post {i} <draft>"), format!("post-{i}-draft"));
+            sqlx::query!("INSERT INTO post_table (title, body, created, author_id, published_at, slug)
+                         SELECT $1, 'Some *text* & more', '2025-05-01T12:00:00+00:00', id, '2025-06-01T12:00:00+00:00', $2 FROM user_table
+                         WHERE username = 'feed_author'", title, slug)
+                .execute(&state.write_pool).await.unwrap();
+        }
+        // drafts never make it into the feed
+        sqlx::query!("INSERT INTO post_table (title, body, created, author_id, slug) SELECT 'Unpublished', 'tbd', '2025-06-02T12:00:00+00:00', id, 'unpublished'
+                     FROM user_table WHERE username = 'feed_author'")
+            .execute(&state.write_pool).await.unwrap();
+        let response = call(state, Request::get("/feed.xml").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/rss+xml; charset=utf-8");
+        let xml = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+
+        let titles = texts_of(&xml, b"title");
+        // the channel's title, then the newest FEED_ITEMS posts
+        assert_eq!(titles.len() as i64, FEED_ITEMS + 1);
+        assert_eq!(titles[0], FEED_TITLE);
+        assert_eq!(titles[1], format!("Post {} <draft>", FEED_ITEMS + 1));
+        assert_eq!(texts_of(&xml, b"link")[0], "http://0.0.0.0:3000/");
+        assert_eq!(texts_of(&xml, b"pubDate")[0], "Sun, 1 Jun 2025 12:00:00 +0000");
+        assert_eq!(texts_of(&xml, b"description")[1], "<p>Some <em>text</em> &amp; more</p>\n");
+        assert_eq!(texts_of(&xml, b"guid").len() as i64, FEED_ITEMS);
+    }
+
+    /// The `href` of every `link` element in `xml`.
+    fn hrefs_of(xml: &str) -> Vec<String> {
+        let mut reader = Reader::from_str(xml);
+        let mut hrefs = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Empty(link) if link.name().as_ref() == b"link" => {
+                    hrefs.push(link.try_get_attribute("href").unwrap().unwrap().unescape_value().unwrap().into_owned());
+                }
+                Event::Eof => return hrefs,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_atom_feed() {
+        let state = test_state().await;
+        insert_user(&User::new("atom_author".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        for (title, slug, published_at) in [("Older", "older", "2025-05-01T12:00:00+00:00"), ("Newer & better", "newer", "2025-06-01T12:00:00+02:00")] {
+            sqlx::query!("INSERT INTO post_table (title, body, created, author_id, published_at, slug)
+                         SELECT $1, 'Some *text*', '2025-04-01T12:00:00+00:00', id, $2, $3 FROM user_table WHERE username = 'atom_author'",
+                         title, published_at, slug)
+                .execute(&state.write_pool).await.unwrap();
+        }
+        let response = call(state.clone(), Request::get("/feed.atom").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/atom+xml; charset=utf-8");
+        let xml = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+
+        assert_eq!(texts_of(&xml, b"title"), [FEED_TITLE, "Newer & better", "Older"]);
+        // the feed's own, then one per entry
+        let updated = texts_of(&xml, b"updated");
+        assert_eq!(updated.len(), 3);
+        for timestamp in &updated {
+            assert!(DateTime::parse_from_rfc3339(timestamp).is_ok(), "{timestamp}");
+        }
+        assert_eq!(updated[0], "2025-06-01T12:00:00+02:00");
+        assert_eq!(texts_of(&xml, b"name"), ["atom_author", "atom_author"]);
+        assert_eq!(texts_of(&xml, b"content")[0], "<p>Some <em>text</em></p>\n");
+        let hrefs = hrefs_of(&xml);
+        assert_eq!(hrefs.len(), 4);
+        assert!(hrefs.iter().all(|href| href.starts_with(&state.base_url)), "{hrefs:?}");
+        assert_eq!(hrefs[1], format!("{}feed.atom", state.base_url));
+        assert_eq!(hrefs[2], format!("{}posts/newer", state.base_url));
+    }
+}
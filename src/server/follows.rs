@@ -0,0 +1,213 @@
+// Following other users. Logged in users can follow and unfollow anyone but themselves, anyone can
+// list a user's followers and followees, and GET /api/feed shows the caller the posts of everyone they follow.
+use super::{acquire_with_timeout, csrf::ValidCsrf, error::{AppError, ErrorBody}, page_offset, page_param, posts::Post,
+            responses::json_response, session::CurrentUser, AppState};
+use anyhow::Error;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use chrono::Utc;
+use std::{collections::HashMap, sync::Arc};
+
+/// POST request handler making the caller follow `username`. Following someone twice changes nothing.
+#[utoipa::path(post, path = "/api/users/{username}/follow", tag = "users", security(("session" = [])), params(("username" = String, Path)),
+    responses(
+        (status = 204, description = "Following"),
+        (status = 400, description = "Tried to follow yourself", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn follow(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                           Path(username): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let Extension(user) = current_user.ok_or(AppError::Unauthorized)?;
+    let following_id = select_user_id(&username, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    if following_id == user.id {
+        return Err(AppError::BadRequest("You can't follow yourself.".to_string()));
+    }
+    insert_follow(user.id, following_id, &state).await?;
+    tracing::info!(follower = user.username, "Followed user");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE request handler making the caller stop following `username`. Unfollowing someone not followed changes nothing.
+#[utoipa::path(delete, path = "/api/users/{username}/follow", tag = "users", security(("session" = [])), params(("username" = String, Path)),
+    responses(
+        (status = 204, description = "Not following"),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn unfollow(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                             Path(username): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let Extension(user) = current_user.ok_or(AppError::Unauthorized)?;
+    let following_id = select_user_id(&username, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    delete_follow(user.id, following_id, &state).await?;
+    tracing::info!(follower = user.username, "Unfollowed user");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// API endpoint returning a page of the usernames following `username`, alphabetically.
+#[utoipa::path(get, path = "/api/users/{username}/followers", tag = "users",
+    params(("username" = String, Path), ("page" = Option<u32>, Query)),
+    responses(
+        (status = 200, body = Vec<String>),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state))]
+pub(super) async fn followers(State(state): State<Arc<AppState>>, Path(username): Path<String>,
+                              Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, AppError> {
+    let id = select_user_id(&username, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    Ok(json_response(StatusCode::OK, select_followers(id, page_param(&params), &state).await?))
+}
+
+/// API endpoint returning a page of the usernames `username` follows, alphabetically.
+#[utoipa::path(get, path = "/api/users/{username}/following", tag = "users",
+    params(("username" = String, Path), ("page" = Option<u32>, Query)),
+    responses(
+        (status = 200, body = Vec<String>),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state))]
+pub(super) async fn following(State(state): State<Arc<AppState>>, Path(username): Path<String>,
+                              Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, AppError> {
+    let id = select_user_id(&username, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    Ok(json_response(StatusCode::OK, select_following(id, page_param(&params), &state).await?))
+}
+
+/// API endpoint returning a page of published posts by the users the caller follows, newest first.
+#[utoipa::path(get, path = "/api/feed", tag = "posts", security(("session" = [])), params(("page" = Option<u32>, Query)),
+    responses(
+        (status = 200, body = Vec<Post>),
+        (status = 401, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn feed(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>,
+                         Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, AppError> {
+    let Extension(user) = current_user.ok_or(AppError::Unauthorized)?;
+    Ok(json_response(StatusCode::OK, select_feed(user.id, page_param(&params), &state).await?))
+}
+
+/// The id of the undeleted user called `username`, if there is one.
+pub(super) async fn select_user_id(username: &str, state: &AppState) -> Result<Option<i64>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = $1 AND deleted_at IS NULL"#, username)
+        .fetch_optional(&mut *read_conn).await?)
+}
+
+/// Records that `follower_id` follows `following_id`, unless it already does.
+async fn insert_follow(follower_id: i64, following_id: i64, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let created = Utc::now().to_rfc3339();
+    sqlx::query!("INSERT OR IGNORE INTO follow_table (follower_id, following_id, created) VALUES ($1, $2, $3)",
+        follower_id,
+        following_id,
+        created)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+/// Removes `follower_id` following `following_id`, if it did.
+async fn delete_follow(follower_id: i64, following_id: i64, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    sqlx::query!("DELETE FROM follow_table WHERE follower_id = $1 AND following_id = $2", follower_id, following_id)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+/// The n=state.per_page undeleted followers of user `id` on the given page, by username.
+async fn select_followers(id: i64, page: u32, state: &AppState) -> Result<Vec<String>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let offset = page_offset(page, state.per_page);
+    Ok(sqlx::query_scalar!("SELECT username FROM follow_table JOIN user_table ON user_table.id = follow_table.follower_id
+        WHERE following_id = $1 AND deleted_at IS NULL ORDER BY username LIMIT $2 OFFSET $3",
+        id,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// The n=state.per_page undeleted users followed by user `id` on the given page, by username.
+async fn select_following(id: i64, page: u32, state: &AppState) -> Result<Vec<String>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let offset = page_offset(page, state.per_page);
+    Ok(sqlx::query_scalar!("SELECT username FROM follow_table JOIN user_table ON user_table.id = follow_table.following_id
+        WHERE follower_id = $1 AND deleted_at IS NULL ORDER BY username LIMIT $2 OFFSET $3",
+        id,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// The n=state.per_page published posts on the given page by users that user `id` follows, newest first.
+async fn select_feed(id: i64, page: u32, state: &AppState) -> Result<Vec<Post>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let offset = page_offset(page, state.per_page);
+    Ok(sqlx::query_as!(Post, r#"SELECT id AS "id!", title, body, created, author_id, published_at, slug AS "slug!" FROM post_table
+        WHERE published_at IS NOT NULL AND author_id IN (SELECT following_id FROM follow_table WHERE follower_id = $1)
+        ORDER BY published_at DESC, id DESC LIMIT $2 OFFSET $3"#,
+        id,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{posts::tests::{published_post, send_json}, tests::{bearer, get_request, send, session_cookie, test_state}, Role};
+    use serde_json::Value;
+
+    async fn usernames(state: Arc<AppState>, uri: &str) -> (StatusCode, Vec<String>) {
+        let (status, body) = get_request(state, uri).await;
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_follow_and_unfollow() {
+        let state = test_state().await;
+        let fan = session_cookie(&state, "fan_user", Role::User).await;
+        session_cookie(&state, "star_user", Role::User).await;
+
+        assert_eq!(send(state.clone(), "POST", "/api/users/star_user/follow", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(state.clone(), "POST", "/api/users/nobody_here/follow", Some(&fan)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(state.clone(), "POST", "/api/users/fan_user/follow", Some(&fan)).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send(state.clone(), "POST", "/api/users/star_user/follow", Some(&fan)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(state.clone(), "POST", "/api/users/star_user/follow", Some(&fan)).await.0, StatusCode::NO_CONTENT);
+
+        assert_eq!(usernames(state.clone(), "/api/users/star_user/followers").await, (StatusCode::OK, vec!["fan_user".to_string()]));
+        assert_eq!(usernames(state.clone(), "/api/users/fan_user/following").await, (StatusCode::OK, vec!["star_user".to_string()]));
+        assert!(usernames(state.clone(), "/api/users/fan_user/followers").await.1.is_empty());
+        assert_eq!(usernames(state.clone(), "/api/users/nobody_here/followers").await.0, StatusCode::NOT_FOUND);
+
+        assert_eq!(send(state.clone(), "DELETE", "/api/users/star_user/follow", Some(&fan)).await.0, StatusCode::NO_CONTENT);
+        assert!(usernames(state, "/api/users/star_user/followers").await.1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_feed_only_has_followed_users_posts() {
+        let state = test_state().await;
+        let reader = session_cookie(&state, "reader_user", Role::User).await;
+        for (author, title) in [("followed_author", "Followed"), ("other_author", "Not followed")] {
+            let token = bearer(&state, author, Role::User).await;
+            published_post(&state, &token, serde_json::json!({"title": title, "body": "text"})).await;
+            // a draft never shows, followed or not
+            send_json(state.clone(), "POST", "/api/posts", &token, serde_json::json!({"title": "Draft", "body": "text"})).await;
+        }
+        assert_eq!(send(state.clone(), "GET", "/api/feed", None).await.0, StatusCode::UNAUTHORIZED);
+        let (status, body) = send(state.clone(), "GET", "/api/feed", Some(&reader)).await;
+        assert_eq!((status, serde_json::from_slice::<Value>(&body).unwrap()), (StatusCode::OK, serde_json::json!([])));
+
+        send(state.clone(), "POST", "/api/users/followed_author/follow", Some(&reader)).await;
+        let (_, body) = send(state, "GET", "/api/feed", Some(&reader)).await;
+        let posts: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(posts.iter().map(|post| post["title"].as_str().unwrap()).collect::<Vec<_>>(), ["Followed"]);
+    }
+}
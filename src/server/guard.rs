@@ -0,0 +1,82 @@
+// Role guards for handlers. Taking one as a parameter restricts the handler to logged in users of at
+// least that role, e.g. `async fn delete_user(AdminGuard(admin): AdminGuard, ...)`, and hands over who they are.
+use super::{error::AppError, session::CurrentUser, AppState, Role};
+use axum::{extract::FromRequestParts, http::request::Parts};
+use std::sync::Arc;
+
+/// Admits only admins, holding the admin. No session is rejected with 401, any other role with 403.
+#[derive(Debug)]
+pub(super) struct AdminGuard(pub(super) CurrentUser);
+
+/// Admits mods and admins, holding the user. No session is rejected with 401, any other role with 403.
+#[derive(Debug)]
+pub(super) struct ModGuard(pub(super) CurrentUser);
+
+/// The session's user, if they have role `min_role` or a more privileged one.
+fn check_role(parts: &Parts, min_role: Role) -> Result<CurrentUser, AppError> {
+    // 'auth_session' has already resolved the session cookie, if there was one
+    let user = parts.extensions.get::<CurrentUser>().ok_or(AppError::Unauthorized)?;
+    if !user.role.at_least(min_role) {
+        tracing::warn!(username = user.username, role = ?user.role, "Refused request needing role {:?}", min_role);
+        return Err(AppError::Forbidden);
+    }
+    Ok(user.clone())
+}
+
+impl FromRequestParts<Arc<AppState>> for AdminGuard {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        check_role(parts, Role::Admin).map(AdminGuard)
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for ModGuard {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        check_role(parts, Role::Mod).map(ModGuard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::test_state;
+    use axum::{extract::Request, http::StatusCode, response::IntoResponse};
+
+    async fn guard_status<G: FromRequestParts<Arc<AppState>, Rejection = AppError>>(role: Option<Role>) -> StatusCode {
+        let mut request = Request::new(());
+        if let Some(role) = role {
+            request.extensions_mut().insert(CurrentUser {
+                id: 1,
+                username: "guarded_user".to_string(),
+                role,
+                impersonated_by: None,
+                session_id: "mock_session".to_string(),
+                csrf_token: None
+            });
+        }
+        let (mut parts, _) = request.into_parts();
+        match G::from_request_parts(&mut parts, &test_state().await).await {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.into_response().status()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_guard() {
+        assert_eq!(guard_status::<AdminGuard>(Some(Role::Admin)).await, StatusCode::OK);
+        assert_eq!(guard_status::<AdminGuard>(Some(Role::Mod)).await, StatusCode::FORBIDDEN);
+        assert_eq!(guard_status::<AdminGuard>(Some(Role::User)).await, StatusCode::FORBIDDEN);
+        assert_eq!(guard_status::<AdminGuard>(None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_mod_guard() {
+        assert_eq!(guard_status::<ModGuard>(Some(Role::Admin)).await, StatusCode::OK);
+        assert_eq!(guard_status::<ModGuard>(Some(Role::Mod)).await, StatusCode::OK);
+        assert_eq!(guard_status::<ModGuard>(Some(Role::User)).await, StatusCode::FORBIDDEN);
+        assert_eq!(guard_status::<ModGuard>(None).await, StatusCode::UNAUTHORIZED);
+    }
+}
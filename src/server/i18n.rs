@@ -0,0 +1,123 @@
+// Translations of the UI strings in the page templates. Every locale is a TOML file of `key = "string"` pairs in
+// LOCALES_DIR named after its language code, e.g. `fr.toml`. English is the default, and fills in any key another
+// locale leaves out, so a template can always find its strings.
+use super::AppState;
+use anyhow::{anyhow, Error};
+use axum::{extract::{FromRequestParts, Query}, http::request::Parts};
+use serde_json::Value;
+use std::{collections::HashMap, convert::Infallible, fs, path::Path, sync::Arc};
+
+pub(super) const LOCALES_DIR: &str = "locales";
+pub(super) const DEFAULT_LOCALE: &str = "en";
+
+pub(super) struct I18n {
+    // language code -> key -> string, with the default locale's strings filled in everywhere
+    locales: HashMap<String, HashMap<String, String>>
+}
+
+impl I18n {
+    /// Reads every `.toml` file in `dir`. Fails if one doesn't parse, or if there's none for the default locale.
+    pub(super) fn load(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut locales = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            let Some(lang) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let strings: HashMap<String, String> = toml::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            locales.insert(lang.to_string(), strings);
+        }
+        let default = locales.get(DEFAULT_LOCALE).cloned()
+            .ok_or_else(|| anyhow!("No strings for the default locale '{DEFAULT_LOCALE}'"))?;
+        for strings in locales.values_mut() {
+            for (key, string) in &default {
+                strings.entry(key.clone()).or_insert_with(|| string.clone());
+            }
+        }
+        Ok(I18n { locales })
+    }
+
+    /// `lang` if it is a loaded locale, otherwise the default.
+    pub(super) fn resolve<'a>(&'a self, lang: Option<&'a str>) -> &'a str {
+        match lang {
+            Some(lang) if self.locales.contains_key(lang) => lang,
+            _ => DEFAULT_LOCALE
+        }
+    }
+
+    /// All the strings of `lang`, or of the default locale if `lang` isn't loaded.
+    pub(super) fn strings(&self, lang: &str) -> &HashMap<String, String> {
+        &self.locales[self.resolve(Some(lang))]
+    }
+}
+
+/// Language the page is shown in, from the `lang` query parameter. Unknown or missing languages get the default.
+pub(super) struct Locale(pub(super) String);
+
+impl FromRequestParts<Arc<AppState>> for Locale {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let params = Query::<HashMap<String, String>>::try_from_uri(&parts.uri).map(|Query(params)| params).unwrap_or_default();
+        Ok(Locale(state.i18n.resolve(params.get("lang").map(String::as_str)).to_string()))
+    }
+}
+
+/// Tera function `t(key, lang)` looking up the string for `key` in `lang`, the default locale if it's left out.
+/// An unknown key is an error, so a typo in a template fails to render rather than showing nothing.
+pub(super) fn translate_function(i18n: Arc<I18n>) -> impl tera::Function {
+    move |args: &HashMap<String, Value>| {
+        let key = args.get("key").and_then(Value::as_str).ok_or_else(|| tera::Error::msg("t() needs a string 'key'"))?;
+        let lang = args.get("lang").and_then(Value::as_str).unwrap_or(DEFAULT_LOCALE);
+        i18n.strings(lang).get(key)
+            .map(|string| Value::from(string.as_str()))
+            .ok_or_else(|| tera::Error::msg(format!("No string for '{key}'")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::page_context;
+    use crate::server::tests::{get_request, test_state};
+    use axum::http::StatusCode;
+
+    #[test]
+    fn test_missing_strings_fall_back_to_default() {
+        let i18n = I18n::load(LOCALES_DIR).unwrap();
+        for (lang, strings) in &i18n.locales {
+            assert_eq!(strings.len(), i18n.strings(DEFAULT_LOCALE).len(), "{lang}");
+        }
+        assert_eq!(i18n.resolve(Some("fr")), "fr");
+        assert_eq!(i18n.resolve(Some("xx")), DEFAULT_LOCALE);
+        assert_eq!(i18n.resolve(None), DEFAULT_LOCALE);
+    }
+
+    #[tokio::test]
+    async fn test_lang_fr_uses_french_strings() {
+        let state = test_state().await;
+        let context = page_context(&state, None, Locale("fr".to_string())).await;
+        assert_eq!(context.get("lang"), Some(&Value::from("fr")));
+        assert_eq!(context.get("t").and_then(|t| t.get("posts")), Some(&Value::from("Articles")));
+
+        let (status, body) = get_request(state, "/posts?lang=fr").await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("<html lang=\"fr\">"));
+        assert!(body.contains("Aucun article pour le moment."));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_lang_falls_back_to_english() {
+        let state = test_state().await;
+        let (status, body) = get_request(state, "/posts?lang=xx").await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("<html lang=\"en\">"));
+        assert!(body.contains("No posts yet."));
+    }
+}
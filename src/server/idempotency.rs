@@ -0,0 +1,238 @@
+// Idempotency keys for the endpoints that create things, so a client retrying after a dropped connection
+// doesn't create the same user or post twice. A request carrying an `Idempotency-Key` header has its response
+// stored under that key, and any later request with the same key, to the same endpoint and from the same caller,
+// gets the stored response back instead of running.
+use super::{acquire_with_timeout, error::AppError, jwt::AuthBearer, responses::JSON, session::CurrentUser, AppState};
+use anyhow::Error;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{FromRequestParts, Request, State},
+    http::{header::{AUTHORIZATION, CONTENT_TYPE}, request::Parts, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{TimeDelta, Utc};
+use regex::Regex;
+use std::sync::Arc;
+
+pub(super) const IDEMPOTENCY_KEY: &str = "idempotency-key";
+// set on responses that were replayed rather than produced by the handler
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+// POST requests to these paths honour idempotency keys
+const IDEMPOTENT_PATHS: [&str; 2] = ["/api/users", "/api/posts"];
+// how long a stored response is replayed for
+const KEY_LIFETIME: TimeDelta = TimeDelta::hours(24);
+
+/// What a stored response is kept against: the key, and the request it came with.
+struct StoredKey {
+    key: String,
+    method: String,
+    path: String,
+    // 'user <id>' for an authenticated caller, '' for an anonymous one
+    caller: String
+}
+
+/// Middleware replaying the stored response for a known `Idempotency-Key`, and storing the response under a new one.
+/// Keys must be UUIDs. Server errors aren't stored, so retrying after one runs the request again. A request with an
+/// `Authorization` header that doesn't authenticate is left to its handler to turn away, and nothing is replayed to it.
+pub(super) async fn idempotency(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if request.method() != Method::POST || !IDEMPOTENT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let Some(key) = key.to_str().ok().filter(|key| key_is_valid(key)).map(str::to_ascii_lowercase) else {
+        return AppError::BadRequest("Idempotency-Key must be a UUID.".to_string()).into_response();
+    };
+    let (mut parts, body) = request.into_parts();
+    let Some(caller) = caller(&mut parts, &state).await else {
+        return next.run(Request::from_parts(parts, body)).await;
+    };
+    let key = StoredKey { key, method: parts.method.to_string(), path: parts.uri.path().to_string(), caller };
+    let request = Request::from_parts(parts, body);
+    match select_response(&key, &state).await {
+        Ok(Some((status, body))) => {
+            tracing::info!(key = key.key, "Replayed response for idempotency key");
+            return replayed_response(status, body);
+        }
+        Ok(None) => {}
+        Err(e) => return AppError::from(e).into_response()
+    }
+    let (parts, body) = next.run(request).await.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => return AppError::Internal(anyhow::anyhow!("Failed to buffer response body: {e}")).into_response()
+    };
+    if !parts.status.is_server_error() {
+        // the response has already happened, so failing to remember it is logged rather than turned into an error
+        let stored = match std::str::from_utf8(&body) {
+            Ok(text) => insert_response(&key, parts.status, text, &state).await,
+            Err(e) => Err(Error::from(e))
+        };
+        if let Err(e) = stored {
+            tracing::error!("Failed to store response for idempotency key: {}", e);
+        }
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Who is making the request: the user of its bearer token or session, or '' for nobody. None if it carries an
+/// `Authorization` header that isn't a valid bearer token.
+async fn caller(parts: &mut Parts, state: &Arc<AppState>) -> Option<String> {
+    if !parts.headers.contains_key(AUTHORIZATION) {
+        // 'auth_session' has already resolved the session cookie, if there was one
+        return Some(parts.extensions.get::<CurrentUser>().map(|user| format!("user {}", user.id)).unwrap_or_default());
+    }
+    AuthBearer::from_request_parts(parts, state).await.ok().map(|auth| format!("user {}", auth.user_id))
+}
+
+/// Whether `key` is a UUID in its usual hyphenated form.
+fn key_is_valid(key: &str) -> bool {
+    Regex::new("^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$").is_ok_and(|val| val.is_match(key))
+}
+
+/// Rebuilds a stored response. Everything these endpoints answer with is JSON, or empty.
+fn replayed_response(status: StatusCode, body: String) -> Response {
+    let has_body = !body.is_empty();
+    let mut response = (status, body).into_response();
+    if has_body {
+        // replacing the text/plain a String body comes with
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(JSON));
+    }
+    response.headers_mut().insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    response
+}
+
+/// The status and body stored under `key`, unless there are none or they are older than KEY_LIFETIME.
+async fn select_response(key: &StoredKey, state: &AppState) -> Result<Option<(StatusCode, String)>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let cutoff = (Utc::now() - KEY_LIFETIME).to_rfc3339();
+    let row = sqlx::query!("SELECT response_status, response_body FROM idempotency_table
+        WHERE key = $1 AND method = $2 AND path = $3 AND caller = $4 AND created > $5",
+        key.key,
+        key.method,
+        key.path,
+        key.caller,
+        cutoff)
+        .fetch_optional(&mut *read_conn).await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    Ok(Some((StatusCode::from_u16(u16::try_from(row.response_status)?)?, row.response_body)))
+}
+
+/// Stores a response under `key`, replacing anything an expired use of the same key left behind.
+async fn insert_response(key: &StoredKey, status: StatusCode, body: &str, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let status = status.as_u16();
+    let created = Utc::now().to_rfc3339();
+    sqlx::query!("INSERT OR REPLACE INTO idempotency_table (key, method, path, caller, response_status, response_body, created)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        key.key,
+        key.method,
+        key.path,
+        key.caller,
+        status,
+        body,
+        created)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{tests::{bearer, call, test_state}, Role};
+    use axum::http::header::AUTHORIZATION;
+    use serde_json::Value;
+
+    async fn post_with_key(state: Arc<AppState>, uri: &str, key: &str, authorization: Option<&str>, json: Value) -> Response {
+        let mut request = Request::post(uri).header(CONTENT_TYPE, "application/json").header(IDEMPOTENCY_KEY, key);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        call(state, request.body(Body::from(json.to_string())).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn test_repeated_key_creates_one_user() {
+        let state = test_state().await;
+        let key = "0f8e2a8c-5b1d-4c7e-9a3f-2d6b8e1c4a70";
+        let sign_up = serde_json::json!({"username": "retrying_user", "password": "correct horse"});
+        let first = post_with_key(state.clone(), "/api/users", key, None, sign_up.clone()).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(!first.headers().contains_key(IDEMPOTENT_REPLAYED));
+        // without the key this would be a 400 for the taken username
+        let retry = post_with_key(state.clone(), "/api/users", &key.to_uppercase(), None, sign_up).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()[IDEMPOTENT_REPLAYED], "true");
+        let users = sqlx::query_scalar!("SELECT COUNT(*) FROM user_table WHERE username = 'retrying_user'")
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 1);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_key_creates_one_post() {
+        let state = test_state().await;
+        let token = bearer(&state, "author_user", Role::User).await;
+        let key = "7c9d4b1e-2f3a-4e5b-8c6d-9e0f1a2b3c4d";
+        let post = serde_json::json!({"title": "Only once", "body": "text"});
+        let first = post_with_key(state.clone(), "/api/posts", key, Some(&token), post.clone()).await;
+        let first_body = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+        let retry = post_with_key(state.clone(), "/api/posts", key, Some(&token), post).await;
+        assert_eq!((retry.status(), &retry.headers()[CONTENT_TYPE]), (StatusCode::CREATED, &HeaderValue::from_static(JSON)));
+        assert_eq!(to_bytes(retry.into_body(), usize::MAX).await.unwrap(), first_body);
+        let posts = sqlx::query_scalar!("SELECT COUNT(*) FROM post_table WHERE title = 'Only once'")
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!(posts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_and_expired_keys() {
+        let state = test_state().await;
+        let sign_up = |username: &str| serde_json::json!({"username": username, "password": "correct horse"});
+        let response = post_with_key(state.clone(), "/api/users", "not-a-uuid", None, sign_up("keyed_user")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // a day old key is forgotten, so the request runs again
+        let key = "1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d";
+        let created = (Utc::now() - KEY_LIFETIME - TimeDelta::minutes(1)).to_rfc3339();
+        sqlx::query!("INSERT INTO idempotency_table (key, method, path, caller, response_status, response_body, created)
+            VALUES ($1, 'POST', '/api/users', '', 418, '', $2)", key, created)
+            .execute(&state.write_pool)
+            .await
+            .unwrap();
+        assert_eq!(post_with_key(state.clone(), "/api/users", key, None, sign_up("keyed_user")).await.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_key_only_replays_to_its_caller_and_path() {
+        let state = test_state().await;
+        let key = "3e4f5a6b-7c8d-4e9f-a0b1-c2d3e4f5a6b7";
+        let author = bearer(&state, "first_author", Role::User).await;
+        let other = bearer(&state, "second_author", Role::User).await;
+        let draft = serde_json::json!({"title": "Private draft", "body": "text"});
+        let first = post_with_key(state.clone(), "/api/posts", key, Some(&author), draft).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+
+        let response = post_with_key(state.clone(), "/api/posts", key, Some(&other), serde_json::json!({"title": "Mine", "body": "text"})).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!response.headers().contains_key(IDEMPOTENT_REPLAYED));
+        let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["title"], "Mine");
+        for authorization in [None, Some("Bearer not.a.token")] {
+            let response = post_with_key(state.clone(), "/api/posts", key, authorization, serde_json::json!({})).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        // the same key on another endpoint runs that endpoint
+        let sign_up = serde_json::json!({"username": "keyed_sign_up", "password": "correct horse"});
+        let response = post_with_key(state.clone(), "/api/users", key, None, sign_up).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!response.headers().contains_key(IDEMPOTENT_REPLAYED));
+    }
+}
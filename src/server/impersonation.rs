@@ -0,0 +1,150 @@
+// Impersonation, for admins reproducing a problem only one user sees. An admin gets a short session as that user,
+// which remembers who started it so handlers can tell, and every impersonation lands in the audit log.
+use super::{acquire_with_timeout, audit::{AuditAction, AuditJob}, csrf::ValidCsrf, error::{AppError, ErrorBody}, follows::select_user_id,
+            guard::AdminGuard, responses::json_response, session::{create_impersonation_session, lookup_session, CurrentUser}, AppState};
+use anyhow::Error;
+use axum::{
+    extract::{Path, State},
+    http::{header::SET_COOKIE, StatusCode},
+    response::IntoResponse,
+};
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// An unexpired session an admin started as another user.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub(super) struct Impersonation {
+    /// The user being impersonated.
+    pub(super) username: String,
+    /// The admin doing it.
+    pub(super) admin: String,
+    pub(super) created: String,
+    pub(super) expires: String
+}
+
+/// POST request handler starting a session as `username` for the calling admin. The session cookie comes back in Set-Cookie
+/// and lasts five minutes. Admin only, and not from a session that is itself an impersonation.
+#[utoipa::path(post, path = "/api/admin/impersonate/{username}", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    responses(
+        (status = 201, description = "Impersonating, with the new session cookie in Set-Cookie", body = CurrentUser),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, admin))]
+pub(super) async fn impersonate(State(state): State<Arc<AppState>>, AdminGuard(admin): AdminGuard, _csrf: ValidCsrf,
+                                Path(username): Path<String>) -> Result<impl IntoResponse, AppError> {
+    // an impersonated admin account can't hand out further impersonations, which would hide who started them
+    if admin.impersonated_by.is_some() {
+        return Err(AppError::Forbidden);
+    }
+    let id = select_user_id(&username, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    let session = create_impersonation_session(id, admin.id, &state).await?;
+    state.jobs.dispatch(AuditJob { user_id: id, action: AuditAction::Impersonate, performed_by: Some(admin.id), detail: None }).await;
+    tracing::warn!(admin = admin.username, username, "Admin started impersonating user");
+    let user = lookup_session(&session.id, &state).await?
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))?;
+    Ok(([(SET_COOKIE, session.cookie(&state))], json_response(StatusCode::CREATED, user)))
+}
+
+/// API endpoint listing the impersonation sessions that haven't expired yet, newest first. Admin only.
+#[utoipa::path(get, path = "/api/admin/impersonate/active", tag = "users", security(("session" = [])),
+    responses(
+        (status = 200, body = Vec<Impersonation>),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn active_impersonations(State(state): State<Arc<AppState>>, _admin: AdminGuard) -> Result<impl IntoResponse, AppError> {
+    Ok(json_response(StatusCode::OK, select_active_impersonations(&state).await?))
+}
+
+/// Every unexpired session with an impersonating admin, newest first.
+async fn select_active_impersonations(state: &AppState) -> Result<Vec<Impersonation>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let now = Utc::now().to_rfc3339();
+    Ok(sqlx::query_as!(Impersonation, r#"SELECT target.username, admin.username AS admin, session_table.created AS "created!", session_table.expires AS "expires!"
+        FROM session_table
+        JOIN user_table AS target ON target.id = session_table.user_id
+        JOIN user_table AS admin ON admin.id = session_table.impersonated_by
+        WHERE session_table.expires > $1
+        ORDER BY session_table.created DESC"#,
+        now)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{csrf::CSRF_HEADER, jobs::tests::finish_jobs, session::{IMPERSONATION_LIFETIME_SECS, SESSION_COOKIE}, tests::{call, csrf_token, send, session_cookie, test_state}, Role};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::header::COOKIE};
+    use serde_json::Value;
+
+    async fn user_id(state: &AppState, username: &str) -> i64 {
+        sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = $1"#, username)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_impersonation() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        session_cookie(&state, "moderator_user", Role::Mod).await;
+        assert_eq!(send(state.clone(), "POST", "/api/admin/impersonate/nobody_here", Some(&admin)).await.0, StatusCode::NOT_FOUND);
+
+        let request = Request::post("/api/admin/impersonate/moderator_user")
+            .header(COOKIE, &admin)
+            .header(CSRF_HEADER, csrf_token(&state, &admin).await)
+            .body(Body::empty())
+            .unwrap();
+        let response = call(state.clone(), request).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains(&format!("Max-Age={IMPERSONATION_LIFETIME_SECS}")));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+
+        // the session is the target's, with their role, and knows which admin started it
+        let admin_id = user_id(&state, "admin_user").await;
+        let session = call(state.clone(), Request::get("/api/session").header(COOKIE, &cookie).body(Body::empty()).unwrap()).await;
+        let session: Value = serde_json::from_slice(&to_bytes(session.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!((&session["username"], &session["role"], &session["impersonated_by"]),
+                   (&Value::from("moderator_user"), &Value::from("mod"), &Value::from(admin_id)));
+
+        finish_jobs(&state).await;
+        let entry = sqlx::query!("SELECT action, performed_by FROM audit_log_table ORDER BY id DESC LIMIT 1")
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!((entry.action.as_str(), entry.performed_by), ("impersonate", Some(admin_id)));
+
+        let (status, body) = send(state.clone(), "GET", "/api/admin/impersonate/active", Some(&admin)).await;
+        assert_eq!(status, StatusCode::OK);
+        let active: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(active.as_array().unwrap().len(), 1);
+        assert_eq!((&active[0]["username"], &active[0]["admin"]), (&Value::from("moderator_user"), &Value::from("admin_user")));
+    }
+
+    #[tokio::test]
+    async fn test_impersonation_is_admin_only() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let moderator = session_cookie(&state, "moderator_user", Role::Mod).await;
+        session_cookie(&state, "plain_user", Role::User).await;
+        assert_eq!(send(state.clone(), "POST", "/api/admin/impersonate/plain_user", Some(&moderator)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state.clone(), "POST", "/api/admin/impersonate/plain_user", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(state.clone(), "GET", "/api/admin/impersonate/active", Some(&moderator)).await.0, StatusCode::FORBIDDEN);
+
+        // impersonating another admin doesn't let the impersonation start more of them
+        session_cookie(&state, "other_admin", Role::Admin).await;
+        let session = create_impersonation_session(user_id(&state, "other_admin").await, user_id(&state, "admin_user").await, &state).await.unwrap();
+        let impersonated = format!("{SESSION_COOKIE}={}", session.id);
+        assert_eq!(send(state.clone(), "POST", "/api/admin/impersonate/plain_user", Some(&impersonated)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state, "POST", "/api/admin/impersonate/plain_user", Some(&admin)).await.0, StatusCode::CREATED);
+    }
+}
@@ -0,0 +1,179 @@
+// Invite codes, for when REQUIRE_INVITE=true closes sign-ups to everyone without one. Admins hand out batches
+// of codes, each good for one account until it expires.
+use super::{acquire_with_timeout, csrf::ValidCsrf, error::{AppError, ErrorBody}, guard::AdminGuard, responses::json_response,
+            session::to_hex, AppState};
+use anyhow::Error;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{TimeDelta, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Connection, SqliteConnection};
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+const INVITE_CODE_BYTES: usize = 16;
+// how long a code can be redeemed for after it was made
+const INVITE_LIFETIME: TimeDelta = TimeDelta::days(7);
+// most codes one request may make
+const MAX_INVITE_BATCH: u64 = 100;
+
+/// An unused row of invite_table.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub(super) struct Invite {
+    pub(super) code: String,
+    /// Name of the admin who made it.
+    pub(super) created_by: String,
+    pub(super) created_at: String,
+    pub(super) expires_at: String
+}
+
+/// POST request handler making a batch of invite codes, from `{"count": ...}`. Admin only.
+#[utoipa::path(post, path = "/admin/invites", tag = "users", security(("session" = [])),
+    params(("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "`count`, 1 to 100 codes, defaulting to 1"),
+    responses(
+        (status = 201, body = Vec<Invite>),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn create_invites(State(state): State<Arc<AppState>>, AdminGuard(admin): AdminGuard, _csrf: ValidCsrf, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let count = match json_map.get("count") {
+        None => 1,
+        Some(count) => count.as_u64().filter(|count| (1..=MAX_INVITE_BATCH).contains(count))
+            .ok_or(AppError::BadRequest(format!("'count' must be between 1 and {MAX_INVITE_BATCH}.")))?
+    };
+    let invites = insert_invites(count, &admin.username, &state).await?;
+    tracing::info!(admin = admin.username, count, "Created invite codes");
+    Ok(json_response(StatusCode::CREATED, invites))
+}
+
+/// API endpoint listing the invite codes that are neither used nor expired, newest first. Admin only.
+#[utoipa::path(get, path = "/admin/invites", tag = "users", security(("session" = [])),
+    responses(
+        (status = 200, body = Vec<Invite>),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn list_invites(State(state): State<Arc<AppState>>, _admin: AdminGuard) -> Result<impl IntoResponse, AppError> {
+    Ok(json_response(StatusCode::OK, select_unused_invites(&state).await?))
+}
+
+/// INVITE_CODE_BYTES random bytes from the OS, hex encoded.
+fn new_invite_code() -> String {
+    let mut bytes = [0u8; INVITE_CODE_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Stores `count` new codes made by `created_by`, all or none of them.
+async fn insert_invites(count: u64, created_by: &str, state: &AppState) -> Result<Vec<Invite>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    let now = Utc::now();
+    let created_at = now.to_rfc3339();
+    let expires_at = (now + INVITE_LIFETIME).to_rfc3339();
+    let mut invites = Vec::new();
+    for _ in 0..count {
+        let code = new_invite_code();
+        sqlx::query!("INSERT INTO invite_table (code, created_by, created_at, expires_at) VALUES ($1, $2, $3, $4)",
+            code,
+            created_by,
+            created_at,
+            expires_at)
+            .execute(&mut *transaction).await?;
+        invites.push(Invite { code, created_by: created_by.to_string(), created_at: created_at.clone(), expires_at: expires_at.clone() });
+    }
+    transaction.commit().await?;
+    Ok(invites)
+}
+
+/// Every code that can still be redeemed, newest first.
+async fn select_unused_invites(state: &AppState) -> Result<Vec<Invite>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let now = Utc::now().to_rfc3339();
+    Ok(sqlx::query_as!(Invite, r#"SELECT code AS "code!", created_by, created_at, expires_at FROM invite_table
+        WHERE used_by IS NULL AND expires_at > $1 ORDER BY created_at DESC, code"#,
+        now)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// Marks an unused, unexpired invite as redeemed by `username`. Returns false if there is no such invite.
+/// Goes through `conn` so the claim can share a transaction with creating the account it's for.
+pub(super) async fn claim_invite(code: &str, username: &str, conn: &mut SqliteConnection) -> Result<bool, Error> {
+    let now = Utc::now().to_rfc3339();
+    // checking and claiming happen in one statement so two sign-ups can't both redeem the same code
+    let claim_statement = sqlx::query!("UPDATE invite_table SET used_by = $1, used_at = $2
+    WHERE code = $3 AND used_by IS NULL AND expires_at > $2",
+        username,
+        now,
+        code)
+        .execute(conn).await?;
+    Ok(claim_statement.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{csrf::CSRF_HEADER, tests::{call, csrf_token, send, session_cookie, test_state}, Role};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::header::{CONTENT_TYPE, COOKIE}};
+
+    async fn create(state: &Arc<AppState>, cookie: &str, body: Value) -> (StatusCode, Value) {
+        let request = Request::post("/admin/invites")
+            .header(COOKIE, cookie)
+            .header(CSRF_HEADER, csrf_token(state, cookie).await)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = call(state.clone(), request).await;
+        let status = response.status();
+        (status, serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list_invites() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let (status, created) = create(&state, &admin, serde_json::json!({"count": 3})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let created = created.as_array().unwrap();
+        assert_eq!(created.len(), 3);
+        assert!(created.iter().all(|invite| invite["created_by"] == "admin_user"
+            && invite["code"].as_str().unwrap().len() == INVITE_CODE_BYTES * 2));
+        assert_eq!(create(&state, &admin, serde_json::json!({})).await.0, StatusCode::CREATED);
+        for count in [0, MAX_INVITE_BATCH + 1] {
+            assert_eq!(create(&state, &admin, serde_json::json!({"count": count})).await.0, StatusCode::BAD_REQUEST);
+        }
+
+        // a redeemed code drops off the list
+        let code = created[0]["code"].as_str().unwrap();
+        let mut write_conn = state.write_pool.acquire().await.unwrap();
+        assert!(claim_invite(code, "invited_user", &mut write_conn).await.unwrap());
+        assert!(!claim_invite(code, "second_user", &mut write_conn).await.unwrap());
+        drop(write_conn);
+        let (status, body) = send(state.clone(), "GET", "/admin/invites", Some(&admin)).await;
+        assert_eq!(status, StatusCode::OK);
+        let listed: Value = serde_json::from_slice(&body).unwrap();
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 3);
+        assert!(listed.iter().all(|invite| invite["code"] != code));
+    }
+
+    #[tokio::test]
+    async fn test_invites_are_admin_only() {
+        let state = test_state().await;
+        let user = session_cookie(&state, "plain_user", Role::User).await;
+        assert_eq!(create(&state, &user, serde_json::json!({"count": 1})).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state.clone(), "GET", "/admin/invites", Some(&user)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state, "GET", "/admin/invites", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,141 @@
+// In-process background jobs, for work a request shouldn't wait on like audit log writes and emails. Jobs are
+// run one at a time, in the order they were dispatched, by a single worker task spawned in 'bootstrap()'.
+// Nothing is persisted, but 'serve' closes the queue and lets the worker finish what's queued before the database
+// pools close, so only a crash loses jobs.
+use super::AppState;
+use std::{future::Future, pin::Pin, sync::{Arc, Mutex}};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+// jobs that can be waiting before dispatching has to wait for room
+const JOB_QUEUE_CAPACITY: usize = 1024;
+
+/// Boxed future returned by `Job::execute`.
+pub(super) type JobFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// A unit of background work. Jobs handle their own errors, since there's nobody left to return them to.
+// 'async fn' would make the trait unusable as 'dyn Job', so the future is boxed by hand
+pub(super) trait Job {
+    fn execute(&self, state: Arc<AppState>) -> JobFuture<'_>;
+}
+
+/// Sending end of the job queue until it's closed, the receiving end until the worker takes it, and the worker.
+pub(super) struct JobQueue {
+    sender: Mutex<Option<mpsc::Sender<Box<dyn Job + Send + Sync>>>>,
+    receiver: Mutex<Option<mpsc::Receiver<Box<dyn Job + Send + Sync>>>>,
+    worker: Mutex<Option<JoinHandle<()>>>
+}
+
+impl JobQueue {
+    pub(super) fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(JOB_QUEUE_CAPACITY);
+        JobQueue { sender: Mutex::new(Some(sender)), receiver: Mutex::new(Some(receiver)), worker: Mutex::new(None) }
+    }
+
+    /// Queues `job` for the worker, waiting for room if the queue is full. Only a closed queue drops the job.
+    pub(super) async fn dispatch(&self, job: impl Job + Send + Sync + 'static) {
+        // cloned so the lock isn't held while waiting for room
+        let sender = self.sender.lock().expect("Job queue lock poisoned").clone();
+        let Some(sender) = sender else {
+            tracing::error!("Job queue is closed, dropping background job");
+            return;
+        };
+        if let Err(e) = sender.send(Box::new(job)).await {
+            tracing::error!("Failed to dispatch background job: {}", e);
+        }
+    }
+
+    /// Stops taking jobs and waits until the worker has run every job already queued.
+    pub(super) async fn close(&self) {
+        drop(self.sender.lock().expect("Job queue lock poisoned").take());
+        let worker = self.worker.lock().expect("Job queue lock poisoned").take();
+        if let Some(worker) = worker && let Err(e) = worker.await {
+            tracing::error!("Job worker failed: {}", e);
+        }
+    }
+}
+
+/// Spawns the task running `state.jobs`, for 'JobQueue::close' to wait on.
+pub(super) fn spawn_worker(state: &Arc<AppState>) {
+    let worker = tokio::spawn(run_jobs(state.clone()));
+    *state.jobs.worker.lock().expect("Job queue lock poisoned") = Some(worker);
+}
+
+/// Runs the jobs dispatched to `state.jobs` as they come in. Only the first call takes the queue, any later one
+/// returns straight away.
+pub(super) async fn run_jobs(state: Arc<AppState>) {
+    let receiver = state.jobs.receiver.lock().expect("Job queue lock poisoned").take();
+    let Some(mut receiver) = receiver else {
+        tracing::error!("Job worker is already running");
+        return;
+    };
+    // the queue ends once 'JobQueue::close' has dropped its sender and every queued job has been received
+    while let Some(job) = receiver.recv().await {
+        job.execute(state.clone()).await;
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::server::tests::test_state;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    /// Reports through its sender once it has run.
+    struct SignalJob(Mutex<Option<oneshot::Sender<()>>>);
+
+    impl Job for SignalJob {
+        fn execute(&self, _state: Arc<AppState>) -> JobFuture<'_> {
+            Box::pin(async move {
+                if let Some(sender) = self.0.lock().unwrap().take() {
+                    let _ = sender.send(());
+                }
+            })
+        }
+    }
+
+    /// Waits until every job dispatched so far has run. Jobs run in order, so that's once a job dispatched now has.
+    pub(in crate::server) async fn finish_jobs(state: &Arc<AppState>) {
+        let (sender, receiver) = oneshot::channel();
+        state.jobs.dispatch(SignalJob(Mutex::new(Some(sender)))).await;
+        receiver.await.expect("Job worker stopped");
+    }
+
+    #[tokio::test]
+    async fn test_dispatched_job_runs() {
+        let state = test_state().await;
+        let (sender, receiver) = oneshot::channel();
+        state.jobs.dispatch(SignalJob(Mutex::new(Some(sender)))).await;
+        tokio::time::timeout(Duration::from_millis(100), receiver).await
+            .expect("Job didn't run within 100 ms")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_runs_queued_jobs() {
+        let state = test_state().await;
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let (sender, receiver) = oneshot::channel();
+            state.jobs.dispatch(SignalJob(Mutex::new(Some(sender)))).await;
+            receivers.push(receiver);
+        }
+        state.jobs.close().await;
+        for mut receiver in receivers {
+            assert_eq!(receiver.try_recv(), Ok(()));
+        }
+
+        // a closed queue drops what it's handed
+        let (sender, mut receiver) = oneshot::channel();
+        state.jobs.dispatch(SignalJob(Mutex::new(Some(sender)))).await;
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_second_worker_returns() {
+        let state = test_state().await;
+        // test_state already spawned one, and once a job has run it has surely taken the queue
+        finish_jobs(&state).await;
+        tokio::time::timeout(Duration::from_millis(100), run_jobs(state)).await.unwrap();
+    }
+}
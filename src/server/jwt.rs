@@ -0,0 +1,145 @@
+// Bearer token authentication for API consumers. Tokens are RS256 signed JWTs handed out by
+// POST /api/auth/token and are short lived, so unlike sessions nothing about them is stored.
+use super::{error::{AppError, ErrorBody}, responses::json_response, select_by_username, verify_credentials, AppState, Role};
+use anyhow::{anyhow, Error};
+use axum::{
+    extract::{rejection::JsonRejection, FromRequestParts, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+pub(super) const TOKEN_LIFETIME_SECS: i64 = 15 * 60;
+
+/// The RSA key pair tokens are signed and verified with.
+pub(super) struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey
+}
+
+/// Claims carried by every token. `sub` is the user's id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct Claims {
+    pub(super) sub: String,
+    pub(super) role: Role,
+    pub(super) iat: i64,
+    pub(super) exp: i64
+}
+
+/// Extractor for a valid `Authorization: Bearer` token. Missing, malformed or expired tokens are rejected with 401.
+#[derive(Debug)]
+pub(super) struct AuthBearer {
+    pub(super) user_id: i64,
+    pub(super) claims: Claims
+}
+
+impl JwtKeys {
+    /// Builds the key pair from PEM encoded RSA keys.
+    pub(super) fn from_pem(private_key: &[u8], public_key: &[u8]) -> Result<Self, Error> {
+        Ok(JwtKeys {
+            encoding: EncodingKey::from_rsa_pem(private_key)?,
+            decoding: DecodingKey::from_rsa_pem(public_key)?
+        })
+    }
+
+    /// Signs a token for `user_id` valid for TOKEN_LIFETIME_SECS from now.
+    pub(super) fn issue(&self, user_id: i64, role: Role) -> Result<String, Error> {
+        let iat = Utc::now().timestamp();
+        let claims = Claims { sub: user_id.to_string(), role, iat, exp: iat + TOKEN_LIFETIME_SECS };
+        Ok(jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.encoding)?)
+    }
+
+    /// Checks a token's signature and expiry, returning its claims.
+    pub(super) fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let mut validation = Validation::new(Algorithm::RS256);
+        // tokens expire exactly when 'exp' says, the default minute of slack would stretch them to 16
+        validation.leeway = 0;
+        Ok(jsonwebtoken::decode::<Claims>(token, &self.decoding, &validation)?.claims)
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for AuthBearer {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let keys = state.jwt.as_ref().ok_or(AppError::Unauthorized)?;
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AppError::Unauthorized)?;
+        let claims = keys.verify(token).map_err(|e| {
+            tracing::info!("Rejected bearer token: {}", e);
+            AppError::Unauthorized
+        })?;
+        let user_id = claims.sub.parse().map_err(|_| AppError::Unauthorized)?;
+        Ok(AuthBearer { user_id, claims })
+    }
+}
+
+/// POST request handler exchanging a username and password for a bearer token.
+#[utoipa::path(post, path = "/api/auth/token", tag = "auth",
+    request_body(content = Object, description = "`username` and `password`, plus `totp_code` if the account has 2FA enabled"),
+    responses(
+        (status = 200, description = "`access_token`, `token_type` and `expires_in` (seconds)", body = Object),
+        (status = 400, description = "Malformed payload, or a missing `totp_code`", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 429, description = "Too many failed logins to this username", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn issue_token(state: State<Arc<AppState>>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let keys = state.jwt.as_ref()
+        .ok_or_else(|| AppError::Internal(anyhow!("Token requested but JWT_PRIVATE_KEY_PATH is not configured.")))?;
+    let Json(json_map) = result?;
+    let (id, username) = verify_credentials(&json_map, &state).await?;
+    let user = select_by_username(&username, &state).await.transpose()?.ok_or(AppError::Unauthorized)?;
+    let token = keys.issue(id, user.role)?;
+    tracing::info!(username, "Issued bearer token");
+    Ok(json_response(StatusCode::OK, serde_json::json!({"access_token": token, "token_type": "Bearer", "expires_in": TOKEN_LIFETIME_SECS})))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::server::tests::{post_json, test_state};
+
+    pub(in crate::server) fn test_keys() -> JwtKeys {
+        JwtKeys::from_pem(include_bytes!("testdata/jwt_private.pem"), include_bytes!("testdata/jwt_public.pem")).unwrap()
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let keys = test_keys();
+        let claims = keys.verify(&keys.issue(7, Role::User).unwrap()).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.role, claims.exp - claims.iat), ("7", Role::User, TOKEN_LIFETIME_SECS));
+        assert!(keys.verify("not.a.token").is_err());
+    }
+
+    #[test]
+    fn test_expired_token_is_rejected() {
+        let keys = test_keys();
+        let iat = Utc::now().timestamp() - 2 * TOKEN_LIFETIME_SECS;
+        let claims = Claims { sub: "7".to_string(), role: Role::User, iat, exp: iat + TOKEN_LIFETIME_SECS };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &keys.encoding).unwrap();
+        assert!(keys.verify(&token).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_token_endpoint() {
+        let state = test_state().await;
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "token_user", "password": "correct horse"})).await;
+        let (status, body) = post_json(state.clone(), "/api/auth/token",
+                                       serde_json::json!({"username": "token_user", "password": "correct horse"})).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let claims = state.jwt.as_ref().unwrap().verify(body["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.role, Role::User);
+        let (status, _) = post_json(state, "/api/auth/token",
+                                    serde_json::json!({"username": "token_user", "password": "wrong horse"})).await;
+        assert_eq!(status, axum::http::StatusCode::UNAUTHORIZED);
+    }
+}
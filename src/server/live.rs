@@ -0,0 +1,76 @@
+// Live updates over WebSocket. Every open socket counts as one visitor online; the count lives in a
+// watch channel in AppState, so each socket's task hears about every change and forwards it.
+use super::AppState;
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, State},
+    response::Response,
+};
+use std::sync::Arc;
+
+/// GET request handler upgrading to a WebSocket that receives `{"online": n}` whenever the number of open sockets changes.
+#[tracing::instrument(skip_all)]
+pub(super) async fn ws(State(state): State<Arc<AppState>>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| online_count_socket(socket, state))
+}
+
+/// Sends the online count over `socket` now and after every change, until the client goes away.
+async fn online_count_socket(mut socket: WebSocket, state: Arc<AppState>) {
+    let mut online = state.online.subscribe();
+    state.online.send_modify(|count| *count += 1);
+    loop {
+        let count = *online.borrow_and_update();
+        if socket.send(Message::text(serde_json::json!({"online": count}).to_string())).await.is_err() {
+            break;
+        }
+        // wait for the next change, ignoring whatever the client sends unless it closes the socket
+        let closed = loop {
+            tokio::select! {
+                changed = online.changed() => break changed.is_err(),
+                received = socket.recv() => match received {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break true,
+                    Some(Ok(_)) => {}
+                }
+            }
+        };
+        if closed {
+            break;
+        }
+    }
+    state.online.send_modify(|count| *count -= 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::{app, tests::test_state};
+    use axum::{extract::Request, ServiceExt};
+    use futures_util::StreamExt;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    async fn next_count(socket: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>) -> u64 {
+        let message = socket.next().await.unwrap().unwrap();
+        serde_json::from_str::<serde_json::Value>(message.to_text().unwrap()).unwrap()["online"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_online_count_broadcast() {
+        let state = test_state().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let service = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app(state.clone()));
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+
+        let (mut first, _) = connect_async(&url).await.unwrap();
+        assert_eq!(next_count(&mut first).await, 1);
+        let (mut second, _) = connect_async(&url).await.unwrap();
+        assert_eq!(next_count(&mut second).await, 2);
+        assert_eq!(next_count(&mut first).await, 2);
+
+        second.close(None).await.unwrap();
+        assert_eq!(next_count(&mut first).await, 1);
+        first.close(None).await.unwrap();
+        // nobody is left to be told, so watch the count directly
+        tokio::time::timeout(Duration::from_secs(5), state.online.subscribe().wait_for(|count| *count == 0))
+            .await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+// Throttle on failed logins, so passwords can't be guessed at whatever rate the per-IP limit allows. Failures are
+// counted per username over a window starting at the first of them, and once a username has MAX_FAILED_LOGINS
+// of them every login to it is refused until the window is over, right password or not.
+use super::{acquire_with_timeout, AppState};
+use anyhow::Error;
+use chrono::{TimeDelta, Utc};
+use std::{sync::Arc, time::Duration};
+
+// failures allowed within a window before logins to the username are refused
+const MAX_FAILED_LOGINS: i64 = 5;
+// how long a window lasts, which is also how long a throttled username waits
+pub(super) const LOGIN_WINDOW_SECS: u64 = 10 * 60;
+const LOGIN_WINDOW: TimeDelta = TimeDelta::seconds(LOGIN_WINDOW_SECS as i64);
+const LOGIN_ATTEMPT_CLEANUP_INTERVAL: Duration = Duration::from_secs(LOGIN_WINDOW_SECS);
+
+/// Whether `username` has used up its failed logins for the current window.
+pub(super) async fn is_throttled(username: &str, state: &AppState) -> Result<bool, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let cutoff = (Utc::now() - LOGIN_WINDOW).to_rfc3339();
+    let attempts = sqlx::query_scalar!("SELECT attempts FROM login_attempt_table WHERE username = $1 AND window_start > $2", username, cutoff)
+        .fetch_optional(&mut *read_conn).await?;
+    Ok(attempts.is_some_and(|attempts| attempts >= MAX_FAILED_LOGINS))
+}
+
+/// Counts a failed login to `username`, starting a new window if the last one is over.
+pub(super) async fn record_failure(username: &str, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let now = Utc::now();
+    let cutoff = (now - LOGIN_WINDOW).to_rfc3339();
+    let now = now.to_rfc3339();
+    // every expression in the SET sees the row as it was, so both CASEs agree on whether the window is over
+    sqlx::query!("INSERT INTO login_attempt_table (username, attempts, window_start) VALUES ($1, 1, $2)
+        ON CONFLICT (username) DO UPDATE SET
+            attempts = CASE WHEN window_start > $3 THEN attempts + 1 ELSE 1 END,
+            window_start = CASE WHEN window_start > $3 THEN window_start ELSE $2 END",
+        username,
+        now,
+        cutoff)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+/// Forgets the failed logins to `username`, after a successful one.
+pub(super) async fn reset_failures(username: &str, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    sqlx::query!("DELETE FROM login_attempt_table WHERE username = $1", username)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+/// Removes the rows of windows that are over, returning how many there were.
+pub(super) async fn delete_stale_attempts(state: &AppState) -> Result<u64, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let cutoff = (Utc::now() - LOGIN_WINDOW).to_rfc3339();
+    let delete_statement = sqlx::query!("DELETE FROM login_attempt_table WHERE window_start <= $1", cutoff)
+        .execute(&mut *write_conn).await?;
+    Ok(delete_statement.rows_affected())
+}
+
+/// Background task sweeping finished windows out of login_attempt_table. Spawned once at startup.
+pub(super) async fn prune_login_attempts_task(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(LOGIN_ATTEMPT_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match delete_stale_attempts(&state).await {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!("Removed {} stale login attempt rows", removed),
+            Err(e) => tracing::error!("Failed to remove stale login attempts: {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::{post_json, test_state};
+    use axum::http::StatusCode;
+
+    async fn login(state: &Arc<AppState>, username: &str, password: &str) -> StatusCode {
+        post_json(state.clone(), "/api/login", serde_json::json!({"username": username, "password": password})).await.0
+    }
+
+    #[tokio::test]
+    async fn test_sixth_failure_is_throttled() {
+        let state = test_state().await;
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "guessed_user", "password": "correct horse"})).await;
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert_eq!(login(&state, "guessed_user", "wrong horse").await, StatusCode::UNAUTHORIZED);
+        }
+        // the right password doesn't help until the window is over
+        assert_eq!(login(&state, "guessed_user", "correct horse").await, StatusCode::TOO_MANY_REQUESTS);
+        // other usernames aren't affected
+        assert_eq!(login(&state, "someone_else", "wrong horse").await, StatusCode::UNAUTHORIZED);
+
+        let expired = (Utc::now() - LOGIN_WINDOW - TimeDelta::minutes(1)).to_rfc3339();
+        sqlx::query!("UPDATE login_attempt_table SET window_start = $1 WHERE username = 'guessed_user'", expired)
+            .execute(&state.write_pool)
+            .await
+            .unwrap();
+        assert_eq!(delete_stale_attempts(&state).await.unwrap(), 1);
+        assert_eq!(login(&state, "guessed_user", "correct horse").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_successful_login_resets_failures() {
+        let state = test_state().await;
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "forgetful_user", "password": "correct horse"})).await;
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert_eq!(login(&state, "forgetful_user", "wrong horse").await, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(login(&state, "forgetful_user", "correct horse").await, StatusCode::OK);
+        assert!(!is_throttled("forgetful_user", &state).await.unwrap());
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert_eq!(login(&state, "forgetful_user", "wrong horse").await, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(login(&state, "forgetful_user", "correct horse").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,86 @@
+// Maintenance mode, for taking the site offline without stopping the process, e.g. while migrating the
+// database by hand. While it is on every request gets a 503, except health checks and turning it back off.
+use super::{csrf::ValidCsrf, error::{error_response, AppError, ErrorBody}, guard::AdminGuard, responses::json_response, AppState};
+use axum::{
+    extract::{rejection::JsonRejection, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::Value;
+use std::sync::{atomic::Ordering, Arc};
+
+pub(super) const MAINTENANCE_PATH: &str = "/api/admin/maintenance";
+// paths that keep working in maintenance mode
+const EXEMPT_PATHS: [&str; 2] = ["/health", MAINTENANCE_PATH];
+// seconds clients are told to wait before trying again
+const RETRY_AFTER_SECS: u32 = 60;
+
+/// POST request handler switching maintenance mode on or off. Admin only.
+#[utoipa::path(post, path = "/api/admin/maintenance", tag = "users", security(("session" = [])),
+    params(("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "`enabled`, true or false"),
+    responses(
+        (status = 200, description = "`{\"enabled\": ...}`, the mode now in effect", body = Object),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn set_maintenance(State(state): State<Arc<AppState>>, _admin: AdminGuard, _csrf: ValidCsrf,
+                                    result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let enabled = json_map.get("enabled").and_then(Value::as_bool)
+        .ok_or(AppError::BadRequest("JSON payload structure invalid.".to_string()))?;
+    state.maintenance.store(enabled, Ordering::Relaxed);
+    tracing::warn!(enabled, "Switched maintenance mode");
+    Ok(json_response(StatusCode::OK, serde_json::json!({"enabled": enabled})))
+}
+
+/// Middleware answering everything but EXEMPT_PATHS with a 503 while maintenance mode is on.
+pub(super) async fn maintenance_mode(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if !state.maintenance.load(Ordering::Relaxed) || EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, "The site is down for maintenance.");
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{csrf::CSRF_HEADER, tests::{call, csrf_token, get_request, send, session_cookie, test_state}, Role};
+    use axum::{body::Body, http::header::{CONTENT_TYPE, COOKIE}};
+
+    async fn toggle(state: Arc<AppState>, cookie: &str, enabled: bool) -> Response {
+        let request = Request::post(MAINTENANCE_PATH)
+            .header(CONTENT_TYPE, "application/json")
+            .header(COOKIE, cookie)
+            .header(CSRF_HEADER, csrf_token(&state, cookie).await)
+            .body(Body::from(serde_json::json!({"enabled": enabled}).to_string()))
+            .unwrap();
+        call(state, request).await
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let user = session_cookie(&state, "plain_user", Role::User).await;
+        assert_eq!(toggle(state.clone(), &user, true).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(send(state.clone(), "POST", MAINTENANCE_PATH, None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(toggle(state.clone(), &admin, true).await.status(), StatusCode::OK);
+
+        let response = call(state.clone(), Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+        assert_eq!(get_request(state.clone(), "/api/users").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get_request(state.clone(), "/health").await.0, StatusCode::OK);
+
+        // the admin can still switch it back off
+        assert_eq!(toggle(state.clone(), &admin, false).await.status(), StatusCode::OK);
+        assert_eq!(get_request(state, "/").await.0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,33 @@
+// Markdown rendering for post bodies. Bodies are stored as the author wrote them and rendered on the
+// way out, so the output is always sanitised against the current rules.
+use pulldown_cmark::{html, Options, Parser};
+
+/// Renders `input` as Markdown to HTML, then strips anything unsafe (scripts, event handlers, ...) with ammonia.
+pub(super) fn render_markdown(input: &str) -> String {
+    let parser = Parser::new_ext(input, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES);
+    let mut output = String::with_capacity(input.len() * 3 / 2);
+    html::push_html(&mut output, parser);
+    ammonia::clean(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_markdown() {
+        assert_eq!(render_markdown("# Title"), "<h1>Title</h1>\n");
+        assert_eq!(render_markdown("```rust\nlet x = 1;\n```"), "<pre><code>let x = 1;\n</code></pre>\n");
+        assert_eq!(render_markdown("[home](https://example.com)"),
+                   "<p><a href=\"https://example.com\" rel=\"noopener noreferrer\">home</a></p>\n");
+    }
+
+    #[test]
+    fn test_render_markdown_strips_scripts() {
+        let rendered = render_markdown("before\n\n<script>alert(1)</script>\n\nafter");
+        assert!(!rendered.contains("<script"));
+        assert!(!rendered.contains("alert(1)"));
+        assert!(rendered.contains("<p>before</p>") && rendered.contains("<p>after</p>"));
+        assert!(!render_markdown("<img src=x onerror=alert(1)>").contains("onerror"));
+    }
+}
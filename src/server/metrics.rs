@@ -0,0 +1,113 @@
+// Prometheus metrics. Every request is counted and timed by `track_metrics`, and GET /metrics renders the
+// lot in Prometheus' text format for whoever is allowed by METRICS_ALLOW_CIDR (loopback by default).
+use super::{error::AppError, responses::plain_response, AppState};
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::{net::SocketAddr, sync::{Arc, OnceLock}, time::Instant};
+
+// the recorder is process wide and can only be installed once
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+// networks allowed to read /metrics when METRICS_ALLOW_CIDR isn't set
+pub(super) const DEFAULT_METRICS_ALLOW_CIDR: &str = "127.0.0.0/8,::1/128";
+// label for requests that didn't match a route, so unknown paths can't blow up the label set
+const UNMATCHED_PATH: &str = "unmatched";
+
+/// Installs the Prometheus recorder on first call. Later calls (from any thread) get the same handle.
+pub(super) fn init_metrics() -> &'static PrometheusHandle {
+    PROMETHEUS.get_or_init(|| match PrometheusBuilder::new().install_recorder() {
+        Ok(handle) => handle,
+        Err(e) => {
+            tracing::error!("Failed to install the Prometheus recorder: {}", e);
+            std::process::exit(1);
+        }
+    })
+}
+
+/// Parses a comma separated list of networks such as METRICS_ALLOW_CIDR.
+pub(super) fn parse_allow_cidrs(cidrs: &str) -> Result<Vec<IpNet>, ipnet::AddrParseError> {
+    cidrs.split(',').map(|cidr| cidr.trim().parse()).collect()
+}
+
+/// Records a request's duration in `http_request_duration_seconds` when dropped, so requests that are cut
+/// short are timed too.
+struct RequestTimer {
+    start: Instant,
+    method: Method,
+    path: String
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        metrics::histogram!("http_request_duration_seconds", "method" => self.method.to_string(), "path" => self.path.clone())
+            .record(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Middleware counting every request in `http_requests_total` by method, route and status, and timing it.
+pub(super) async fn track_metrics(request: Request, next: Next) -> Response {
+    // the route's pattern rather than the actual path, so /user/{username} is one series and not one per user
+    let path = request.extensions().get::<MatchedPath>().map_or(UNMATCHED_PATH, MatchedPath::as_str).to_string();
+    let method = request.method().clone();
+    let _timer = RequestTimer { start: Instant::now(), method: method.clone(), path: path.clone() };
+    let response = next.run(request).await;
+    metrics::counter!("http_requests_total", "method" => method.to_string(), "path" => path, "status" => response.status().as_u16().to_string())
+        .increment(1);
+    response
+}
+
+/// GET request handler rendering the metrics for Prometheus. Only clients in state.metrics_allow may read them.
+#[tracing::instrument(skip(state))]
+pub(super) async fn metrics(State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>) -> Result<impl IntoResponse, AppError> {
+    if !state.metrics_allow.iter().any(|net| net.contains(&addr.ip())) {
+        return Err(AppError::Forbidden);
+    }
+    Ok(plain_response(StatusCode::OK, init_metrics().render()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::{get_request, test_app_state, test_state};
+
+    #[test]
+    fn test_parse_allow_cidrs() {
+        let nets = parse_allow_cidrs(DEFAULT_METRICS_ALLOW_CIDR).unwrap();
+        assert!(nets.iter().any(|net| net.contains(&"127.0.0.1".parse::<std::net::IpAddr>().unwrap())));
+        assert!(nets.iter().any(|net| net.contains(&"::1".parse::<std::net::IpAddr>().unwrap())));
+        assert!(!nets.iter().any(|net| net.contains(&"10.0.0.1".parse::<std::net::IpAddr>().unwrap())));
+        assert_eq!(parse_allow_cidrs("10.0.0.0/8, 192.168.1.0/24").unwrap().len(), 2);
+        assert!(parse_allow_cidrs("10.0.0.0").is_err());
+        assert!(parse_allow_cidrs("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_counted() {
+        let state = test_state().await;
+        get_request(state.clone(), "/api/posts").await;
+        let (status, body) = get_request(state, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        // other tests share the recorder, so only check this request's series is there with a non-zero count
+        let count = body.lines()
+            .find(|line| line.starts_with("http_requests_total{") && line.contains(r#"path="/api/posts""#) && line.contains(r#"status="200""#))
+            .and_then(|line| line.rsplit(' ').next())
+            .and_then(|count| count.parse::<f64>().ok())
+            .unwrap();
+        assert!(count >= 1.0);
+        assert!(body.contains("http_request_duration_seconds"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_only_for_allowed_networks() {
+        // test requests come from 127.0.0.1
+        let state = Arc::new(AppState { metrics_allow: parse_allow_cidrs("10.0.0.0/8").unwrap(), ..test_app_state().await });
+        assert_eq!(get_request(state, "/metrics").await.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,3007 @@
+mod api_tokens;
+mod audit;
+mod bookmarks;
+mod cli;
+mod comments;
+mod conditional;
+mod cors;
+mod csrf;
+mod error;
+mod feed;
+mod follows;
+mod guard;
+mod i18n;
+mod idempotency;
+mod impersonation;
+mod invites;
+mod jobs;
+mod jwt;
+mod live;
+mod login_throttle;
+mod maintenance;
+mod markdown;
+mod metrics;
+mod openapi;
+mod pagination;
+mod password_reset;
+mod posts;
+mod responses;
+mod series;
+mod session;
+mod shutdown;
+mod sitemap;
+mod text_analysis;
+mod totp;
+
+use anyhow::{anyhow, Error};
+use argon2::{password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+use axum::http::header::{HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_SECURITY_POLICY, COOKIE, LOCATION, REFERRER_POLICY, SET_COOKIE,
+                         STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS};
+use axum::response::Response;
+use axum_server::tls_rustls::RustlsConfig;
+use axum::{extract::{rejection::{JsonRejection, QueryRejection}, ConnectInfo, DefaultBodyLimit, Path, Query, Request, State}, http::{HeaderMap, StatusCode}, middleware, response::{IntoResponse, Redirect}, routing::{delete, get, post, put, MethodRouter}, Extension, Json, Router, ServiceExt};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use cli::{Cli, Command};
+pub use cli::ServeArgs;
+use governor::middleware::NoOpMiddleware;
+use lru::LruCache;
+use maxminddb::geoip2;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{pool::PoolConnection, sqlite, sqlite::{Sqlite, SqliteConnectOptions, SqliteConnection, SqliteJournalMode}, Connection, Executor, Pool, QueryBuilder};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    sync::{atomic::AtomicBool, Arc, LazyLock, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use audit::{append_audit, AuditAction, AuditJob};
+use conditional::conditional_response;
+use csrf::ValidCsrf;
+use error::{AppError, ErrorBody};
+use pagination::Page;
+use guard::AdminGuard;
+use i18n::{I18n, Locale};
+use responses::{csv_response, html_response, json_response, plain_response, raw_json_response};
+use session::{create_session, expire_session, expired_cookie, CurrentUser};
+use tera::Tera;
+use tower_http::{
+    compression::{predicate::{NotForContentType, Predicate}, CompressionLayer, DefaultPredicate},
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
+    set_header::SetResponseHeaderLayer,
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tower_governor::{
+    governor::{GovernorConfig, GovernorConfigBuilder},
+    key_extractor::PeerIpKeyExtractor,
+    GovernorError, GovernorLayer,
+};
+use tokio::sync::watch;
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+use unicode_normalization::UnicodeNormalization;
+use utoipa::{IntoParams, ToSchema};
+
+// Page templating
+static TEMPLATES: OnceLock<Tera> = OnceLock::new();
+
+/// Every fixed segment of the route paths, like 'search' of /api/users/search, plus EXTRA_RESERVED_NAMES, all
+/// lowercase. A username must never be one of these, so it can't shadow a route.
+static RESERVED_NAMES: LazyLock<HashSet<String>> = LazyLock::new(|| {
+    routes().into_iter().chain(unlimited_routes())
+        .flat_map(|(path, _)| path.split('/'))
+        .filter(|segment| !segment.is_empty() && !segment.starts_with('{'))
+        .chain(EXTRA_RESERVED_NAMES.iter().copied())
+        .map(str::to_lowercase)
+        .collect()
+});
+
+/// Compiles the page templates on first call, with `i18n` behind their `t` function. Later calls (from any thread)
+/// get the same instance.
+fn init_templates(i18n: &Arc<I18n>) -> &'static Tera {
+    TEMPLATES.get_or_init(|| {
+        let source = "src/templates/**/*.html";
+        match Tera::new(source) {
+            Ok(mut t) => {
+                t.register_filter("flag", flag_filter);
+                t.register_function("t", i18n::translate_function(i18n.clone()));
+                tracing::info!("Source template compiled correctly");
+                t
+            },
+            Err(e) => {
+                tracing::error!("Parsing error(s) encountered: {}", e);
+                std::process::exit(1);
+            }
+        }
+    })
+}
+
+/// The compiled page templates.
+fn templates() -> &'static Tera {
+    TEMPLATES.get().expect("Templates are compiled in 'bootstrap()' before any request is served")
+}
+
+// constant(s)
+// DATABASE_URL value for a throwaway database that lives in memory
+const IN_MEMORY_DATABASE: &str = ":memory:";
+const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(5);
+// how many users select_by_username keeps in memory unless USER_CACHE_SIZE says otherwise
+const DEFAULT_USER_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+// largest request body accepted unless MAX_REQUEST_BODY_BYTES says otherwise
+const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 1024 * 1024;
+// how many posts, users and so on make a page unless PER_PAGE says otherwise, and what PER_PAGE may be
+const DEFAULT_PER_PAGE: u32 = 32;
+const PER_PAGE_RANGE: RangeInclusive<u32> = 1..=200;
+// each database check made by /health gives up after this long
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// upper bound on the page size a client may ask the JSON listings for
+const MAX_PER_PAGE: u32 = 100;
+const RATE_LIMIT_BURST: u32 = 10;
+const RATE_LIMIT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+// acquisitions slower than this get logged even if they succeed
+const SLOW_ACQUIRE_THRESHOLD: Duration = Duration::from_millis(500);
+// sent with every response when serving HTTPS: browsers then refuse plain HTTP to the site for a year
+const HSTS: &str = "max-age=31536000; includeSubDomains";
+// the layout pulls its stylesheet from unpkg, so that one origin is allowed styles on top of the site's own
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; style-src 'self' https://unpkg.com";
+// CycloneDX bill of materials generated from Cargo.lock by build.rs
+const SBOM: &str = include_str!(concat!(env!("OUT_DIR"), "/sbom.json"));
+// user_table columns added after the table was first deployed but before migrations existed, with their definitions.
+// Databases from that era get them through ALTER TABLE in 'bootstrap()'. New columns belong in migrations/.
+const ADDED_USER_COLUMNS: &[(&str, &str)] = &[("country_code", "TEXT"), ("password_hash", "TEXT"), ("bio", "TEXT"), ("email", "TEXT")];
+// names kept from users although no route has them as a segment, see 'RESERVED_NAMES'
+const EXTRA_RESERVED_NAMES: &[&str] = &["healthz", "static", "uploads"];
+
+/// What a user may do on the site. Stored in user_table.role as 0 admin, 1 mod or 2 user, since sqlite has no enums,
+/// and sent over the API as `"admin"`, `"mod"` or `"user"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    Admin = 0,
+    Mod = 1,
+    User = 2
+}
+
+impl Role {
+    /// Whether this role has every privilege `other` has: admins outrank mods, who outrank users.
+    fn at_least(self, other: Role) -> bool {
+        matches!((self, other), (Role::Admin, _) | (Role::Mod, Role::Mod | Role::User) | (Role::User, Role::User))
+    }
+}
+
+impl TryFrom<u32> for Role {
+    type Error = ();
+
+    fn try_from(role: u32) -> Result<Self, Self::Error> {
+        match role {
+            0 => Ok(Role::Admin),
+            1 => Ok(Role::Mod),
+            2 => Ok(Role::User),
+            _ => Err(())
+        }
+    }
+}
+
+impl From<Role> for u32 {
+    fn from(role: Role) -> Self {
+        role as u32
+    }
+}
+
+/// Reads a user_table.role value, which sqlx hands back as an i64.
+fn role_from_db(role: i64) -> Result<Role, Error> {
+    u32::try_from(role).ok().and_then(|role| Role::try_from(role).ok())
+        .ok_or(anyhow!("Invalid role {role} in user_table."))
+}
+
+#[derive(Serialize, Debug, Clone, sqlx::FromRow, ToSchema)]
+struct User {
+    // size of values will not change while in-memory, so a Box serves better than a String here
+    username: String,
+    // stored as RFC 3339 text, which sqlx's chrono support reads and writes
+    #[serde(serialize_with = "serialize_rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    last_online: DateTime<Utc>,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    created: DateTime<Utc>,
+    #[sqlx(try_from = "u32")]
+    role: Role,
+    // ISO 3166-1 alpha-2 code inferred from the sign-up IP, only recorded when GeoIP is configured
+    country_code: Option<String>,
+    // optional profile fields, only ever set through PATCH /api/users/{username}
+    bio: Option<String>,
+    email: Option<String>,
+    website: Option<String>,
+    // never serialized, and public queries don't select it: it's only read back through 'select_hash_by_username'
+    #[serde(skip)]
+    #[sqlx(default)]
+    password_hash: Box<str>
+}
+
+/// Serializes a timestamp the way they have always been stored and returned: RFC 3339 with a `+00:00` offset.
+fn serialize_rfc3339<S: serde::Serializer>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&timestamp.to_rfc3339())
+}
+
+/// Query string of GET /api/users.
+#[derive(Debug, Deserialize, IntoParams)]
+struct UserListParams {
+    /// One of `username` (the default), `created` or `last_online`.
+    sort_by: Option<String>,
+    /// `asc` (the default) or `desc`.
+    order: Option<String>,
+    /// Only list users with this role: `admin`, `mod` or `user`.
+    role: Option<Role>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+    // cursor pagination, see 'UserCursor'
+    /// Switches to cursor pagination: list users whose name sorts after this one.
+    after: Option<String>,
+    /// Page size for cursor pagination.
+    limit: Option<u32>
+}
+
+/// A validated GET /api/users query. `sort_column` only ever holds one of a fixed set of column names.
+#[derive(Debug, PartialEq)]
+struct UserListing {
+    sort_column: &'static str,
+    descending: bool,
+    role: Option<Role>,
+    page: u32,
+    per_page: u32
+}
+
+/// A line of the CSV export of GET /api/users.
+#[derive(Serialize, Debug, PartialEq, sqlx::FromRow)]
+struct UserCsvRow {
+    username: String,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    last_online: DateTime<Utc>,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    created: DateTime<Utc>,
+    #[sqlx(try_from = "u32")]
+    role: Role
+}
+
+/// A validated cursor paginated GET /api/users query: up to `limit` users after `after`, in username order.
+/// Unlike offset pages these stay cheap however far in a client gets.
+#[derive(Debug, PartialEq)]
+struct UserCursor {
+    after: Option<String>,
+    role: Option<Role>,
+    limit: u32
+}
+
+/// Query string of GET /api/users/search.
+#[derive(Debug, Deserialize, IntoParams)]
+struct SearchParams {
+    /// 2 to 32 characters to look for in usernames, ignoring case.
+    q: String
+}
+
+/// Fields a PATCH to /api/users/{username} may change. None leaves the stored value untouched.
+#[derive(Debug, Default, PartialEq)]
+struct UserUpdate {
+    bio: Option<String>,
+    email: Option<String>,
+    website: Option<String>,
+    last_online: Option<String>
+}
+
+impl User {
+    fn new(username: String, role: Role) -> Self {
+        let now = Utc::now();
+        User {
+            username,
+            last_online: now,
+            created: now,
+            role,
+            country_code: None,
+            bio: None,
+            email: None,
+            website: None,
+            password_hash: Box::default()
+        }
+    }
+    
+    // one argument per user_table column. Fails if 'role' isn't one of the stored role values.
+    #[allow(clippy::too_many_arguments)]
+    fn create_from_db(username: String, last_online: DateTime<Utc>, created: DateTime<Utc>, role: i64, country_code: Option<String>,
+                      bio: Option<String>, email: Option<String>, website: Option<String>) -> Result<Self, Error> {
+        Ok(User {
+            username,
+            last_online,
+            created,
+            country_code,
+            bio,
+            email,
+            website,
+            password_hash: Box::default(),
+            role: role_from_db(role)?
+        })
+    }
+
+    /// Number of whole days since the account was created.
+    fn age_days(&self) -> u32 {
+        self.age_days_at(Utc::now())
+    }
+
+    fn age_days_at(&self, now: DateTime<Utc>) -> u32 {
+        // clock skew can put 'created' slightly in the future, which should read as 0 days rather than wrap
+        (now - self.created).num_days().max(0) as u32
+    }
+}
+
+/// Formats an account age as "Member for X days", switching to months after 30 days and years after 365.
+fn format_tenure(days: u32) -> String {
+    let (amount, unit) = match days {
+        0..30 => (days, "day"),
+        30..365 => (days / 30, "month"),
+        _ => (days / 365, "year"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("Member for {amount} {unit}{plural}")
+}
+
+/// Values of the security headers sent with every response that operators may want to change.
+#[derive(Debug, Clone)]
+pub struct SecurityHeadersConfig {
+    pub content_security_policy: HeaderValue
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig { content_security_policy: HeaderValue::from_static(DEFAULT_CONTENT_SECURITY_POLICY) }
+    }
+}
+
+pub struct AppState {
+    read_pool: Pool<sqlite::Sqlite>,
+    write_pool: Pool<sqlite::Sqlite>,
+    per_page: u32,
+    acquire_timeout: Duration,
+    // where the site is reachable from outside, always ending in '/'
+    base_url: String,
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    // when set, sign-ups must redeem an unused code from invite_table
+    require_invite: bool,
+    // bearer tokens are only issued or accepted when a key pair is configured
+    jwt: Option<jwt::JwtKeys>,
+    // number of open /ws sockets, which each get told whenever it changes
+    online: watch::Sender<u32>,
+    // certificate and key to serve HTTPS with. None serves plain HTTP, e.g. behind a TLS terminating proxy.
+    tls: Option<RustlsConfig>,
+    security_headers: SecurityHeadersConfig,
+    // clients allowed to read /metrics
+    metrics_allow: Vec<ipnet::IpNet>,
+    // recently looked up users by name. Anything writing to user_table must call 'forget_cached_user'.
+    user_cache: Mutex<LruCache<Box<str>, User>>,
+    // requests with bigger bodies get a 413 before reaching any handler
+    max_request_body_bytes: usize,
+    // while set, every route but /health and the switch itself answers 503
+    maintenance: AtomicBool,
+    // UI strings of every locale the pages can be shown in
+    i18n: Arc<I18n>,
+    // work handed off to run after the response, see 'jobs::run_jobs'
+    jobs: jobs::JobQueue,
+    // origins browsers may call the site from besides its own
+    cors_origins: cors::CorsOrigins
+}
+
+#[tokio::main(flavor = "multi_thread")]
+pub async fn main() {
+    match Cli::parse().command.unwrap_or(Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => serve(&args).await,
+        Command::CreateAdmin { username, password } => {
+            let state = bootstrap(&ServeArgs::default()).await;
+            if let Err(e) = create_admin_user(&username, password, &state).await {
+                tracing::error!("Failed to create admin '{}': {:#}", username, e);
+                std::process::exit(1);
+            }
+            tracing::info!("Created admin '{}'", username);
+        }
+        // bootstrap already brings the schema up to date, so there is nothing left to do
+        Command::Migrate => {
+            bootstrap(&ServeArgs::default()).await;
+            tracing::info!("Migrations are up to date");
+        }
+    }
+}
+
+/// Serves the site on `args.addr()` until SIGINT or SIGTERM, then shuts down gracefully.
+async fn serve(args: &ServeArgs) {
+    let shutdown_timeout = shutdown::shutdown_timeout_from_env().unwrap_or_else(|e| {
+        tracing::error!("Failed to parse SHUTDOWN_TIMEOUT_SECS: {:#}", e);
+        std::process::exit(1);
+    });
+    let shared_state = bootstrap(args).await;
+    let tls = shared_state.tls.clone();
+    let service = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app(shared_state.clone()));
+    let addr = args.addr();
+    let shutdown = shutdown::listen_for_shutdown();
+    // obviously if these fail the issue is irrecoverable, therefore 'expect' is reasonable to use.
+    match tls {
+        Some(config) => {
+            tracing::info!("Serving HTTPS on {}", addr);
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown::shutdown_started(shutdown).await;
+                    tracing::info!("Stopped accepting connections, waiting up to {:?} for in-flight requests", shutdown_timeout);
+                    handle.graceful_shutdown(Some(shutdown_timeout));
+                }
+            });
+            axum_server::bind_rustls(addr, config).handle(handle).serve(service).await.expect("Serving failed");
+        }
+        None => {
+            tracing::info!("Serving plain HTTP on {}", addr);
+            let listener = tokio::net::TcpListener::bind(addr).await.expect("Bind failed");
+            let server = axum::serve(listener, service).with_graceful_shutdown(shutdown::shutdown_started(shutdown.clone()));
+            // axum waits for in-flight requests indefinitely, so the timeout is raced against it here
+            let deadline = async {
+                shutdown::shutdown_started(shutdown).await;
+                tracing::info!("Stopped accepting connections, waiting up to {:?} for in-flight requests", shutdown_timeout);
+                tokio::time::sleep(shutdown_timeout).await;
+            };
+            tokio::select! {
+                result = server.into_future() => result.expect("Serving failed"),
+                _ = deadline => tracing::warn!("Requests still in flight after {:?}, dropping them", shutdown_timeout)
+            }
+        }
+    }
+    // jobs write to the database, so the queue is run dry before the pools close
+    tracing::info!("Running the background jobs still queued");
+    shared_state.jobs.close().await;
+    tracing::info!("Closing database pools");
+    shared_state.read_pool.close().await;
+    shared_state.write_pool.close().await;
+    tracing::info!("Shut down");
+}
+
+/// The rate limited routes, as paths and their handlers. Listed rather than chained onto the router so
+/// 'RESERVED_NAMES' can be read off the same paths.
+fn routes() -> Vec<(&'static str, MethodRouter<Arc<AppState>>)> {
+    vec![
+        ("/", get(root)),
+        ("/users", get(users_list_route)),
+        ("/user/{username}", get(get_user_route)),
+        ("/api/users/search", get(search_users)),
+        ("/api/users", get(get_users).post(post_user)),
+        ("/api/users/{username}", delete(delete_user).patch(patch_user)),
+        ("/api/users/{username}/2fa/enable", post(totp::enable)),
+        ("/api/users/{username}/2fa/confirm", post(totp::confirm)),
+        ("/api/users/{username}/2fa/disable", post(totp::disable)),
+        ("/api/users/{username}/follow", post(follows::follow).delete(follows::unfollow)),
+        ("/api/users/{username}/followers", get(follows::followers)),
+        ("/api/users/{username}/following", get(follows::following)),
+        ("/api/users/{username}/bookmarks", get(bookmarks::bookmarks)),
+        ("/api/users/{username}/tokens", get(api_tokens::list_tokens).post(api_tokens::create_token)),
+        ("/api/users/{username}/tokens/{id}", delete(api_tokens::revoke_token)),
+        ("/api/feed", get(follows::feed)),
+        ("/api/admin/users/deleted", get(get_deleted_users)),
+        ("/api/admin/users/{username}/restore", post(restore_user)),
+        ("/api/admin/users/{username}/role", put(set_role)),
+        ("/api/admin/impersonate/active", get(impersonation::active_impersonations)),
+        ("/api/admin/impersonate/{username}", post(impersonation::impersonate)),
+        (maintenance::MAINTENANCE_PATH, post(maintenance::set_maintenance)),
+        ("/api/admin/audit", get(audit::get_audit_log)),
+        ("/api/system/sbom", get(get_sbom)),
+        ("/admin/invites", get(invites::list_invites).post(invites::create_invites)),
+        ("/posts", get(posts::posts_route)),
+        ("/posts/{slug}", get(posts::post_route)),
+        ("/api/posts", get(posts::list_posts).post(posts::create_post)),
+        ("/feed.xml", get(feed::feed)),
+        ("/feed.atom", get(feed::atom_feed)),
+        ("/sitemap.xml", get(sitemap::sitemap)),
+        ("/api/posts/{id}", get(posts::get_post).patch(posts::patch_post).delete(posts::delete_post)),
+        ("/api/posts/popular", get(posts::popular_posts)),
+        ("/api/posts/search", get(posts::search_posts)),
+        ("/api/posts/by-slug/{slug}", get(posts::get_post_by_slug)),
+        ("/api/posts/{id}/publish", post(posts::publish_post)),
+        ("/api/admin/posts/drafts", get(posts::list_drafts)),
+        ("/api/posts/{id}/bookmark", post(bookmarks::bookmark).delete(bookmarks::remove_bookmark)),
+        ("/api/series", get(series::list_series).post(series::create_series)),
+        ("/api/series/{slug}", get(series::get_series).patch(series::patch_series).delete(series::delete_series)),
+        ("/api/series/{slug}/posts/{id}", put(series::add_post).delete(series::remove_post)),
+        ("/api/posts/{id}/comments", get(comments::list_comments).post(comments::create_comment)),
+        ("/api/comments/{id}", delete(comments::delete_comment)),
+        ("/api/admin/comments/{id}/flag", post(comments::flag_comment)),
+        ("/api/login", post(login)),
+        ("/api/auth/token", post(jwt::issue_token)),
+        ("/api/auth/forgot-password", post(password_reset::forgot_password)),
+        ("/api/auth/reset-password", post(password_reset::reset_password)),
+        ("/api/logout", post(logout)),
+        ("/api/session", get(get_session)),
+        (openapi::OPENAPI_PATH, get(openapi::openapi_json)),
+        ("/api/docs", get(openapi::docs)),
+        ("/api/docs/{*file}", get(openapi::docs_file)),
+        ("/ws", get(live::ws)),
+    ]
+}
+
+/// Routes kept out of the rate limit, see 'app'.
+fn unlimited_routes() -> Vec<(&'static str, MethodRouter<Arc<AppState>>)> {
+    vec![
+        ("/health", get(health)),
+        ("/metrics", get(metrics::metrics))
+    ]
+}
+
+/// Adds every (path, handlers) pair in `routes` to `router`.
+fn with_routes(router: Router<Arc<AppState>>, routes: Vec<(&'static str, MethodRouter<Arc<AppState>>)>) -> Router<Arc<AppState>> {
+    routes.into_iter().fold(router, |router, (path, handlers)| router.route(path, handlers))
+}
+
+/// Builds the application router wrapped in its outermost middleware.
+// the path redirect has to run before routing: Router::layer only runs after a route has been
+// matched, so '/users/' and '//users' would already have hit the fallback by then. Hence the
+// router is the fallback of an outer one with no routes of its own, which the redirect wraps.
+pub fn app(state: Arc<AppState>) -> Router {
+    let tls = state.tls.is_some();
+    let content_security_policy = state.security_headers.content_security_policy.clone();
+    let max_request_body_bytes = state.max_request_body_bytes;
+    let cors_origins = state.cors_origins.clone();
+    let router = with_routes(Router::new(), routes())
+        .fallback(unknown_path)
+        // inside the rate limit, so a replayed response still counts against it
+        .layer(middleware::from_fn_with_state(state.clone(), idempotency::idempotency))
+        .layer(GovernorLayer { config: rate_limit_config() });
+    // Router::layer only wraps routes added before it, which is what keeps /health out of the rate limit
+    let router = with_routes(router, unlimited_routes())
+        .layer(middleware::from_fn_with_state(state.clone(), session::auth_session))
+        // outside the session lookup, so maintenance mode keeps requests away from the database
+        .layer(middleware::from_fn_with_state(state.clone(), maintenance::maintenance_mode))
+        // MatchedPath is only set once a route matched, which is why this is a Router layer rather than wrapping the router
+        .layer(middleware::from_fn(metrics::track_metrics))
+        .with_state(state)
+        // the one body limit for every route, so axum's own default is switched off. A route that needs more,
+        // like a file upload would, has to be kept out from under this layer and given its own.
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_request_body_bytes))
+        .layer(middleware::map_response(error::errors_as_json))
+        // gzip or brotli, whichever the client accepts. Binary bodies are left alone, they rarely shrink.
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("application/octet-stream"))))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http()
+            .make_span_with(request_span)
+            .on_response(DefaultOnResponse::new().level(Level::INFO)))
+        // the id is assigned before tracing starts so every event of a request carries it
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        // credentials are marked sensitive so any logging/tracing layers print them as redacted. It has to wrap
+        // TraceLayer and everything within it. The response header, HSTS and CORS layers added after it sit further
+        // out, which is fine as long as none of them logs request headers.
+        .layer(SetSensitiveRequestHeadersLayer::new([AUTHORIZATION, COOKIE]))
+        // a handler that sets one of these itself keeps its own value
+        .layer(SetResponseHeaderLayer::if_not_present(X_FRAME_OPTIONS, HeaderValue::from_static("DENY")))
+        .layer(SetResponseHeaderLayer::if_not_present(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")))
+        .layer(SetResponseHeaderLayer::if_not_present(REFERRER_POLICY, HeaderValue::from_static("strict-origin-when-cross-origin")))
+        .layer(SetResponseHeaderLayer::if_not_present(CONTENT_SECURITY_POLICY, content_security_policy))
+        .layer(SetResponseHeaderLayer::if_not_present(HeaderName::from_static("permissions-policy"), HeaderValue::from_static("geolocation=()")));
+    // HSTS is ignored over plain HTTP anyway, so only send it when this server is the one speaking TLS
+    let router = match tls {
+        true => router.layer(SetResponseHeaderLayer::overriding(STRICT_TRANSPORT_SECURITY, HeaderValue::from_static(HSTS))),
+        false => router
+    };
+    // outermost, so preflights are answered before anything else and every response gets the CORS headers
+    let router = match cors::cors_layer(&cors_origins) {
+        Some(cors_layer) => router
+            .layer(cors_layer)
+            .layer(middleware::from_fn_with_state(cors_origins, cors::reject_disallowed_preflight)),
+        None => router
+    };
+    Router::new()
+        .fallback_service(router)
+        .layer(middleware::from_fn(redirect_unnormalized_path))
+}
+
+/// `path` without a trailing '/' and with doubled ones collapsed, e.g. '/users' for '/users/' or '//users'.
+fn normalize_path(path: &str) -> String {
+    format!("/{}", path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>().join("/"))
+}
+
+/// Answers a request for a path with a trailing or doubled '/' with a 301 to the normalized path, query string
+/// included. Routes are registered without trailing slashes, so a normalized path is never redirected.
+async fn redirect_unnormalized_path(request: Request, next: middleware::Next) -> Response {
+    let normalized = normalize_path(request.uri().path());
+    if normalized == request.uri().path() {
+        return next.run(request).await;
+    }
+    // normalizing leaves a single leading '/', so this can't become a protocol relative URL to another host
+    let location = match request.uri().query() {
+        Some(query) => format!("{normalized}?{query}"),
+        None => normalized
+    };
+    (StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response()
+}
+
+/// Per-IP rate limit: bursts of RATE_LIMIT_BURST requests, refilled at one request per second.
+// each router gets its own limiter, cleaned up by a task that ends when the router is dropped
+fn rate_limit_config() -> Arc<GovernorConfig<PeerIpKeyExtractor, NoOpMiddleware>> {
+    let config = Arc::new(GovernorConfigBuilder::default()
+        .per_second(1)
+        .burst_size(RATE_LIMIT_BURST)
+        .error_handler(|error| match error {
+            GovernorError::TooManyRequests { headers, .. } => {
+                (headers.unwrap_or_default(), error::error_response(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded"))
+                    .into_response()
+            }
+            e => AppError::Internal(anyhow!("Rate limiter failed: {e}")).into_response()
+        })
+        .finish()
+        .expect("Rate limit period and burst size must be non-zero"));
+    let limiter = Arc::downgrade(&config);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RATE_LIMIT_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match limiter.upgrade() {
+                // forget clients that have been quiet long enough to be back at a full burst
+                Some(config) => config.limiter().retain_recent(),
+                None => break
+            }
+        }
+    });
+    config
+}
+
+/// Span wrapping everything logged while handling one request. Headers marked sensitive show up as `Sensitive`.
+fn request_span(request: &Request) -> tracing::Span {
+    let request_id = request.headers().get("x-request-id").and_then(|id| id.to_str().ok()).unwrap_or_default();
+    tracing::info_span!("request", request_id, method = %request.method(), uri = %request.uri(), headers = ?request.headers())
+}
+
+/// Creates or connects to database needed for internal application state.
+// as this is a function run at startup, this uses unsafe functions like expect() and can fail.
+// Safe to call more than once in a process, as integration tests do.
+pub async fn bootstrap(args: &ServeArgs) -> Arc<AppState> {
+    let env_file = dotenvy::dotenv();
+    let env_filter = match &args.log_level {
+        Some(level) => EnvFilter::try_new(level).unwrap_or_else(|e| {
+            // nothing can be logged before the subscriber exists
+            eprintln!("Invalid log level '{level}': {e}");
+            std::process::exit(1);
+        }),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+    };
+    // RUST_LOG may come from .env, so the subscriber is only installed once that has been read.
+    // A second bootstrap finds one already installed, which is fine.
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .try_init();
+    match env_file {
+        Ok(_buf) => tracing::info!("Loaded env variables!"),
+        // without a .env file everything comes from the process environment
+        Err(e) if e.not_found() => tracing::info!("No .env file, using the process environment"),
+        Err(e) => {
+            tracing::error!("Failed to parse env variables: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let database = env::var("DATABASE_URL").expect("DATABASE_URL environment variable not found.");
+    tracing::info!("Database URL: {}", database);
+    let i18n = match I18n::load(i18n::LOCALES_DIR) {
+        Ok(i18n) => Arc::new(i18n),
+        Err(e) => {
+            tracing::error!("Failed to load locales from {}: {:#}", i18n::LOCALES_DIR, e);
+            std::process::exit(1);
+        }
+    };
+    init_templates(&i18n);
+    let write_conn_opt: SqliteConnectOptions = SqliteConnectOptions::new()
+        .filename(&database)
+        .journal_mode(SqliteJournalMode::Wal)
+        .create_if_missing(true);
+    let read_conn_opt: SqliteConnectOptions = SqliteConnectOptions::new()
+        .filename(&database)
+        .journal_mode(SqliteJournalMode::Wal)
+        .create_if_missing(true)
+        .read_only(true);
+    let acquire_timeout = match env::var("DB_ACQUIRE_TIMEOUT_MS").map(|ms| ms.parse::<u64>()) {
+        Ok(Ok(ms)) => Duration::from_millis(ms),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to parse DB_ACQUIRE_TIMEOUT_MS: {}", e);
+            std::process::exit(1);
+        }
+        Err(_) => DEFAULT_ACQUIRE_TIMEOUT,
+    };
+    let user_cache_size = match env::var("USER_CACHE_SIZE").map(|size| size.parse::<NonZeroUsize>()) {
+        Ok(Ok(size)) => size,
+        Ok(Err(e)) => {
+            tracing::error!("Failed to parse USER_CACHE_SIZE: {}", e);
+            std::process::exit(1);
+        }
+        Err(_) => DEFAULT_USER_CACHE_SIZE,
+    };
+    let max_request_body_bytes = match env::var("MAX_REQUEST_BODY_BYTES").map(|bytes| bytes.parse::<usize>()) {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(e)) => {
+            tracing::error!("Failed to parse MAX_REQUEST_BODY_BYTES: {}", e);
+            std::process::exit(1);
+        }
+        Err(_) => DEFAULT_MAX_REQUEST_BODY_BYTES,
+    };
+    let per_page = per_page_from_env().unwrap_or_else(|e| {
+        tracing::error!("Failed to parse PER_PAGE: {:#}", e);
+        std::process::exit(1);
+    });
+    // GeoIP lookups are optional; without a database users simply have no country recorded.
+    let geoip = env::var("GEOIP_DB_PATH").ok().map(|path| match maxminddb::Reader::open_readfile(&path) {
+        Ok(reader) => {
+            tracing::info!("Loaded GeoIP database from {}", path);
+            reader
+        }
+        Err(e) => {
+            tracing::error!("Failed to open GeoIP database at {}: {}", path, e);
+            std::process::exit(1);
+        }
+    });
+    let base_url = base_url_from_env(args.addr());
+    tracing::info!("Base URL: {}", base_url);
+    let require_invite = env::var("REQUIRE_INVITE").is_ok_and(|value| value == "true");
+    // bearer tokens are optional too; without a key pair the API only takes session cookies.
+    let jwt = match (env::var("JWT_PRIVATE_KEY_PATH"), env::var("JWT_PUBLIC_KEY_PATH")) {
+        (Ok(private_path), Ok(public_path)) => {
+            let keys = std::fs::read(&private_path)
+                .and_then(|private_key| Ok((private_key, std::fs::read(&public_path)?)))
+                .map_err(Error::from)
+                .and_then(|(private_key, public_key)| jwt::JwtKeys::from_pem(&private_key, &public_key));
+            match keys {
+                Ok(keys) => {
+                    tracing::info!("Loaded JWT key pair from {}", private_path);
+                    Some(keys)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to load JWT key pair from {} and {}: {}", private_path, public_path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (Err(_), Err(_)) => None,
+        _ => {
+            tracing::error!("JWT_PRIVATE_KEY_PATH and JWT_PUBLIC_KEY_PATH must be set together.");
+            std::process::exit(1);
+        }
+    };
+    let tls = match (env::var("TLS_CERT_PATH"), env::var("TLS_KEY_PATH")) {
+        (Ok(cert_path), Ok(key_path)) => match RustlsConfig::from_pem_file(&cert_path, &key_path).await {
+            Ok(config) => {
+                tracing::info!("Loaded TLS certificate from {}", cert_path);
+                Some(config)
+            }
+            Err(e) => {
+                tracing::error!("Failed to load TLS certificate from {} and key from {}: {}", cert_path, key_path, e);
+                std::process::exit(1);
+            }
+        },
+        (Err(_), Err(_)) => None,
+        _ => {
+            tracing::error!("TLS_CERT_PATH and TLS_KEY_PATH must be set together.");
+            std::process::exit(1);
+        }
+    };
+    let security_headers = match env::var("CONTENT_SECURITY_POLICY").map(HeaderValue::try_from) {
+        Ok(Ok(content_security_policy)) => SecurityHeadersConfig { content_security_policy },
+        Ok(Err(e)) => {
+            tracing::error!("Failed to parse CONTENT_SECURITY_POLICY: {}", e);
+            std::process::exit(1);
+        }
+        Err(_) => SecurityHeadersConfig::default(),
+    };
+    metrics::init_metrics();
+    let metrics_allow = match metrics::parse_allow_cidrs(&env::var("METRICS_ALLOW_CIDR").unwrap_or(metrics::DEFAULT_METRICS_ALLOW_CIDR.to_string())) {
+        Ok(metrics_allow) => metrics_allow,
+        Err(e) => {
+            tracing::error!("Failed to parse METRICS_ALLOW_CIDR: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let cors_origins = match env::var("CORS_ALLOWED_ORIGINS").map(|origins| cors::parse_allowed_origins(&origins)) {
+        Ok(Ok(cors_origins)) => cors_origins,
+        Ok(Err(e)) => {
+            tracing::error!("Failed to parse CORS_ALLOWED_ORIGINS: {}", e);
+            std::process::exit(1);
+        }
+        Err(_) => cors::CorsOrigins::default(),
+    };
+    let (read_conn, write_conn) = if database == IN_MEMORY_DATABASE {
+        // every connection to ':memory:' gets a fresh database of its own, so reads and writes have to
+        // share a single connection that is never closed
+        let pool = sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_lazy_with(SqliteConnectOptions::new().filename(IN_MEMORY_DATABASE));
+        (pool.clone(), pool)
+    } else {
+        (sqlite::SqlitePool::connect_lazy_with(read_conn_opt), sqlite::SqlitePool::connect_lazy_with(write_conn_opt))
+    };
+    let mut conn = acquire_with_timeout(&write_conn, "write", acquire_timeout).await
+        .expect("Failed to acquire write connection in 'bootstrap()'");
+    // databases from before migrations existed get their missing user_table columns first, since later
+    // migrations may build on these columns. A fresh database has no user_table yet and
+    // gets them all from the migrations instead.
+    let has_user_table = sqlx::query_scalar!(r#"SELECT COUNT(*) > 0 AS "has_table!: bool" FROM sqlite_master WHERE type = 'table' AND name = 'user_table'"#)
+        .fetch_one(&mut *conn).await.expect("Failed to inspect the schema in 'bootstrap()'");
+    for (column, definition) in ADDED_USER_COLUMNS.iter().filter(|_| has_user_table) {
+        let has_column = sqlx::query_scalar!(r#"SELECT COUNT(*) > 0 AS "has_column!: bool" FROM pragma_table_info('user_table') WHERE name = $1"#, column)
+            .fetch_one(&mut *conn).await.expect("Failed to inspect user_table in 'bootstrap()'");
+        if !has_column {
+            conn.execute(format!("ALTER TABLE user_table ADD COLUMN {column} {definition}").as_str()).await
+                .expect("Failed to add missing column to user_table in 'bootstrap()'");
+        }
+    }
+    drop(conn);
+    sqlx::migrate!("./migrations").run(&write_conn).await.expect("Failed to run migrations in 'bootstrap()'");
+    tracing::info!("Acquired / created DB file");
+    let state = Arc::new(AppState { read_pool: read_conn, write_pool: write_conn, per_page, acquire_timeout, base_url, geoip, require_invite, jwt,
+                                     online: watch::Sender::new(0), tls, security_headers, metrics_allow,
+                                     user_cache: Mutex::new(LruCache::new(user_cache_size)), max_request_body_bytes,
+                                     maintenance: AtomicBool::new(false), i18n, jobs: jobs::JobQueue::new(),
+                                     cors_origins });
+    jobs::spawn_worker(&state);
+    tokio::spawn(session::expire_sessions_task(state.clone()));
+    tokio::spawn(login_throttle::prune_login_attempts_task(state.clone()));
+    state
+}
+
+/// The default page size for listings: PER_PAGE if it is set, which has to be within PER_PAGE_RANGE.
+fn per_page_from_env() -> Result<u32, Error> {
+    let Ok(per_page) = env::var("PER_PAGE") else {
+        return Ok(DEFAULT_PER_PAGE);
+    };
+    let per_page = per_page.parse::<u32>()?;
+    if !PER_PAGE_RANGE.contains(&per_page) {
+        return Err(anyhow!("{per_page} is not between {} and {}", PER_PAGE_RANGE.start(), PER_PAGE_RANGE.end()));
+    }
+    Ok(per_page)
+}
+
+/// Home page
+#[tracing::instrument(skip_all)]
+async fn root(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, locale: Locale) -> Response {
+    let context = page_context(&state, current_user, locale).await;
+    match templates().render("index.html", &context) {
+        Ok(page) => html_response(StatusCode::OK, page),
+        Err(_e) => {
+            tracing::error!("Failed to create page: {:?}", _e);
+            error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display page.")
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, current_user, locale))]
+async fn users_list_route(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, locale: Locale,
+                          Query(params): Query<HashMap<String, String>>) -> Response {
+    let page_no = page_param(&params);
+    let mut context = page_context(&state, current_user, locale).await;
+    context.insert("page_no", &page_no);
+    let Ok(users) = get_users_by_pagination(state.clone(), page_no).await else {
+        return error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display users.");
+    };
+    context.insert("total_pages", &users.total_pages);
+    context.insert("users", &users.items);
+    match templates().render("users.html", &context) {
+        Ok(page) => html_response(StatusCode::OK, page),
+        Err(_e) => error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display page.")
+    }
+}
+
+/// Profile page for a single user.
+#[tracing::instrument(skip(state, current_user, locale))]
+async fn get_user_route(state: State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, locale: Locale,
+                        Path(username): Path<String>) -> Response {
+    let user = match select_by_username(&username, &state).await {
+        Some(Ok(user)) => user,
+        None => return error_page(&state, StatusCode::NOT_FOUND, &format!("No user named '{}' exists.", username)),
+        Some(Err(_e)) => return error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display user.")
+    };
+    let mut context = page_context(&state, current_user, locale).await;
+    context.insert("role_name", role_name(user.role));
+    let days = user.age_days();
+    context.insert("tenure_days", &days);
+    context.insert("tenure", &format_tenure(days));
+    context.insert("user", &user);
+    match templates().render("user.html", &context) {
+        Ok(page) => html_response(StatusCode::OK, page),
+        Err(_e) => error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display page.")
+    }
+}
+
+/// Reads BASE_URL, falling back to the address being served on. A trailing '/' is added if missing so paths can be appended directly.
+fn base_url_from_env(addr: SocketAddr) -> String {
+    let base_url = env::var("BASE_URL").unwrap_or_else(|_| format!("http://{addr}/"));
+    if base_url.ends_with('/') { base_url } else { format!("{base_url}/") }
+}
+
+/// Template context every page starts from, with the `lang` it is shown in and that locale's strings as `t`.
+/// Logged in visitors also get their session's `csrf_token`.
+async fn page_context(state: &AppState, current_user: Option<Extension<CurrentUser>>, Locale(lang): Locale) -> tera::Context {
+    let mut context = tera::Context::new();
+    context.insert("base_url", &state.base_url);
+    context.insert("t", state.i18n.strings(&lang));
+    context.insert("lang", &lang);
+    if let Some(Extension(user)) = current_user {
+        match csrf::generate_csrf_token(&user.session_id, state).await {
+            Ok(token) => context.insert("csrf_token", &token),
+            // the page itself still works, only its state changing requests will be refused
+            Err(e) => tracing::error!("Failed to generate CSRF token: {}", e)
+        }
+    }
+    context
+}
+
+/// Renders the shared error page. Falls back to a bare message if the template itself fails.
+fn error_page(state: &AppState, status: StatusCode, message: &str) -> Response {
+    let mut context = tera::Context::new();
+    context.insert("base_url", &state.base_url);
+    context.insert("status", &status.as_u16());
+    context.insert("message", message);
+    let body = templates().render("error.html", &context)
+        .unwrap_or_else(|_e| "<h1>Internal server error. Please contact site administrator for help.<h1>".to_string());
+    html_response(status, body)
+}
+
+/// Display name for a role.
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Admin => "Admin",
+        Role::Mod => "Mod",
+        Role::User => "User"
+    }
+}
+
+///    API endpoint to return usernames as a JSON list, optionally filtered by role and sorted.
+///    Sends the page as a CSV download instead when the Accept header asks for text/csv.
+#[utoipa::path(get, path = "/api/users", tag = "users", params(UserListParams),
+    responses(
+        (status = 200, description = "A page of usernames, or `{\"users\": [...], \"next_cursor\": ...}` when `after` or `limit` is given, \
+            or `username,last_online,created,role` CSV when `text/csv` is accepted",
+            content((Page<String> = "application/json"), (String = "text/csv"))),
+        (status = 304, description = "The If-None-Match ETag still matches"),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+        (status = 406, description = "The Accept header allows neither JSON nor CSV", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, headers))]
+async fn get_users(State(state): State<Arc<AppState>>, headers: HeaderMap, params: Result<Query<UserListParams>, QueryRejection>)
+                   -> Result<impl IntoResponse, AppError> {
+    let Query(params) = params?;
+    let format = user_list_format(&headers)?;
+    if params.after.is_some() || params.limit.is_some() {
+        if format == UserListFormat::Csv {
+            return Err(AppError::BadRequest("CSV exports page by 'page', not by 'after' and 'limit'.".to_string()));
+        }
+        let cursor = user_cursor_check(params, state.per_page)?;
+        let usernames = get_usernames_after(&state, &cursor).await?;
+        // a short page means the listing is exhausted
+        let next_cursor = usernames.last().filter(|_| usernames.len() == cursor.limit as usize);
+        return conditional_response(&headers, &serde_json::json!({"users": usernames, "next_cursor": next_cursor}));
+    }
+    let listing = user_listing_check(params, state.per_page)?;
+    match format {
+        UserListFormat::Json => conditional_response(&headers, &get_usernames_by_listing(&state, &listing).await?),
+        UserListFormat::Csv => Ok(csv_response(StatusCode::OK, "users.csv", users_csv(&get_user_rows_by_listing(&state, &listing).await?)?))
+    }
+}
+
+/// What GET /api/users responds with.
+#[derive(Debug, PartialEq)]
+enum UserListFormat {
+    Json,
+    Csv
+}
+
+/// Picks the format of the first media range in the Accept header that GET /api/users can produce, JSON when there
+/// is no Accept header. Quality values aren't weighed, so clients wanting CSV should list it first.
+fn user_list_format(headers: &HeaderMap) -> Result<UserListFormat, AppError> {
+    let Some(accept) = headers.get(ACCEPT) else {
+        return Ok(UserListFormat::Json);
+    };
+    accept.to_str().map_err(|_| AppError::NotAcceptable)?
+        .split(',')
+        .map(|range| range.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .find_map(|media_type| match media_type.as_str() {
+            "text/csv" => Some(UserListFormat::Csv),
+            // curl and most HTTP libraries send */* unless told otherwise
+            "application/json" | "application/*" | "*/*" => Some(UserListFormat::Json),
+            _ => None
+        })
+        .ok_or(AppError::NotAcceptable)
+}
+
+/// `rows` as CSV, under a header line even when there are none.
+fn users_csv(rows: &[UserCsvRow]) -> Result<Vec<u8>, Error> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    writer.write_record(["username", "last_online", "created", "role"])?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.into_inner().map_err(|e| anyhow!("Failed to flush CSV: {}", e.error()))
+}
+
+/// Validates a cursor paginated GET /api/users query. Cursors follow username order, so other sorts and page numbers are rejected.
+fn user_cursor_check(params: UserListParams, default_limit: u32) -> Result<UserCursor, AppError> {
+    if params.sort_by.as_deref().is_some_and(|column| column != "username") || params.order.as_deref().is_some_and(|order| order != "asc")
+        || params.page.is_some() || params.per_page.is_some() {
+        return Err(AppError::BadRequest("'after' and 'limit' only page through users by username, in ascending order.".to_string()));
+    }
+    Ok(UserCursor {
+        after: params.after,
+        role: params.role,
+        limit: params.limit.unwrap_or(default_limit).clamp(1, MAX_PER_PAGE)
+    })
+}
+
+/// Validates the GET /api/users query. Unknown sort columns and orders are rejected rather than ignored.
+fn user_listing_check(params: UserListParams, default_per_page: u32) -> Result<UserListing, AppError> {
+    // the column name ends up in the SQL text, so it must come from this match and never from the request
+    let sort_column = match params.sort_by.as_deref().unwrap_or("username") {
+        "username" => "username",
+        "created" => "created",
+        "last_online" => "last_online",
+        other => return Err(AppError::BadRequest(format!("Cannot sort by '{other}'.")))
+    };
+    let descending = match params.order.as_deref().unwrap_or("asc") {
+        "asc" => false,
+        "desc" => true,
+        other => return Err(AppError::BadRequest(format!("Unknown sort order '{other}', expected 'asc' or 'desc'.")))
+    };
+    Ok(UserListing {
+        sort_column,
+        descending,
+        role: params.role,
+        page: params.page.unwrap_or(1).max(1),
+        per_page: params.per_page.unwrap_or(default_per_page).clamp(1, MAX_PER_PAGE)
+    })
+}
+
+/// API endpoint returning users whose name contains `q`, ignoring case.
+#[utoipa::path(get, path = "/api/users/search", tag = "users", params(SearchParams),
+    responses(
+        (status = 200, body = Vec<User>),
+        (status = 400, description = "Query too short or too long", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state))]
+async fn search_users(State(state): State<Arc<AppState>>, params: Result<Query<SearchParams>, QueryRejection>)
+                      -> Result<impl IntoResponse, AppError> {
+    let Query(params) = params?;
+    if !(2..=32).contains(&params.q.chars().count()) {
+        return Err(AppError::BadRequest("Search query must be between 2 and 32 characters.".to_string()));
+    }
+    Ok(json_response(StatusCode::OK, search_users_db(&params.q, &state).await?))
+}
+
+/// Handles detailed account creation and database access once fn 'post_user' has validated the request body.
+#[tracing::instrument(skip_all, fields(username = %user.username))]
+async fn post_user_body(state: State<Arc<AppState>>, mut user: User, password: String,
+                        ip: IpAddr, invite_code: Option<String>) -> Result<impl IntoResponse, AppError> {
+    if username_taken(&user.username, &state).await? {
+        return Err(AppError::Conflict(format!("User with name '{}' already exists.", user.username)));
+    }
+    user.country_code = lookup_country(&state, ip);
+    user.password_hash = hash_password(password).await?;
+    // user is not a duplicate, can be created once any required invite is redeemed. Both happen in one
+    // transaction, so a failed insert leaves the code unused
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    if let Some(code) = invite_code.filter(|_| state.require_invite)
+        && !invites::claim_invite(&code, &user.username, &mut transaction).await? {
+        return Err(AppError::BadRequest("Invite code is invalid, expired or already used.".to_string()));
+    }
+    // the check above can race another sign-up for the same name, which the unique index settles
+    insert_user_row(&user, &mut transaction).await.map_err(account_conflict)?;
+    transaction.commit().await?;
+    forget_cached_user(&user.username, &state);
+    tracing::info!("Created user");
+    Ok((StatusCode::CREATED, [(LOCATION, format!("{}user/{}", state.base_url, user.username))]))
+}
+
+/// POST request handler for account creation.
+#[utoipa::path(post, path = "/api/users", tag = "users",
+    params(("Idempotency-Key" = Option<String>, Header, description = "A UUID. Retries with the same key get the first response replayed")),
+    request_body(content = Object, description = "`username` and `password`, plus `invite_code` when invites are required and optionally `email`"),
+    responses(
+        (status = 201, description = "Created, with the profile page in Location"),
+        (status = 400, description = "Invalid username, weak password, invalid email or bad invite", body = ErrorBody),
+        (status = 409, description = "Username or email already in use", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+async fn post_user(state: State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                   result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    // extracts user information from the POST body and makes sure content is valid
+    let Json(json_map) = result?;
+    let invite_code = json_map.get("invite_code").and_then(Value::as_str).map(str::to_string);
+    let mut user = username_check(json_map.get("username"))?;
+    user.email = match json_map.get("email") {
+        None => None,
+        Some(Value::String(email)) if email_is_valid(email) => Some(email.to_string()),
+        Some(_) => return Err(AppError::BadRequest("Invalid email address.".to_string()))
+    };
+    if state.require_invite && invite_code.is_none() {
+        return Err(AppError::BadRequest("Invite code required.".to_string()));
+    }
+    let password = password_check(json_map.get("password"))?;
+    post_user_body(state, user, password, addr.ip(), invite_code).await
+}
+
+/// Creates an admin account for the `create-admin` command. Names have the same shape as sign-ups' but may be
+/// reserved ones like 'admin', since whoever runs the command already controls the site.
+async fn create_admin_user(username: &str, password: String, state: &Arc<AppState>) -> Result<(), Error> {
+    if !username_is_well_formed(username) {
+        return Err(anyhow!("Usernames are 5 to 32 letters, digits or underscores, with at least one letter."));
+    }
+    let mut user = User::new(username.to_string(), Role::Admin);
+    let password = password_check(Some(&Value::from(password)))
+        .map_err(|_| anyhow!("Passwords are 8 to 128 characters."))?;
+    if username_taken(username, state).await? {
+        return Err(anyhow!("The name is already taken."));
+    }
+    user.password_hash = hash_password(password).await?;
+    insert_user(&user, &State(state.clone())).await?;
+    Ok(())
+}
+
+/// POST request handler for logging in with a username and password.
+#[utoipa::path(post, path = "/api/login", tag = "auth",
+    request_body(content = Object, description = "`username` and `password`, plus `totp_code` if the account has 2FA enabled"),
+    responses(
+        (status = 200, description = "Logged in, with the session cookie in Set-Cookie", body = String),
+        (status = 400, description = "Malformed payload, or a missing `totp_code`", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 429, description = "Too many failed logins to this username, retry after the Retry-After seconds", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+async fn login(state: State<Arc<AppState>>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let (id, username) = verify_credentials(&json_map, &state).await?;
+    let session = create_session(id, &state).await?;
+    state.jobs.dispatch(AuditJob { user_id: id, action: AuditAction::Login, performed_by: Some(id), detail: None }).await;
+    tracing::info!(username, "User logged in");
+    Ok(([(SET_COOKIE, session.cookie(&state))], plain_response(StatusCode::OK, "Logged in.")))
+}
+
+/// Checks the `username` and `password` of a login payload, returning the user's id and name if they match.
+/// Accounts with two-factor authentication also need a current `totp_code`. Failures count towards the
+/// username's login throttle, and a throttled username is refused without checking anything.
+async fn verify_credentials(json_map: &Value, state: &State<Arc<AppState>>) -> Result<(i64, String), AppError> {
+    let field = |name: &str| json_map.get(name).and_then(Value::as_str).map(str::to_string);
+    let (username, password) = field("username").zip(field("password"))
+        .ok_or(AppError::BadRequest("JSON payload structure invalid.".to_string()))?;
+    if login_throttle::is_throttled(&username, state).await? {
+        tracing::warn!(username, "Refused login to throttled username");
+        return Err(AppError::TooManyRequests(login_throttle::LOGIN_WINDOW_SECS));
+    }
+    match check_credentials(&username, password, json_map, state).await {
+        Ok(id) => {
+            login_throttle::reset_failures(&username, state).await?;
+            Ok((id, username))
+        }
+        Err(AppError::Unauthorized) => {
+            login_throttle::record_failure(&username, state).await?;
+            Err(AppError::Unauthorized)
+        }
+        Err(e) => Err(e)
+    }
+}
+
+/// The id of the user `username` if `password`, and the `totp_code` of a 2FA account, are right.
+async fn check_credentials(username: &str, password: String, json_map: &Value, state: &State<Arc<AppState>>) -> Result<i64, AppError> {
+    // unknown users get the same response as a wrong password so accounts can't be enumerated
+    let Some((id, hash)) = select_hash_by_username(username, state).await? else {
+        return Err(AppError::Unauthorized);
+    };
+    if !verify_password(password, hash).await? {
+        return Err(AppError::Unauthorized);
+    }
+    totp::check_login(id, username, json_map, state).await?;
+    Ok(id)
+}
+
+/// POST request handler ending the caller's session.
+#[utoipa::path(post, path = "/api/logout", tag = "auth", security(("session" = [])),
+    params(("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    responses(
+        (status = 200, description = "Logged out, with an expired session cookie in Set-Cookie", body = String),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Missing or wrong CSRF token", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+async fn logout(state: State<Arc<AppState>>, _csrf: ValidCsrf, current_user: Option<Extension<CurrentUser>>) -> Result<impl IntoResponse, AppError> {
+    let Some(Extension(user)) = current_user else {
+        return Err(AppError::Unauthorized);
+    };
+    expire_session(&user.session_id, &state).await?;
+    tracing::info!(username = user.username, "User logged out");
+    Ok(([(SET_COOKIE, expired_cookie(&state))], plain_response(StatusCode::OK, "Logged out.")))
+}
+
+/// API endpoint returning the user the caller's session belongs to.
+#[utoipa::path(get, path = "/api/session", tag = "auth", security(("session" = [])),
+    responses(
+        (status = 200, body = CurrentUser),
+        (status = 401, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+async fn get_session(current_user: Option<Extension<CurrentUser>>) -> Result<impl IntoResponse, AppError> {
+    current_user.map(|Extension(user)| json_response(StatusCode::OK, user)).ok_or(AppError::Unauthorized)
+}
+
+/// DELETE request handler soft deleting a user account. Admin only.
+#[utoipa::path(delete, path = "/api/users/{username}", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, admin))]
+async fn delete_user(state: State<Arc<AppState>>, AdminGuard(admin): AdminGuard, _csrf: ValidCsrf,
+                     Path(username): Path<String>) -> Result<impl IntoResponse, AppError> {
+    if !delete_user_db(&username, Some(admin.id), &state).await? {
+        return Err(AppError::NotFound(format!("User with name '{}' does not exist.", username)));
+    }
+    tracing::info!("Deleted user");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// API endpoint listing soft deleted users with when they were deleted. Admin only.
+#[utoipa::path(get, path = "/api/admin/users/deleted", tag = "users", security(("session" = [])),
+    responses(
+        (status = 200, description = "`{\"username\": ..., \"deleted_at\": ...}` for every deleted user", body = Vec<Object>),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+async fn get_deleted_users(State(state): State<Arc<AppState>>, _admin: AdminGuard) -> Result<impl IntoResponse, AppError> {
+    let users = select_deleted_users(&state).await?.into_iter()
+        .map(|(username, deleted_at)| serde_json::json!({"username": username, "deleted_at": deleted_at}))
+        .collect::<Vec<_>>();
+    Ok(json_response(StatusCode::OK, users))
+}
+
+/// POST request handler undoing a user's soft delete. Admin only.
+#[utoipa::path(post, path = "/api/admin/users/{username}/restore", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    responses(
+        (status = 200, body = User),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Another account has the name now", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, _admin))]
+async fn restore_user(State(state): State<Arc<AppState>>, _admin: AdminGuard, _csrf: ValidCsrf,
+                      Path(username): Path<String>) -> Result<impl IntoResponse, AppError> {
+    if !restore_user_db(&username, &state).await.map_err(account_conflict)? {
+        return Err(AppError::NotFound(format!("No deleted user named '{}'.", username)));
+    }
+    tracing::info!("Restored user");
+    select_by_username(&username, &State(state.clone())).await.transpose()?
+        .map(|user| json_response(StatusCode::OK, user))
+        .ok_or(AppError::NotFound(format!("No deleted user named '{}'.", username)))
+}
+
+/// PUT request handler setting a user's role, from `{"role": "admin"|"mod"|"user"}`. Admin only, and not on the admin's own account,
+/// so the last admin can't demote themselves by accident.
+#[utoipa::path(put, path = "/api/admin/users/{username}/role", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "`role`: `admin`, `mod` or `user`"),
+    responses(
+        (status = 200, body = User),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, admin, result))]
+async fn set_role(State(state): State<Arc<AppState>>, AdminGuard(admin): AdminGuard, _csrf: ValidCsrf,
+                  Path(username): Path<String>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let role = json_map.get("role").and_then(|role| Role::deserialize(role).ok())
+        .ok_or(AppError::BadRequest("'role' must be 'admin', 'mod' or 'user'.".to_string()))?;
+    if admin.username == username {
+        return Err(AppError::Forbidden);
+    }
+    let Some(old_role) = update_role_db(&username, role, admin.id, &state).await? else {
+        return Err(AppError::NotFound(format!("User with name '{}' does not exist.", username)));
+    };
+    tracing::info!(old_role, ?role, "Changed user role");
+    select_by_username(&username, &State(state.clone())).await.transpose()?
+        .map(|user| json_response(StatusCode::OK, user))
+        .ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))
+}
+
+/// PATCH request handler updating a user's profile. Users may update themselves, admins anyone.
+#[utoipa::path(patch, path = "/api/users/{username}", tag = "users", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "Any of `bio`, `email`, `website` and `last_online`"),
+    responses(
+        (status = 200, body = User),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Email already in use", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user, result))]
+async fn patch_user(state: State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                    Path(username): Path<String>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let performed_by = match current_user {
+        None => return Err(AppError::Unauthorized),
+        Some(Extension(user)) if user.username != username && user.role != Role::Admin => return Err(AppError::Forbidden),
+        Some(Extension(user)) => user.id
+    };
+    let Json(json_map) = result?;
+    let update = user_update_check(&json_map)?;
+    let updated = match update_user_db(&username, &update, Some(performed_by), &state).await.map_err(account_conflict)? {
+        true => {
+            tracing::info!("Updated user");
+            select_by_username(&username, &state).await.transpose()?
+        }
+        false => None
+    };
+    updated.map(|user| json_response(StatusCode::OK, user)).ok_or(AppError::NotFound(format!("User with name '{}' does not exist.", username)))
+}
+
+/// Whether `email` looks like an address. Deliberately loose: something@something.something, no whitespace.
+/// Deliverability is a different problem.
+fn email_is_valid(email: &str) -> bool {
+    Regex::new(r"^[^@\s]{1,64}@[^@\s]+\.[^@\s]+$").is_ok_and(|val| val.is_match(email))
+}
+
+/// Turns a write refused because another account already has the username or email address into a 409.
+fn account_conflict(e: Error) -> AppError {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_error)) if db_error.is_unique_violation() && db_error.message().contains("email") => {
+            AppError::Conflict("Email already in use".to_string())
+        }
+        Some(sqlx::Error::Database(db_error)) if db_error.is_unique_violation() && db_error.message().contains("username") => {
+            AppError::Conflict("Username already in use".to_string())
+        }
+        _ => AppError::from(e)
+    }
+}
+
+/// Validates the body of a profile update. At least one of `bio`, `email`, `website` or `last_online` must be given,
+/// `email` must look like an address, `website` an http(s) URL and `last_online` an RFC 3339 timestamp.
+fn user_update_check(json_map: &Value) -> Result<UserUpdate, AppError> {
+    let field = |name: &str| match json_map.get(name) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.to_string())),
+        Some(_) => Err(AppError::BadRequest(format!("'{name}' must be a string."))),
+    };
+    let update = UserUpdate {
+        bio: field("bio")?,
+        email: field("email")?,
+        website: field("website")?,
+        last_online: field("last_online")?
+    };
+    if update == UserUpdate::default() {
+        return Err(AppError::BadRequest("No updatable fields supplied.".to_string()));
+    }
+    if update.bio.as_ref().is_some_and(|bio| bio.chars().count() > 500) {
+        return Err(AppError::BadRequest("Bio must be at most 500 characters.".to_string()));
+    }
+    if update.email.as_ref().is_some_and(|email| !email_is_valid(email)) {
+        return Err(AppError::BadRequest("Invalid email address.".to_string()));
+    }
+    // the scheme check also keeps javascript: and data: links off profile pages
+    if update.website.as_ref().is_some_and(|website| website.chars().count() > 200 || !Regex::new(r"^https?://\S+$").is_ok_and(|val| val.is_match(website))) {
+        return Err(AppError::BadRequest("Website must be an http:// or https:// URL of at most 200 characters.".to_string()));
+    }
+    if update.last_online.as_ref().is_some_and(|last_online| DateTime::parse_from_rfc3339(last_online).is_err()) {
+        return Err(AppError::BadRequest("'last_online' must be an RFC 3339 timestamp.".to_string()));
+    }
+    Ok(update)
+}
+
+/// API endpoint serving the CycloneDX bill of materials of the running build. Admin only, since it tells
+/// anyone looking for a vulnerable dependency exactly which versions to try.
+#[utoipa::path(get, path = "/api/system/sbom", security(("session" = [])),
+    responses(
+        (status = 200, description = "CycloneDX JSON", body = Object),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+async fn get_sbom(_admin: AdminGuard) -> Response {
+    raw_json_response(StatusCode::OK, SBOM)
+}
+
+/// Reports whether the read and write pools can reach the database. 503 if either cannot.
+#[utoipa::path(get, path = "/health",
+    responses(
+        (status = 200, description = "Both pools reach the database", body = Object),
+        (status = 503, description = "A pool cannot reach the database", body = Object)
+    ))]
+#[tracing::instrument(skip(state))]
+async fn health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let write = tokio::time::timeout(HEALTH_CHECK_TIMEOUT,
+        sqlx::query_scalar!("PRAGMA integrity_check").fetch_one(&state.write_pool));
+    let read = tokio::time::timeout(HEALTH_CHECK_TIMEOUT,
+        sqlx::query_scalar!(r#"SELECT 1 AS "one!: i64""#).fetch_one(&state.read_pool));
+    let (write, read) = tokio::join!(write, read);
+    // integrity_check answers with a single "ok" row when the database is sound
+    let write = matches!(write, Ok(Ok(ref result)) if result.as_deref() == Some("ok"));
+    let read = matches!(read, Ok(Ok(1)));
+    let (status, label) = match read && write {
+        true => (StatusCode::OK, "ok"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    json_response(status, serde_json::json!({"status": label, "read": read, "write": write}))
+}
+
+/// Validates a password is present and between 8 and 128 characters long.
+fn password_check(json_value: Option<&Value>) -> Result<String, AppError> {
+    match json_value.and_then(|password_json| password_json.as_str()) {
+        Some(password) if (8..=128).contains(&password.chars().count()) => Ok(password.to_string()),
+        Some(_) => Err(AppError::BadRequest("Password must be between 8 and 128 characters.".to_string())),
+        None => Err(AppError::BadRequest("JSON payload structure invalid.".to_string()))
+    }
+}
+
+/// Hashes a password with Argon2 and a random salt into a PHC string.
+// argon2 is deliberately slow, so it runs on the blocking pool instead of stalling the async runtime
+async fn hash_password(password: String) -> Result<Box<str>, Error> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string().into_boxed_str())
+            .map_err(|e| anyhow!("Failed to hash password: {e}"))
+    }).await?
+}
+
+/// Checks a password against a stored PHC hash string. Malformed hashes never verify.
+async fn verify_password(password: String, hash: String) -> Result<bool, Error> {
+    Ok(tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash)
+            .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+    }).await?)
+}
+
+/// Validates username contains no special characters (underscores permitted) and is at least 5 letters/numbers long.
+/// Must include at least one letter and must not be one of the reserved route segments. Checked after NFC normalization.
+fn username_check(json_value: Option<&Value>) -> Result<User, AppError> {
+    let username = json_value.and_then(|username_json| username_json.as_str()).map(normalize_username);
+    let username = username.as_deref();
+    if username.is_some_and(username_is_reserved) {
+        return Err(AppError::BadRequest("Username is reserved".to_string()));
+    }
+    // if the extractor passes and a username field exists + is valid, evaluates to a new user.
+    // For obvious security reasons only plain users can be created via the API.
+    username
+        .filter(|name| username_is_well_formed(name))
+        .map(|name| User::new(name.to_string(), Role::User))
+        .ok_or(AppError::BadRequest("JSON payload structure invalid.".to_string()))
+}
+
+/// `name` in Unicode NFC, so visually identical names like "café" typed precomposed or with a combining accent
+/// compare and store the same.
+fn normalize_username(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Whether `name` is a reserved name, ignoring case.
+fn username_is_reserved(name: &str) -> bool {
+    RESERVED_NAMES.contains(&name.to_lowercase())
+}
+
+/// Whether `name` is 5 to 32 letters, digits or underscores, with at least one letter.
+fn username_is_well_formed(name: &str) -> bool {
+    // the character class is ASCII only on purpose, and stays that way after NFC normalization: normalizing
+    // only folds identical looking sequences together, it does nothing about homoglyphs from other scripts
+    // like a Cyrillic 'а' for a Latin 'a'. Keeping names ASCII rules those out altogether.
+    // rust's regex engine doesn't support look-ahead for some reason, so this checks
+    // for at least 5 and up to 32 alphanumeric values, with at least one of them being strictly alphabetic
+    Regex::new(r"^[_a-zA-Z0-9]{5,32}$").is_ok_and(|val| val.is_match(name))
+        && name.chars().any(|c| c.is_alphabetic())
+}
+
+/// Acquires a connection from `pool`, giving up after `timeout`. `name` identifies the pool in logs.
+async fn acquire_with_timeout(pool: &Pool<Sqlite>, name: &str, timeout: Duration) -> Result<PoolConnection<Sqlite>, Error> {
+    let start = Instant::now();
+    let conn = tokio::time::timeout(timeout, pool.acquire()).await
+        .map_err(|_| anyhow!("Timed out after {timeout:?} acquiring a connection from the {name} pool."))??;
+    let elapsed = start.elapsed();
+    if elapsed > SLOW_ACQUIRE_THRESHOLD {
+        tracing::warn!("Slow acquisition from the {name} pool: {elapsed:?}");
+    }
+    Ok(conn)
+}
+
+/// Find a given User in the database by username, going through state.user_cache first.
+async fn select_by_username(username: &str, state: &State<Arc<AppState>>) -> Option<Result<User, Error>> {
+    let normalized = normalize_username(username);
+    let username = normalized.as_str();
+    if let Some(user) = state.user_cache.lock().unwrap().get(username) {
+        return Some(Ok(user.clone()));
+    }
+    let mut read_conn = match acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await {
+        Ok(conn) => conn,
+        Err(e) => return Some(Err(e))
+    };
+    let user = sqlx::query!(r#"SELECT username, last_online AS "last_online: DateTime<Utc>", created AS "created: DateTime<Utc>", role, country_code, bio, email, website FROM user_table WHERE username = $1 AND deleted_at IS NULL LIMIT 1"#, username)
+        .fetch_optional(&mut *read_conn)
+        .await
+        // branch depending on error status of query. If db has an issue, we have SOME ERRor to
+        // return or we have SOME OK value.
+        .map_or_else(|error| Some(Err(anyhow!("Internal server error: {error}."))), // error case
+                        |row| row.map(|content| // success case
+                            User::create_from_db(content.username,
+                                                    content.last_online,
+                                                    content.created,
+                                                    content.role,
+                                                    content.country_code,
+                                                    content.bio,
+                                                    content.email,
+                                                    content.website)));
+    if let Some(Ok(user)) = &user {
+        state.user_cache.lock().unwrap().put(username.into(), user.clone());
+    }
+    user
+}
+
+/// Finds the undeleted user with this email address, ignoring case.
+async fn select_by_email(email: &str, state: &AppState) -> Result<Option<User>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let row = sqlx::query!(r#"SELECT username, last_online AS "last_online: DateTime<Utc>", created AS "created: DateTime<Utc>", role, country_code, bio, email, website FROM user_table WHERE email = $1 COLLATE NOCASE AND deleted_at IS NULL"#, email)
+        .fetch_optional(&mut *read_conn).await?;
+    row.map(|row| User::create_from_db(row.username, row.last_online, row.created, row.role, row.country_code, row.bio, row.email, row.website))
+        .transpose()
+}
+
+/// Drops a user from state.user_cache, so the next lookup reads what was just written.
+fn forget_cached_user(username: &str, state: &AppState) {
+    state.user_cache.lock().unwrap().pop(username);
+}
+
+/// Inserts a user into persistent storage, auditing it as created by nobody in particular.
+async fn insert_user(user: &User, state: &State<Arc<AppState>>) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    insert_user_row(user, &mut transaction).await?;
+    transaction.commit().await?;
+    forget_cached_user(&user.username, state);
+    Ok(true)
+}
+
+/// The insert and audit entry behind `insert_user`, on a connection the caller commits.
+async fn insert_user_row(user: &User, conn: &mut SqliteConnection) -> Result<(), Error> {
+    let password_hash = &*user.password_hash;
+    let role = u32::from(user.role);
+    let id = sqlx::query_scalar!(r#"INSERT INTO user_table (username, last_online, created, role, country_code, password_hash, email)
+    VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id AS "id!""#, 
+        user.username, 
+        user.last_online, 
+        user.created, 
+        role,
+        user.country_code,
+        password_hash,
+        user.email)
+        .fetch_optional(&mut *conn).await?
+        .ok_or(anyhow!("Unable to create user."))?;
+    append_audit(conn, id, AuditAction::Created, None, None).await
+}
+
+/// Soft deletes a user by stamping `deleted_at`, after which every read skips them. The row stays so posts
+/// and other references keep pointing at it. Returns false if no undeleted user had that name.
+async fn delete_user_db(username: &str, performed_by: Option<i64>, state: &State<Arc<AppState>>) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    let now = Utc::now().to_rfc3339();
+    let Some(id) = sqlx::query_scalar!(r#"UPDATE user_table SET deleted_at = $1 WHERE username = $2 AND deleted_at IS NULL RETURNING id AS "id!""#, now, username)
+        .fetch_optional(&mut *transaction).await? else {
+        return Ok(false);
+    };
+    append_audit(&mut transaction, id, AuditAction::Deleted, performed_by, None).await?;
+    transaction.commit().await?;
+    forget_cached_user(username, state);
+    Ok(true)
+}
+
+/// Sets a user's role, auditing the old and new ones. Returns the old role, or None if no user had that name.
+async fn update_role_db(username: &str, role: Role, performed_by: i64, state: &AppState) -> Result<Option<i64>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    let Some(old) = sqlx::query!(r#"SELECT id AS "id!", role FROM user_table WHERE username = $1 AND deleted_at IS NULL"#, username)
+        .fetch_optional(&mut *transaction).await? else {
+        return Ok(None);
+    };
+    let role = u32::from(role);
+    sqlx::query!("UPDATE user_table SET role = $1 WHERE id = $2", role, old.id)
+        .execute(&mut *transaction).await?;
+    append_audit(&mut transaction, old.id, AuditAction::Updated, Some(performed_by), Some(&format!("role: {} -> {}", old.role, role))).await?;
+    transaction.commit().await?;
+    forget_cached_user(username, state);
+    Ok(Some(old.role))
+}
+
+/// Undoes a soft delete. Returns false if no deleted user had that name.
+async fn restore_user_db(username: &str, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let restore_statement = sqlx::query!("UPDATE user_table SET deleted_at = NULL WHERE username = $1 AND deleted_at IS NOT NULL", username)
+        .execute(&mut *write_conn).await?;
+    forget_cached_user(username, state);
+    Ok(restore_statement.rows_affected() == 1)
+}
+
+/// Lists soft deleted users as (username, deleted_at) pairs, most recently deleted first.
+async fn select_deleted_users(state: &AppState) -> Result<Vec<(String, String)>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let rows = sqlx::query!(r#"SELECT username, deleted_at AS "deleted_at!" FROM user_table WHERE deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC, username"#)
+        .fetch_all(&mut *read_conn).await?;
+    Ok(rows.into_iter().map(|row| (row.username, row.deleted_at)).collect())
+}
+
+/// Whether any user, deleted or not, has this name. Deleted users keep their name so they can be restored.
+async fn username_taken(username: &str, state: &AppState) -> Result<bool, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_scalar!(r#"SELECT COUNT(*) > 0 AS "taken!: bool" FROM user_table WHERE username = $1"#, username)
+        .fetch_one(&mut *read_conn).await?)
+}
+
+/// Applies a profile update, only touching the columns it sets, and audits which columns those were.
+/// Returns false if no user had that name.
+async fn update_user_db(username: &str, update: &UserUpdate, performed_by: Option<i64>, state: &State<Arc<AppState>>) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    // column names are fixed here, only the values come from the caller and those are always bound
+    let mut query = QueryBuilder::<Sqlite>::new("UPDATE user_table SET ");
+    let mut columns = query.separated(", ");
+    let mut changed = Vec::new();
+    for (column, value) in [("bio", &update.bio), ("email", &update.email), ("website", &update.website), ("last_online", &update.last_online)] {
+        if let Some(value) = value {
+            columns.push(format!("{column} = "));
+            columns.push_bind_unseparated(value);
+            changed.push(column);
+        }
+    }
+    query.push(" WHERE username = ").push_bind(username).push(" AND deleted_at IS NULL RETURNING id");
+    let Some(id) = query.build_query_scalar::<i64>().fetch_optional(&mut *transaction).await? else {
+        return Ok(false);
+    };
+    append_audit(&mut transaction, id, AuditAction::Updated, performed_by, Some(&changed.join(", "))).await?;
+    transaction.commit().await?;
+    forget_cached_user(username, state);
+    Ok(true)
+}
+
+/// Finds up to state.per_page users whose name contains `query`, case-insensitively.
+async fn search_users_db(query: &str, state: &AppState) -> Result<Vec<User>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    // '_' is legal in usernames but a LIKE wildcard, so the query is escaped to match literally
+    let pattern = format!("%{}%", query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    let rows = sqlx::query!(r#"SELECT username, last_online AS "last_online: DateTime<Utc>", created AS "created: DateTime<Utc>", role, country_code, bio, email, website FROM user_table WHERE username LIKE $1 ESCAPE '\' COLLATE NOCASE AND deleted_at IS NULL ORDER BY username LIMIT $2"#,
+        pattern,
+        state.per_page)
+        .fetch_all(&mut *read_conn).await?;
+    rows.into_iter()
+        .map(|row| User::create_from_db(row.username, row.last_online, row.created, row.role, row.country_code, row.bio, row.email, row.website))
+        .collect()
+}
+
+/// Fetches the id and stored password hash for a username. None if the user doesn't exist or has no password set.
+async fn select_hash_by_username(username: &str, state: &State<Arc<AppState>>) -> Result<Option<(i64, String)>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    // read through the username index sqlx can no longer tell id is never null, hence the "id!" override
+    let row = sqlx::query!(r#"SELECT id AS "id!", password_hash FROM user_table WHERE username = $1 AND deleted_at IS NULL LIMIT 1"#, username)
+        .fetch_optional(&mut *read_conn)
+        .await?;
+    Ok(row.and_then(|row| row.password_hash.map(|hash| (row.id, hash))))
+}
+
+/// Reads the 1-based `page` query parameter, falling back to the first page when it is missing or invalid.
+fn page_param(params: &HashMap<String, String>) -> u32 {
+    params.get("page")
+        .and_then(|page| page.parse::<u32>().ok())
+        .filter(|page| *page >= 1)
+        .unwrap_or(1)
+}
+
+/// Row offset of the first entry on a 1-based `page`.
+fn page_offset(page: u32, per_page: u32) -> i64 {
+    (i64::from(page) - 1) * i64::from(per_page)
+}
+
+/// Selects `columns` of the users on one page of a user listing.
+fn user_listing_query(columns: &str, listing: &UserListing) -> QueryBuilder<'static, Sqlite> {
+    let mut query = QueryBuilder::<Sqlite>::new(format!("SELECT {columns} FROM user_table WHERE deleted_at IS NULL"));
+    if let Some(role) = listing.role {
+        query.push(" AND role = ").push_bind(u32::from(role));
+    }
+    let direction = if listing.descending { "DESC" } else { "ASC" };
+    // username breaks ties so pages stay stable when many users share a timestamp
+    query.push(format!(" ORDER BY {} {direction}, username {direction}", listing.sort_column))
+        .push(" LIMIT ").push_bind(listing.per_page)
+        .push(" OFFSET ").push_bind(page_offset(listing.page, listing.per_page));
+    query
+}
+
+/// Retrieves the usernames on one page of a user listing, counting the whole listing in the same transaction.
+async fn get_usernames_by_listing(state: &AppState, listing: &UserListing) -> Result<Page<String>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut transaction = read_conn.begin().await?;
+    let usernames = user_listing_query("username", listing).build_query_scalar::<String>().fetch_all(&mut *transaction).await?;
+    let role = listing.role.map(u32::from);
+    let total = sqlx::query_scalar!("SELECT COUNT(*) FROM user_table WHERE deleted_at IS NULL AND ($1 IS NULL OR role = $1)", role)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Page::new(usernames, listing.page, listing.per_page, total))
+}
+
+/// Retrieves the rows of the CSV export for one page of a user listing.
+async fn get_user_rows_by_listing(state: &AppState, listing: &UserListing) -> Result<Vec<UserCsvRow>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(user_listing_query("username, last_online, created, role", listing).build_query_as::<UserCsvRow>().fetch_all(&mut *read_conn).await?)
+}
+
+/// Retrieves up to `cursor.limit` usernames following `cursor.after`, in username order.
+async fn get_usernames_after(state: &AppState, cursor: &UserCursor) -> Result<Vec<String>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut query = QueryBuilder::<Sqlite>::new("SELECT username FROM user_table WHERE deleted_at IS NULL");
+    if let Some(after) = &cursor.after {
+        query.push(" AND username > ").push_bind(after);
+    }
+    if let Some(role) = cursor.role {
+        query.push(" AND role = ").push_bind(u32::from(role));
+    }
+    // the user_table_username index serves this without reading the rows before the cursor
+    query.push(" ORDER BY username LIMIT ").push_bind(cursor.limit);
+    Ok(query.build_query_scalar::<String>().fetch_all(&mut *read_conn).await?)
+}
+
+/// Returns a page of the n=state.per_page User structs on the given page, along with how many users there are in total.
+/// # Arguments
+/// * `state`: Shared app state across threads
+/// * `page`: 1-based page number
+///
+/// returns: Result<Page<User>, Error>
+async fn get_users_by_pagination(state: Arc<AppState>, page: u32) -> Result<Page<User>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut transaction = read_conn.begin().await?;
+    let offset = page_offset(page, state.per_page);
+    let users = sqlx::query!(r#"SELECT username, last_online AS "last_online: DateTime<Utc>", created AS "created: DateTime<Utc>", role, country_code, bio, email, website FROM user_table WHERE deleted_at IS NULL ORDER BY username LIMIT $1 OFFSET $2"#,
+        state.per_page, offset)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_or_else(|err| Err(anyhow!("Internal server error: {err}.")),
+        |record_vec| record_vec.into_iter()
+            .map(|element| {
+                User::create_from_db(element.username, 
+                                     element.last_online, 
+                                     element.created, 
+                                     element.role,
+                                     element.country_code,
+                                     element.bio,
+                                     element.email,
+                                     element.website) }
+            ).collect::<Result<Vec<_>, _>>())?;
+    let total = sqlx::query_scalar!("SELECT COUNT(*) FROM user_table WHERE deleted_at IS NULL")
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Page::new(users, page, state.per_page, total))
+}
+
+/// Looks up the ISO country code of `ip`. Always None when no GeoIP database is configured.
+fn lookup_country(state: &AppState, ip: IpAddr) -> Option<String> {
+    let reader = state.geoip.as_ref()?;
+    let record = reader.lookup::<geoip2::Country>(ip).ok()??;
+    record.country?.iso_code.map(|code| code.to_string())
+}
+
+/// Converts an ISO 3166-1 alpha-2 country code into its flag emoji, which is spelled with the
+/// Unicode Regional Indicator Symbol matching each letter of the code.
+fn flag_emoji(country_code: &str) -> Option<String> {
+    if country_code.len() != 2 || !country_code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    country_code.to_ascii_uppercase().chars()
+        .map(|c| char::from_u32(0x1F1E6 + (c as u32 - 'A' as u32)))
+        .collect()
+}
+
+/// Tera filter rendering a country code as its flag, or nothing for a missing/invalid code.
+fn flag_filter(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(Value::from(value.as_str().and_then(flag_emoji).unwrap_or_default()))
+}
+
+async fn unknown_path() -> Redirect {
+    Redirect::to("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertables::{assert_err, assert_ok};
+    use axum::{body::{to_bytes, Body}, http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER}};
+    use proptest::prelude::*;
+    use serde_json::to_value;
+    use tower::ServiceExt;
+
+    // what BASE_URL falls back to when serving on the default address
+    const DEFAULT_BASE_URL: &str = "http://0.0.0.0:3000/";
+
+    /// App state backed by a fresh in-memory database. A single connection is shared by both
+    /// pools, since every new connection to ':memory:' would otherwise open an empty database.
+    pub(super) async fn test_app_state() -> AppState {
+        let i18n = Arc::new(I18n::load(i18n::LOCALES_DIR).unwrap());
+        init_templates(&i18n);
+        metrics::init_metrics();
+        let pool = sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+        AppState {
+            read_pool: pool.clone(),
+            write_pool: pool,
+            per_page: DEFAULT_PER_PAGE,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            geoip: None,
+            require_invite: false,
+            jwt: Some(jwt::tests::test_keys()),
+            online: watch::Sender::new(0),
+            tls: None,
+            security_headers: SecurityHeadersConfig::default(),
+            metrics_allow: metrics::parse_allow_cidrs(metrics::DEFAULT_METRICS_ALLOW_CIDR).unwrap(),
+            user_cache: Mutex::new(LruCache::new(DEFAULT_USER_CACHE_SIZE)),
+            max_request_body_bytes: DEFAULT_MAX_REQUEST_BODY_BYTES,
+            maintenance: AtomicBool::new(false),
+            i18n,
+            jobs: jobs::JobQueue::new(),
+            cors_origins: cors::CorsOrigins::default()
+        }
+    }
+
+    pub(super) async fn test_state() -> Arc<AppState> {
+        let state = Arc::new(test_app_state().await);
+        jobs::spawn_worker(&state);
+        state
+    }
+
+    /// Runs a request through a fresh router, as if it came from a client at `127.0.0.1`.
+    pub(super) async fn call(state: Arc<AppState>, mut request: Request) -> Response {
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+        app(state).oneshot(request).await.unwrap()
+    }
+
+    pub(super) async fn get_request(state: Arc<AppState>, uri: &str) -> (StatusCode, Vec<u8>) {
+        let response = call(state, Request::get(uri).body(Body::empty()).unwrap()).await;
+        let status = response.status();
+        (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+    }
+
+    /// Creates a user with the given role and logs them in, returning their session cookie.
+    pub(super) async fn session_cookie(state: &Arc<AppState>, username: &str, role: Role) -> String {
+        insert_user(&User::new(username.to_string(), role), &State(state.clone())).await.unwrap();
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = $1"#, username)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        format!("{}={}", session::SESSION_COOKIE, create_session(id, state).await.unwrap().id)
+    }
+
+    /// Creates a user with the given role and returns an `Authorization` header value carrying a token for them.
+    pub(super) async fn bearer(state: &Arc<AppState>, username: &str, role: Role) -> String {
+        insert_user(&User::new(username.to_string(), role), &State(state.clone())).await.unwrap();
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = $1"#, username)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        format!("Bearer {}", state.jwt.as_ref().unwrap().issue(id, role).unwrap())
+    }
+
+    /// The CSRF token of the session a `session_cookie` cookie refers to.
+    pub(super) async fn csrf_token(state: &AppState, cookie: &str) -> String {
+        let session_id = cookie.split_once('=').map(|(_, id)| id).unwrap_or_default();
+        csrf::generate_csrf_token(session_id, state).await.unwrap_or_default()
+    }
+
+    /// Sends a bodiless request, optionally carrying a session cookie and its CSRF token.
+    pub(super) async fn send(state: Arc<AppState>, method: &str, uri: &str, cookie: Option<&str>) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(cookie) = cookie {
+            request = request.header(COOKIE, cookie).header(csrf::CSRF_HEADER, csrf_token(&state, cookie).await);
+        }
+        let response = call(state, request.body(Body::empty()).unwrap()).await;
+        let status = response.status();
+        (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+    }
+
+    pub(super) async fn post_json(state: Arc<AppState>, uri: &str, json: Value) -> (StatusCode, Vec<u8>) {
+        let request = Request::post(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json.to_string()))
+            .unwrap();
+        let response = call(state, request).await;
+        let status = response.status();
+        (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+    }
+
+    #[test]
+    fn test_valid_user_api_post_value() {
+        let json = to_value("Water_Bottle".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_ok!(result);
+        let json = to_value("Water_Bottle123".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_ok!(result);
+        let json = to_value("123Water_Bottle".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_ok!(result);
+        let json = to_value("1234f".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_ok!(result);
+    }
+    #[test]
+    fn test_invalid_user_api_post_type() {
+        let json = to_value(true).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value(1).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value([1, 5]).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value(["test", "test_string_vec"]).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+    }
+
+    #[test]
+    fn test_username_normalization() {
+        // 'é' precomposed and as 'e' plus a combining acute accent
+        assert_eq!(normalize_username("caf\u{e9}_user"), normalize_username("cafe\u{301}_user"));
+        assert_eq!(normalize_username("cafe\u{301}_user"), "caf\u{e9}_user");
+        assert_eq!(normalize_username("Water_Bottle"), "Water_Bottle");
+    }
+
+    #[test]
+    fn test_username_stays_ascii_after_normalization() {
+        for name in ["caf\u{e9}_user", "cafe\u{301}_user", "\u{430}lpha_user"] {
+            assert_err!(username_check(Some(&to_value(name).unwrap())));
+        }
+    }
+
+    #[test]
+    fn test_invalid_user_api_post_name() {
+        let json = to_value("  f".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value("f  ".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value("   ".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value("DELETE * FROM user_table WHERE 1=1;".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+        let json = to_value("1234".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_err!(result);
+    }
+
+    #[test]
+    fn test_reserved_user_api_post_name() {
+        for reserved in RESERVED_NAMES.iter().map(String::as_str).chain(["Admin", "USERS", "Feed.xml", "sitemap.xml", "ws", "search"]) {
+            let json = to_value(reserved.to_string()).unwrap();
+            let result = username_check(Some(&json));
+            assert!(matches!(result, Err(AppError::BadRequest(reason)) if reason == "Username is reserved"), "{reserved}");
+        }
+        // only exact matches are reserved
+        let json = to_value("administration".to_string()).unwrap();
+        let result = username_check(Some(&json));
+        assert_ok!(result);
+    }
+
+    // the invariants the hand-picked cases above only sample
+    proptest! {
+        #[test]
+        fn prop_well_formed_names_with_a_letter_are_accepted(name in "[_a-zA-Z0-9]{5,32}") {
+            prop_assume!(name.chars().any(|c| c.is_ascii_alphabetic()) && !username_is_reserved(&name));
+            let user = username_check(Some(&to_value(&name).unwrap())).unwrap();
+            prop_assert_eq!((user.username, user.role), (name, Role::User));
+        }
+
+        #[test]
+        fn prop_short_names_are_rejected(name in ".{0,4}") {
+            prop_assert!(username_check(Some(&to_value(&name).unwrap())).is_err());
+        }
+
+        #[test]
+        fn prop_names_with_other_characters_are_rejected(prefix in "[_a-zA-Z0-9]{0,16}", other in "[^_a-zA-Z0-9]",
+                                                           suffix in "[_a-zA-Z0-9]{0,16}") {
+            let name = format!("{prefix}{other}{suffix}");
+            prop_assert!(username_check(Some(&to_value(&name).unwrap())).is_err());
+        }
+
+        #[test]
+        fn prop_all_digit_names_are_rejected(name in "[0-9]{5,32}") {
+            prop_assert!(username_check(Some(&to_value(&name).unwrap())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_embedded_sbom_lists_components() {
+        let sbom: Value = serde_json::from_str(SBOM).unwrap();
+        assert_eq!(sbom["bomFormat"], "CycloneDX");
+        let components = sbom["components"].as_array().unwrap();
+        assert!(!components.is_empty());
+        assert!(components.iter().any(|component| component["name"] == "axum"));
+    }
+
+    #[tokio::test]
+    async fn test_sbom_is_admin_only() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let response = call(state.clone(), Request::get("/api/system/sbom").header(COOKIE, &admin).body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let sbom: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert!(!sbom["components"].as_array().unwrap().is_empty());
+        let user = session_cookie(&state, "plain_user", Role::User).await;
+        assert_eq!(send(state.clone(), "GET", "/api/system/sbom", Some(&user)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state, "GET", "/api/system/sbom", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_times_out_on_exhausted_pool() {
+        let state = test_state().await;
+        // the test pool only has one connection, so holding it starves the next acquisition
+        let _held = state.read_pool.acquire().await.unwrap();
+        let result = acquire_with_timeout(&state.read_pool, "read", Duration::from_millis(50)).await;
+        assert_err!(result);
+    }
+
+    #[test]
+    fn test_user_age_days() {
+        let at = |timestamp: &str| DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&Utc);
+        let mut user = User::new("tenured_user".to_string(), Role::User);
+        let now = at("2025-03-01T12:00:00+00:00");
+        user.created = at("2025-03-01T00:00:00+00:00");
+        assert_eq!(user.age_days_at(now), 0);
+        user.created = at("2024-03-02T12:00:00+00:00");
+        assert_eq!(user.age_days_at(now), 364);
+        user.created = at("2024-03-01T12:00:00+00:00");
+        assert_eq!(user.age_days_at(now), 365);
+        // the calendar year leading up to 2024-03-01 contains February 29th, so it spans 366 days
+        user.created = at("2023-03-01T12:00:00+00:00");
+        assert_eq!(user.age_days_at(at("2024-03-01T12:00:00+00:00")), 366);
+        user.created = at("2025-03-02T00:00:00+00:00");
+        assert_eq!(user.age_days_at(now), 0);
+    }
+
+    #[tokio::test]
+    async fn test_user_timestamps_round_trip() {
+        let state = test_state().await;
+        let mut user = User::new("timestamped_user".to_string(), Role::User);
+        user.created = DateTime::parse_from_rfc3339("2024-05-06T07:08:09.123+00:00").unwrap().with_timezone(&Utc);
+        insert_user(&user, &State(state.clone())).await.unwrap();
+        let stored = select_by_username("timestamped_user", &State(state.clone())).await.unwrap().unwrap();
+        assert_eq!(stored.created, user.created);
+        assert_eq!(stored.created.timestamp_subsec_millis(), 123);
+        assert_eq!(stored.last_online, user.last_online);
+        // the API keeps returning the same RFC 3339 strings as before
+        let json = serde_json::to_value(&stored).unwrap();
+        assert_eq!(json["created"], "2024-05-06T07:08:09.123+00:00");
+    }
+
+    #[tokio::test]
+    async fn test_user_lookups_are_cached() {
+        let state = Arc::new(AppState { acquire_timeout: Duration::from_millis(50), ..test_app_state().await });
+        insert_user(&User::new("cached_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        assert!(select_by_username("cached_user", &State(state.clone())).await.unwrap().is_ok());
+        // the test pool only has one connection, so with it held any query would time out
+        let _held = state.read_pool.acquire().await.unwrap();
+        let cached = select_by_username("cached_user", &State(state.clone())).await.unwrap().unwrap();
+        assert_eq!(cached.username, "cached_user");
+        assert_err!(select_by_username("uncached_user", &State(state.clone())).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_user_cache_invalidation() {
+        let state = test_state().await;
+        insert_user(&User::new("cached_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        assert_eq!(select_by_username("cached_user", &State(state.clone())).await.unwrap().unwrap().bio, None);
+        let update = UserUpdate { bio: Some("fresh".to_string()), ..UserUpdate::default() };
+        update_user_db("cached_user", &update, None, &State(state.clone())).await.unwrap();
+        let updated = select_by_username("cached_user", &State(state.clone())).await.unwrap().unwrap();
+        assert_eq!(updated.bio.as_deref(), Some("fresh"));
+        delete_user_db("cached_user", None, &State(state.clone())).await.unwrap();
+        assert!(select_by_username("cached_user", &State(state.clone())).await.is_none());
+        restore_user_db("cached_user", &state).await.unwrap();
+        assert!(select_by_username("cached_user", &State(state)).await.is_some());
+    }
+
+    #[test]
+    fn test_format_tenure() {
+        assert_eq!(format_tenure(0), "Member for 0 days");
+        assert_eq!(format_tenure(1), "Member for 1 day");
+        assert_eq!(format_tenure(29), "Member for 29 days");
+        assert_eq!(format_tenure(30), "Member for 1 month");
+        assert_eq!(format_tenure(364), "Member for 12 months");
+        assert_eq!(format_tenure(365), "Member for 1 year");
+        assert_eq!(format_tenure(366), "Member for 1 year");
+        assert_eq!(format_tenure(730), "Member for 2 years");
+    }
+
+    #[test]
+    fn test_role_conversions() {
+        for role in [Role::Admin, Role::Mod, Role::User] {
+            assert_eq!(Role::try_from(u32::from(role)), Ok(role));
+        }
+        assert_eq!(Role::try_from(3_u32), Err(()));
+        assert_eq!(serde_json::to_value(Role::Mod).unwrap(), "mod");
+        assert_eq!(serde_json::from_value::<Role>(Value::from("admin")).unwrap(), Role::Admin);
+        assert!(role_from_db(-1).is_err());
+    }
+
+    #[test]
+    fn test_role_ordering() {
+        assert!(Role::Admin.at_least(Role::Admin) && Role::Admin.at_least(Role::Mod) && Role::Admin.at_least(Role::User));
+        assert!(!Role::Mod.at_least(Role::Admin) && Role::Mod.at_least(Role::Mod) && Role::Mod.at_least(Role::User));
+        assert!(!Role::User.at_least(Role::Admin) && !Role::User.at_least(Role::Mod) && Role::User.at_least(Role::User));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_stored_role_is_an_error() {
+        let state = test_state().await;
+        insert_user(&User::new("corrupt_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        sqlx::query("UPDATE user_table SET role = 7 WHERE username = 'corrupt_user'").execute(&state.write_pool).await.unwrap();
+        assert!(select_by_username("corrupt_user", &State(state)).await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_templates_initialise_once() {
+        let i18n = Arc::new(I18n::load(i18n::LOCALES_DIR).unwrap());
+        let handles: Vec<_> = (0..8).map(|_| {
+            let i18n = i18n.clone();
+            std::thread::spawn(move || init_templates(&i18n) as *const Tera as usize)
+        }).collect();
+        let addresses: Vec<usize> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert!(addresses.iter().all(|address| *address == addresses[0]));
+        assert!(std::ptr::eq(templates(), init_templates(&i18n)));
+    }
+
+    #[test]
+    fn test_flag_emoji() {
+        assert_eq!(flag_emoji("US").as_deref(), Some("\u{1F1FA}\u{1F1F8}"));
+        assert_eq!(flag_emoji("de").as_deref(), Some("\u{1F1E9}\u{1F1EA}"));
+        assert_eq!(flag_emoji("USA"), None);
+        assert_eq!(flag_emoji("1A"), None);
+        assert_eq!(flag_emoji(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_users_list_shows_country_flag() {
+        let state = test_state().await;
+        let mut user = User::new("flagged_user".to_string(), Role::User);
+        user.country_code = Some("CA".to_string());
+        insert_user(&user, &State(state.clone())).await.unwrap();
+        insert_user(&User::new("unflagged_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        let (status, body) = get_request(state, "/users").await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("flagged_user \u{1F1E8}\u{1F1E6}"));
+        assert!(body.contains("unflagged_user </p>"));
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_gzip_compressed_on_request() {
+        use std::io::Read;
+        let state = test_state().await;
+        insert_user(&User::new("zipped_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        // bodies under 32 bytes aren't worth compressing, so this uses a page rather than a tiny JSON list
+        let (_, expected) = get_request(state.clone(), "/users").await;
+        let request = Request::get("/users").header(ACCEPT_ENCODING, "gzip").body(Body::empty()).unwrap();
+        let response = call(state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        let compressed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut body = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut body).unwrap();
+        assert_eq!(body, expected);
+    }
+
+    async fn insert_invite(state: &AppState, code: &str, expires_at: DateTime<Utc>) {
+        let (created_at, expires_at) = (Utc::now().to_rfc3339(), expires_at.to_rfc3339());
+        sqlx::query!("INSERT INTO invite_table (code, created_by, created_at, expires_at) VALUES ($1, 'admin_user', $2, $3)", code, created_at, expires_at)
+            .execute(&state.write_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_post_user_requires_valid_invite() {
+        let mut state = test_app_state().await;
+        state.require_invite = true;
+        let state = Arc::new(state);
+        insert_invite(&state, "valid_code", Utc::now() + chrono::Duration::days(1)).await;
+        insert_invite(&state, "expired_code", Utc::now() - chrono::Duration::days(1)).await;
+
+        let (status, body) = post_json(state.clone(), "/api/users", serde_json::json!({"username": "invited_user", "password": "hunter2_hunter2"})).await;
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!((status, body), (StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invite code required.", "code": 400})));
+        let (status, _) = post_json(state.clone(), "/api/users",
+                                    serde_json::json!({"username": "invited_user", "password": "hunter2_hunter2", "invite_code": "unknown_code"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post_json(state.clone(), "/api/users",
+                                    serde_json::json!({"username": "invited_user", "password": "hunter2_hunter2", "invite_code": "expired_code"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post_json(state.clone(), "/api/users",
+                                    serde_json::json!({"username": "invited_user", "password": "hunter2_hunter2", "invite_code": "valid_code"})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let used_by = sqlx::query_scalar!("SELECT used_by FROM invite_table WHERE code = 'valid_code'")
+            .fetch_one(&state.read_pool).await.unwrap();
+        assert_eq!(used_by.as_deref(), Some("invited_user"));
+        // codes are single use
+        let (status, _) = post_json(state.clone(), "/api/users",
+                                    serde_json::json!({"username": "second_user", "password": "hunter2_hunter2", "invite_code": "valid_code"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(select_by_username("second_user", &State(state)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_usernames_unique_among_undeleted_users() {
+        let state = test_state().await;
+        insert_user(&User::new("twin_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        // a sign-up that raced past 'username_taken' runs into the index instead
+        let error = insert_user(&User::new("twin_user".to_string(), Role::User), &State(state.clone())).await.unwrap_err();
+        assert_eq!(account_conflict(error).into_response().status(), StatusCode::CONFLICT);
+        // only the app keeps deleted accounts' names, the index doesn't
+        delete_user_db("twin_user", None, &State(state.clone())).await.unwrap();
+        insert_user(&User::new("twin_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_sign_up_leaves_invite_unused() {
+        let mut state = test_app_state().await;
+        state.require_invite = true;
+        let state = Arc::new(state);
+        insert_invite(&state, "first_code", Utc::now() + chrono::Duration::days(1)).await;
+        insert_invite(&state, "second_code", Utc::now() + chrono::Duration::days(1)).await;
+        let (status, _) = post_json(state.clone(), "/api/users", serde_json::json!(
+            {"username": "first_user", "password": "hunter2_hunter2", "invite_code": "first_code", "email": "taken@example.com"})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        // the insert fails on the email, which has to undo the claim
+        let (status, _) = post_json(state.clone(), "/api/users", serde_json::json!(
+            {"username": "second_user", "password": "hunter2_hunter2", "invite_code": "second_code", "email": "taken@example.com"})).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let used_by = sqlx::query_scalar!("SELECT used_by FROM invite_table WHERE code = 'second_code'")
+            .fetch_one(&state.read_pool).await.unwrap();
+        assert_eq!(used_by, None);
+    }
+
+    #[tokio::test]
+    async fn test_post_user_without_invite_requirement() {
+        let state = test_state().await;
+        let (status, _) = post_json(state, "/api/users", serde_json::json!({"username": "open_user", "password": "hunter2_hunter2"})).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_base_url_from_env_sets_location() {
+        // SAFETY: no other test reads or writes BASE_URL
+        unsafe { env::set_var("BASE_URL", "https://example.com") };
+        let base_url = base_url_from_env(ServeArgs::default().addr());
+        unsafe { env::remove_var("BASE_URL") };
+        assert_eq!(base_url, "https://example.com/");
+        assert_eq!(base_url_from_env(ServeArgs::default().addr()), DEFAULT_BASE_URL);
+        assert_eq!(base_url_from_env(SocketAddr::from(([127, 0, 0, 1], 8080))), "http://127.0.0.1:8080/");
+
+        let mut state = test_app_state().await;
+        state.base_url = base_url;
+        let request = Request::post("/api/users")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({"username": "located_user", "password": "hunter2_hunter2"}).to_string()))
+            .unwrap();
+        let response = call(Arc::new(state), request).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[LOCATION], "https://example.com/user/located_user");
+    }
+
+    async fn paginated_state(per_page: u32, user_count: usize) -> Arc<AppState> {
+        let mut state = test_app_state().await;
+        state.per_page = per_page;
+        let state = Arc::new(state);
+        for i in 0..user_count {
+            insert_user(&User::new(format!("paged_user_{i:02}"), Role::User), &State(state.clone())).await.unwrap();
+        }
+        state
+    }
+
+    #[tokio::test]
+    async fn test_get_users_by_pagination_pages() {
+        let state = paginated_state(3, 7).await;
+        let names = |users: Page<User>| users.items.into_iter().map(|user| user.username).collect::<Vec<_>>();
+        assert_eq!(names(get_users_by_pagination(state.clone(), 1).await.unwrap()),
+                   ["paged_user_00", "paged_user_01", "paged_user_02"]);
+        assert_eq!(names(get_users_by_pagination(state.clone(), 2).await.unwrap()),
+                   ["paged_user_03", "paged_user_04", "paged_user_05"]);
+        assert_eq!(names(get_users_by_pagination(state.clone(), 3).await.unwrap()), ["paged_user_06"]);
+        let past_end = get_users_by_pagination(state.clone(), 4).await.unwrap();
+        assert!(past_end.items.is_empty());
+        assert_eq!((past_end.total, past_end.total_pages), (7, 3));
+        let listing = UserListing { sort_column: "username", descending: false, role: None, page: 2, per_page: 3 };
+        let page = get_usernames_by_listing(&state, &listing).await.unwrap();
+        assert_eq!(page, Page { items: vec!["paged_user_03".to_string(), "paged_user_04".to_string(), "paged_user_05".to_string()],
+                                page: 2, per_page: 3, total: 7, total_pages: 3 });
+    }
+
+    #[tokio::test]
+    async fn test_get_users_by_cursor() {
+        let state = paginated_state(3, 7).await;
+        let page = |uri: String| {
+            let state = state.clone();
+            async move {
+                let (status, body) = get_request(state, &uri).await;
+                (status, serde_json::from_slice::<Value>(&body).unwrap_or_default())
+            }
+        };
+        let (status, first) = page("/api/users?limit=3".to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first, serde_json::json!({"users": ["paged_user_00", "paged_user_01", "paged_user_02"], "next_cursor": "paged_user_02"}));
+        // clients should percent-encode the cursor, which must come back out unchanged
+        let (_, second) = page("/api/users?limit=3&after=paged%5Fuser%5F02".to_string()).await;
+        assert_eq!(second["users"], serde_json::json!(["paged_user_03", "paged_user_04", "paged_user_05"]));
+        let (_, last) = page(format!("/api/users?limit=3&after={}", second["next_cursor"].as_str().unwrap())).await;
+        assert_eq!(last, serde_json::json!({"users": ["paged_user_06"], "next_cursor": null}));
+        // without a limit, pages are state.per_page long
+        let (_, default_limit) = page("/api/users?after=paged_user_05".to_string()).await;
+        assert_eq!(default_limit["users"], serde_json::json!(["paged_user_06"]));
+        let (_, past_end) = page("/api/users?after=zzz".to_string()).await;
+        assert_eq!(past_end, serde_json::json!({"users": [], "next_cursor": null}));
+
+        for uri in ["/api/users?limit=3&page=2", "/api/users?after=a&sort_by=created", "/api/users?limit=3&order=desc"] {
+            assert_eq!(page(uri.to_string()).await.0, StatusCode::BAD_REQUEST, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_users_sorting_and_filtering() {
+        let state = test_state().await;
+        for (name, role, created) in [("charlie_user", Role::User, "2024-01-03T00:00:00+00:00"),
+                                      ("alpha_user", Role::User, "2024-01-02T00:00:00+00:00"),
+                                      ("bravo_mod", Role::Mod, "2024-01-01T00:00:00+00:00")] {
+            let mut user = User::new(name.to_string(), role);
+            user.created = DateTime::parse_from_rfc3339(created).unwrap().with_timezone(&Utc);
+            insert_user(&user, &State(state.clone())).await.unwrap();
+        }
+        let names = |uri: &'static str| {
+            let state = state.clone();
+            async move {
+                let (status, body) = get_request(state, uri).await;
+                let page = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+                (status, serde_json::from_value::<Vec<String>>(page["items"].clone()).unwrap_or_default())
+            }
+        };
+        assert_eq!(names("/api/users").await, (StatusCode::OK, vec!["alpha_user".into(), "bravo_mod".into(), "charlie_user".into()]));
+        assert_eq!(names("/api/users?sort_by=created").await.1, ["bravo_mod", "alpha_user", "charlie_user"]);
+        assert_eq!(names("/api/users?sort_by=created&order=desc").await.1, ["charlie_user", "alpha_user", "bravo_mod"]);
+        assert_eq!(names("/api/users?role=user&order=desc").await.1, ["charlie_user", "alpha_user"]);
+        assert_eq!(names("/api/users?per_page=1&page=2").await.1, ["bravo_mod"]);
+        assert_eq!(names("/api/users?sort_by=password_hash").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(names("/api/users?sort_by=username;DROP%20TABLE%20user_table").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(names("/api/users?order=sideways").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(names("/api/users?role=7").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(names("/api/users?role=Admin").await.0, StatusCode::BAD_REQUEST);
+
+        // the envelope counts the whole filtered listing, not just the page
+        for (uri, total, total_pages) in [("/api/users?per_page=2", 3, 2), ("/api/users?per_page=3", 3, 1), ("/api/users?role=user&per_page=1", 2, 2),
+                                          ("/api/users?role=admin", 0, 0)] {
+            let page = serde_json::from_slice::<Value>(&get_request(state.clone(), uri).await.1).unwrap();
+            assert_eq!((page["total"].as_u64().unwrap(), page["total_pages"].as_u64().unwrap()), (total, total_pages), "{uri}");
+            let per_page = page["per_page"].as_u64().unwrap();
+            assert!(per_page * total_pages >= total && per_page * total_pages < total + per_page, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_users_content_negotiation() {
+        let state = test_state().await;
+        for (name, role) in [("bravo_mod", Role::Mod), ("alpha_user", Role::User)] {
+            let mut user = User::new(name.to_string(), role);
+            user.created = DateTime::parse_from_rfc3339("2024-01-02T00:00:00+00:00").unwrap().with_timezone(&Utc);
+            user.last_online = DateTime::parse_from_rfc3339("2024-02-03T04:05:06+00:00").unwrap().with_timezone(&Utc);
+            insert_user(&user, &State(state.clone())).await.unwrap();
+        }
+        let request = |accept: &'static str| {
+            let state = state.clone();
+            async move {
+                let response = call(state, Request::get("/api/users").header(ACCEPT, accept).body(Body::empty()).unwrap()).await;
+                let headers = response.headers().clone();
+                (response.status(), headers, String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap())
+            }
+        };
+
+        let (status, headers, body) = request("text/csv").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_TYPE], "text/csv; charset=utf-8");
+        assert_eq!(headers[axum::http::header::CONTENT_DISPOSITION], "attachment; filename=\"users.csv\"");
+        assert_eq!(body, "username,last_online,created,role\n\
+                          alpha_user,2024-02-03T04:05:06+00:00,2024-01-02T00:00:00+00:00,user\n\
+                          bravo_mod,2024-02-03T04:05:06+00:00,2024-01-02T00:00:00+00:00,mod\n");
+        // filters and paging apply to the export too, and an empty one still has its header
+        let response = call(state.clone(), Request::get("/api/users?role=admin").header(ACCEPT, "text/csv").body(Body::empty()).unwrap()).await;
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), "username,last_online,created,role\n");
+
+        for accept in ["application/json", "*/*", "text/html, application/json;q=0.9"] {
+            let (status, headers, body) = request(accept).await;
+            assert_eq!((status, &headers[CONTENT_TYPE]), (StatusCode::OK, &HeaderValue::from_static("application/json")), "{accept}");
+            assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["items"], serde_json::json!(["alpha_user", "bravo_mod"]));
+        }
+        assert_eq!(get_request(state.clone(), "/api/users").await.0, StatusCode::OK);
+        assert_eq!(request("text/html").await.0, StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(request("application/xml, image/png").await.0, StatusCode::NOT_ACCEPTABLE);
+        let response = call(state, Request::get("/api/users?limit=1").header(ACCEPT, "text/csv").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_users_list_route_renders_requested_page() {
+        let state = paginated_state(3, 7).await;
+        let (_, body) = get_request(state.clone(), "/users?page=2").await;
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("paged_user_03"));
+        assert!(!body.contains("paged_user_02"));
+        assert!(body.contains("Page 2"));
+        assert!(body.contains("users?page=1"));
+        assert!(body.contains("users?page=3"));
+        // invalid page numbers fall back to the first page
+        let (_, body) = get_request(state, "/users?page=0").await;
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("paged_user_00"));
+        assert!(!body.contains("users?page=0"));
+    }
+
+    #[tokio::test]
+    async fn test_user_profile_page() {
+        let state = test_state().await;
+        let mut user = User::new("profile_user".to_string(), Role::Mod);
+        user.country_code = Some("NZ".to_string());
+        insert_user(&user, &State(state.clone())).await.unwrap();
+        let (status, body) = get_request(state.clone(), "/user/profile_user").await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("profile_user \u{1F1F3}\u{1F1FF}"));
+        assert!(body.contains("Mod"));
+        assert!(body.contains("Member for 0 days"));
+
+        let (status, body) = get_request(state.clone(), "/user/missing_user").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(String::from_utf8(body).unwrap().contains("No user named &#x27;missing_user&#x27; exists."));
+
+        state.read_pool.close().await;
+        // profile_user is cached by now, so it takes a user that has never been looked up to reach the database
+        let (status, body) = get_request(state, "/user/missing_user").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(String::from_utf8(body).unwrap().contains("Cannot display user."));
+    }
+
+    #[test]
+    fn test_password_check() {
+        assert_ok!(password_check(Some(&to_value("12345678").unwrap())));
+        assert_ok!(password_check(Some(&to_value("p".repeat(128)).unwrap())));
+        assert_err!(password_check(Some(&to_value("1234567").unwrap())));
+        assert_err!(password_check(Some(&to_value("p".repeat(129)).unwrap())));
+        assert_err!(password_check(Some(&to_value(12345678).unwrap())));
+        assert_err!(password_check(None));
+    }
+
+    #[tokio::test]
+    async fn test_post_user_rejects_invalid_password() {
+        let state = test_state().await;
+        let (status, _) = post_json(state.clone(), "/api/users", serde_json::json!({"username": "short_pass", "password": "short"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post_json(state.clone(), "/api/users", serde_json::json!({"username": "no_password"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(select_by_username("short_pass", &State(state)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let state = test_state().await;
+        let (status, _) = post_json(state.clone(), "/api/users",
+                                    serde_json::json!({"username": "login_user", "password": "correct horse"})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        // the hash is stored, never the password itself
+        let (_, stored) = select_hash_by_username("login_user", &State(state.clone())).await.unwrap().unwrap();
+        assert!(stored.starts_with("$argon2"));
+
+        let (status, _) = post_json(state.clone(), "/api/login",
+                                    serde_json::json!({"username": "login_user", "password": "correct horse"})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = post_json(state.clone(), "/api/login",
+                                    serde_json::json!({"username": "login_user", "password": "wrong horse"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post_json(state.clone(), "/api/login",
+                                    serde_json::json!({"username": "nobody_here", "password": "correct horse"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post_json(state, "/api/login", serde_json::json!({"username": "login_user"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_per_page_from_env() {
+        // no other test reads PER_PAGE, so changing it here can't race with them
+        for (value, expected) in [(None, Some(DEFAULT_PER_PAGE)), (Some("5"), Some(5)), (Some("200"), Some(200)), (Some("0"), None),
+                                  (Some("201"), None), (Some("five"), None)] {
+            match value {
+                Some(value) => unsafe { env::set_var("PER_PAGE", value) },
+                None => unsafe { env::remove_var("PER_PAGE") }
+            }
+            assert_eq!(per_page_from_env().ok(), expected, "{value:?}");
+        }
+        unsafe { env::remove_var("PER_PAGE") };
+    }
+
+    #[tokio::test]
+    async fn test_create_admin_user() {
+        let state = test_state().await;
+        create_admin_user("site_admin", "correct horse".to_string(), &state).await.unwrap();
+        let role = sqlx::query_scalar!("SELECT role FROM user_table WHERE username = 'site_admin'")
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!(role, 0);
+        let (status, _) = post_json(state.clone(), "/api/login",
+                                    serde_json::json!({"username": "site_admin", "password": "correct horse"})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert!(create_admin_user("site_admin", "correct horse".to_string(), &state).await.is_err());
+        // reserved names are only off limits to sign-ups
+        create_admin_user("admin", "correct horse".to_string(), &state).await.unwrap();
+        assert_eq!(post_json(state.clone(), "/api/users", serde_json::json!({"username": "users", "password": "correct horse"})).await.0,
+                   StatusCode::BAD_REQUEST);
+        assert!(create_admin_user("bad name!", "correct horse".to_string(), &state).await.is_err());
+        assert!(create_admin_user("other_admin", "short".to_string(), &state).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login_session_cookie() {
+        let state = test_state().await;
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "cookie_user", "password": "correct horse"})).await;
+        let login = Request::post("/api/login")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({"username": "cookie_user", "password": "correct horse"}).to_string()))
+            .unwrap();
+        let response = call(state.clone(), login).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+
+        let csrf = std::cell::RefCell::new(String::new());
+        let with_cookie = |method: &str, uri: &str| Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(COOKIE, &cookie)
+            .header(csrf::CSRF_HEADER, csrf.borrow().as_str())
+            .body(Body::empty())
+            .unwrap();
+        let response = call(state.clone(), with_cookie("GET", "/api/session")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!((&body["username"], &body["role"]), (&Value::from("cookie_user"), &Value::from("user")));
+
+        // pages hand logged in visitors the CSRF token their state changing requests must carry
+        let response = call(state.clone(), with_cookie("GET", "/")).await;
+        let page = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        let token = page.split(r#"<meta name="csrf-token" content=""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap();
+        assert_eq!(token.len(), 64);
+        *csrf.borrow_mut() = token.to_string();
+
+        let response = call(state.clone(), with_cookie("POST", "/api/logout")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[SET_COOKIE].to_str().unwrap().contains("Max-Age=0"));
+        let response = call(state.clone(), with_cookie("GET", "/api/session")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let (status, _) = get_request(state, "/api/session").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let moderator = session_cookie(&state, "mod_user", Role::Mod).await;
+        insert_user(&User::new("doomed_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+
+        let (status, _) = send(state.clone(), "DELETE", "/api/users/doomed_user", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(state.clone(), "DELETE", "/api/users/doomed_user", Some(&moderator)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(select_by_username("doomed_user", &State(state.clone())).await.is_some());
+
+        let (status, _) = send(state.clone(), "DELETE", "/api/users/doomed_user", Some(&admin)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(select_by_username("doomed_user", &State(state.clone())).await.is_none());
+        let (status, _) = send(state, "DELETE", "/api/users/doomed_user", Some(&admin)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_soft_deleted_users_can_be_listed_and_restored() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let doomed = session_cookie(&state, "doomed_user", Role::User).await;
+        let usernames = |state: Arc<AppState>| async move {
+            let page = serde_json::from_slice::<Value>(&get_request(state, "/api/users").await.1).unwrap();
+            serde_json::from_value::<Vec<String>>(page["items"].clone()).unwrap()
+        };
+        let deleted = |state: Arc<AppState>| {
+            let admin = admin.clone();
+            async move {
+                let (status, body) = send(state, "GET", "/api/admin/users/deleted", Some(&admin)).await;
+                (status, serde_json::from_slice::<Value>(&body).unwrap_or_default())
+            }
+        };
+        assert_eq!(deleted(state.clone()).await, (StatusCode::OK, serde_json::json!([])));
+
+        let (status, _) = send(state.clone(), "DELETE", "/api/users/doomed_user", Some(&admin)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(usernames(state.clone()).await, ["admin_user"]);
+        let (_, listed) = deleted(state.clone()).await;
+        assert_eq!(listed[0]["username"], "doomed_user");
+        assert!(DateTime::parse_from_rfc3339(listed[0]["deleted_at"].as_str().unwrap()).is_ok());
+        // the row is still there, so neither its session nor its name are up for grabs
+        assert_eq!(send(state.clone(), "GET", "/api/session", Some(&doomed)).await.0, StatusCode::UNAUTHORIZED);
+        let (status, _) = post_json(state.clone(), "/api/users", serde_json::json!({"username": "doomed_user", "password": "hunter2_hunter2"})).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(send(state.clone(), "GET", "/api/admin/users/deleted", Some(&doomed)).await.0, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = send(state.clone(), "POST", "/api/admin/users/doomed_user/restore", Some(&admin)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["username"], "doomed_user");
+        assert_eq!(usernames(state.clone()).await, ["admin_user", "doomed_user"]);
+        assert_eq!(deleted(state.clone()).await.1, serde_json::json!([]));
+        let (status, _) = send(state, "POST", "/api/admin/users/doomed_user/restore", Some(&admin)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_set_role() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        let moderator = session_cookie(&state, "mod_user", Role::Mod).await;
+        session_cookie(&state, "plain_user", Role::User).await;
+        let set_role = |cookie: &str, username: &str, role: Value| {
+            let (state, cookie, uri) = (state.clone(), cookie.to_string(), format!("/api/admin/users/{username}/role"));
+            async move {
+                let request = Request::put(uri)
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(COOKIE, &cookie)
+                    .header(csrf::CSRF_HEADER, csrf_token(&state, &cookie).await)
+                    .body(Body::from(serde_json::json!({"role": role}).to_string()))
+                    .unwrap();
+                call(state, request).await.status()
+            }
+        };
+        assert_eq!(set_role(&moderator, "plain_user", Value::from("user")).await, StatusCode::FORBIDDEN);
+        assert_eq!(set_role(&admin, "admin_user", Value::from("user")).await, StatusCode::FORBIDDEN);
+        for bad_role in [Value::from(1), Value::from("1"), Value::from("Mod"), Value::from("owner")] {
+            assert_eq!(set_role(&admin, "plain_user", bad_role).await, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(set_role(&admin, "nobody_here", Value::from("mod")).await, StatusCode::NOT_FOUND);
+
+        // looked up once beforehand, so the change only shows if the cache entry was dropped
+        assert_eq!(select_by_username("plain_user", &State(state.clone())).await.unwrap().unwrap().role, Role::User);
+        assert_eq!(set_role(&admin, "plain_user", Value::from("mod")).await, StatusCode::OK);
+        assert_eq!(select_by_username("plain_user", &State(state.clone())).await.unwrap().unwrap().role, Role::Mod);
+        let detail = sqlx::query_scalar!("SELECT detail FROM audit_log_table ORDER BY id DESC LIMIT 1")
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        assert_eq!(detail.as_deref(), Some("role: 2 -> 1"));
+    }
+
+    async fn patch_json(state: Arc<AppState>, uri: &str, cookie: &str, json: Value) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder()
+            .method("PATCH")
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .header(COOKIE, cookie)
+            .header(csrf::CSRF_HEADER, csrf_token(&state, cookie).await)
+            .body(Body::from(json.to_string()))
+            .unwrap();
+        let response = call(state, request).await;
+        let status = response.status();
+        (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+    }
+
+    #[test]
+    fn test_user_update_check() {
+        assert_eq!(user_update_check(&serde_json::json!({"bio": "hello"})).unwrap(),
+                   UserUpdate { bio: Some("hello".to_string()), ..UserUpdate::default() });
+        assert_ok!(user_update_check(&serde_json::json!({"email": "someone@example.com", "last_online": "2024-03-01T00:00:00+00:00"})));
+        assert_err!(user_update_check(&serde_json::json!({})));
+        assert_err!(user_update_check(&serde_json::json!({"email": "not an email"})));
+        assert_err!(user_update_check(&serde_json::json!({"email": "two@@example.com"})));
+        assert_err!(user_update_check(&serde_json::json!({"bio": 7})));
+        assert_err!(user_update_check(&serde_json::json!({"last_online": "yesterday"})));
+        assert_err!(user_update_check(&serde_json::json!({"bio": "a".repeat(501)})));
+        assert_ok!(user_update_check(&serde_json::json!({"website": "https://example.com/about"})));
+        assert_ok!(user_update_check(&serde_json::json!({"website": "http://example.com"})));
+        assert_err!(user_update_check(&serde_json::json!({"website": "example.com"})));
+        assert_err!(user_update_check(&serde_json::json!({"website": "javascript:alert(1)"})));
+        assert_err!(user_update_check(&serde_json::json!({"website": "https://example.com/has space"})));
+        assert_err!(user_update_check(&serde_json::json!({"website": format!("https://{}", "a".repeat(200))})));
+    }
+
+    #[tokio::test]
+    async fn test_user_emails() {
+        let state = test_state().await;
+        let sign_up = |username: &'static str, email: Value| {
+            let state = state.clone();
+            async move {
+                let (status, body) = post_json(state, "/api/users", serde_json::json!({"username": username, "password": "correct horse", "email": email})).await;
+                (status, serde_json::from_slice::<Value>(&body).unwrap_or_default())
+            }
+        };
+        assert_eq!(sign_up("mailed_user", "mailed@example.com".into()).await.0, StatusCode::CREATED);
+        let user = select_by_email("Mailed@Example.com", &state).await.unwrap().unwrap();
+        assert_eq!((user.username.as_str(), user.email.as_deref()), ("mailed_user", Some("mailed@example.com")));
+        assert!(select_by_email("nobody@example.com", &state).await.unwrap().is_none());
+
+        // addresses are unique whatever their case
+        let (status, body) = sign_up("copycat_user", "MAILED@example.com".into()).await;
+        assert_eq!((status, &body["error"]), (StatusCode::CONFLICT, &Value::from("Email already in use")));
+        assert_eq!(sign_up("typo_user", "not an email".into()).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(sign_up("numeric_user", 5.into()).await.0, StatusCode::BAD_REQUEST);
+
+        let cookie = session_cookie(&state, "patching_user", Role::User).await;
+        let (status, body) = patch_json(state.clone(), "/api/users/patching_user", &cookie, serde_json::json!({"email": "mailed@EXAMPLE.com"})).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["error"], "Email already in use");
+        let (status, _) = patch_json(state.clone(), "/api/users/patching_user", &cookie, serde_json::json!({"email": "patching@example.com"})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_patch_user() {
+        let state = test_state().await;
+        let own = session_cookie(&state, "patch_user", Role::User).await;
+        let other = session_cookie(&state, "other_user", Role::User).await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+
+        let (status, body) = patch_json(state.clone(), "/api/users/patch_user", &own, serde_json::json!({"bio": "hello there"})).await;
+        assert_eq!(status, StatusCode::OK);
+        let user: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(user["bio"], "hello there");
+        assert_eq!(user["email"], Value::Null);
+
+        // fields left out of the body keep their stored value
+        let (status, body) = patch_json(state.clone(), "/api/users/patch_user", &admin, serde_json::json!({"email": "patch@example.com"})).await;
+        assert_eq!(status, StatusCode::OK);
+        let user: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!((&user["bio"], &user["email"]), (&serde_json::json!("hello there"), &serde_json::json!("patch@example.com")));
+
+        let (status, _) = patch_json(state.clone(), "/api/users/patch_user", &other, serde_json::json!({"bio": "hijacked"})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = patch_json(state.clone(), "/api/users/patch_user", &own, serde_json::json!({"email": "nope"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = patch_json(state.clone(), "/api/users/nobody_here", &admin, serde_json::json!({"bio": "ghost"})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = patch_json(state.clone(), "/api/users/patch_user", &own, serde_json::json!({"website": "not a url"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // an unset bio comes back as null rather than missing or empty
+        let (status, body) = patch_json(state.clone(), "/api/users/other_user", &other, serde_json::json!({"website": "https://example.com"})).await;
+        assert_eq!(status, StatusCode::OK);
+        let user: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!((&user["bio"], &user["website"]), (&Value::Null, &serde_json::json!("https://example.com")));
+        let (_, page) = get_request(state.clone(), "/user/other_user").await;
+        assert!(String::from_utf8(page).unwrap().contains(r#"<a href="https:&#x2F;&#x2F;example.com">"#));
+        let stored = select_by_username("patch_user", &State(state)).await.unwrap().unwrap();
+        assert_eq!(stored.bio.as_deref(), Some("hello there"));
+    }
+
+    #[tokio::test]
+    async fn test_search_users() {
+        let state = test_state().await;
+        for name in ["Alice_Smith", "alice_jones", "bob_builder", "alicexsmith"] {
+            insert_user(&User::new(name.to_string(), Role::User), &State(state.clone())).await.unwrap();
+        }
+        let search = |query: &str| {
+            let state = state.clone();
+            let uri = format!("/api/users/search?q={query}");
+            async move {
+                let (status, body) = get_request(state, &uri).await;
+                let names = serde_json::from_slice::<Vec<Value>>(&body).ok()
+                    .map(|users| users.iter().map(|user| user["username"].as_str().unwrap().to_string()).collect::<Vec<_>>());
+                (status, names)
+            }
+        };
+        assert_eq!(search("zz_nobody").await, (StatusCode::OK, Some(vec![])));
+        assert_eq!(search("build").await, (StatusCode::OK, Some(vec!["bob_builder".to_string()])));
+        // case-insensitive, and '_' only matches a literal underscore
+        assert_eq!(search("ALICE_").await, (StatusCode::OK, Some(vec!["Alice_Smith".to_string(), "alice_jones".to_string()])));
+        assert_eq!(search("a").await.0, StatusCode::BAD_REQUEST);
+        let (status, _) = get_request(state.clone(), "/api/users/search").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_health_ok() {
+        let (status, body) = get_request(test_state().await, "/health").await;
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!((status, body), (StatusCode::OK, serde_json::json!({"status": "ok", "read": true, "write": true})));
+    }
+
+    #[tokio::test]
+    async fn test_health_unavailable_with_bad_database_url() {
+        let mut state = test_app_state().await;
+        // the parent directory doesn't exist, so every connection attempt fails
+        state.write_pool = sqlite::SqlitePool::connect_lazy_with(SqliteConnectOptions::new()
+            .filename("/nonexistent/directory/database.db"));
+        let (status, body) = get_request(Arc::new(state), "/health").await;
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!((status, body), (StatusCode::SERVICE_UNAVAILABLE,
+                                    serde_json::json!({"status": "unavailable", "read": true, "write": false})));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let router = app(test_state().await);
+        let request = |uri: &str| Request::get(uri)
+            .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .body(Body::empty())
+            .unwrap();
+        let mut statuses = Vec::new();
+        for _ in 0..15 {
+            statuses.push(router.clone().oneshot(request("/api/users")).await.unwrap().status());
+        }
+        assert!(statuses[..10].iter().all(|status| *status == StatusCode::OK));
+        assert!(statuses[10..].iter().all(|status| *status == StatusCode::TOO_MANY_REQUESTS));
+
+        let response = router.clone().oneshot(request("/api/users")).await.unwrap();
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({"error": "rate limit exceeded", "code": 429}));
+        // health checks are exempt
+        for _ in 0..15 {
+            assert_eq!(router.clone().oneshot(request("/health")).await.unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_responses_carry_request_id() {
+        let request = Request::get("/health").body(Body::empty()).unwrap();
+        let response = call(test_state().await, request).await;
+        let request_id = response.headers()["x-request-id"].to_str().unwrap();
+        assert_eq!(request_id.len(), 36);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_credentials_stay_out_of_logs() {
+        let state = test_state().await;
+        let cookie = session_cookie(&state, "logged_user", Role::User).await;
+        let authorization = bearer(&state, "token_user", Role::User).await;
+        let session_id = cookie.split_once('=').unwrap().1;
+        let token = authorization.strip_prefix("Bearer ").unwrap();
+        let request = Request::get("/api/session")
+            .header(COOKIE, &cookie)
+            .header(AUTHORIZATION, &authorization)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(call(state, request).await.status(), StatusCode::OK);
+        // the request was traced with its headers, just not the credentials among them
+        assert!(logs_contain("/api/session"));
+        assert!(!logs_contain(session_id));
+        assert!(!logs_contain(token));
+        assert!(logs_contain(r#""cookie": Sensitive"#));
+    }
+
+    #[tokio::test]
+    async fn test_hsts_only_with_tls() {
+        let request = || Request::get("/health").body(Body::empty()).unwrap();
+        let response = call(test_state().await, request()).await;
+        assert!(!response.headers().contains_key(STRICT_TRANSPORT_SECURITY));
+
+        let tls = RustlsConfig::from_pem(include_bytes!("testdata/tls_cert.pem").to_vec(), include_bytes!("testdata/tls_key.pem").to_vec())
+            .await.unwrap();
+        let state = Arc::new(AppState { tls: Some(tls), ..test_app_state().await });
+        let response = call(state, request()).await;
+        assert_eq!(response.headers()[STRICT_TRANSPORT_SECURITY], HSTS);
+    }
+
+    #[tokio::test]
+    async fn test_security_headers() {
+        let response = call(test_state().await, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[REFERRER_POLICY], "strict-origin-when-cross-origin");
+        assert_eq!(headers[CONTENT_SECURITY_POLICY], DEFAULT_CONTENT_SECURITY_POLICY);
+        assert_eq!(headers["permissions-policy"], "geolocation=()");
+
+        let security_headers = SecurityHeadersConfig { content_security_policy: HeaderValue::from_static("default-src 'none'") };
+        let state = Arc::new(AppState { security_headers, ..test_app_state().await });
+        let response = call(state, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.headers()[CONTENT_SECURITY_POLICY], "default-src 'none'");
+    }
+
+    #[tokio::test]
+    async fn test_error_bodies_are_json() {
+        let state = test_state().await;
+        let error_body = |response: Response| async move {
+            let (parts, body) = response.into_parts();
+            assert_eq!(parts.headers[CONTENT_TYPE], "application/json");
+            (parts, serde_json::from_slice::<Value>(&to_bytes(body, usize::MAX).await.unwrap()).unwrap())
+        };
+
+        let request = Request::post("/api/users").header(CONTENT_TYPE, "application/json").body(Body::from("{not json")).unwrap();
+        let (parts, body) = error_body(call(state.clone(), request).await).await;
+        assert_eq!(parts.status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, serde_json::json!({"error": "Invalid JSON syntax.", "code": 400}));
+
+        // axum's own rejections and the router's empty responses get the same envelope
+        let (parts, body) = error_body(call(state.clone(), Request::get("/api/posts/not_a_number").body(Body::empty()).unwrap()).await).await;
+        assert_eq!((parts.status, &body["code"]), (StatusCode::BAD_REQUEST, &Value::from(400)));
+        assert!(body["error"].as_str().unwrap().contains("not_a_number"));
+        let (parts, body) = error_body(call(state.clone(), Request::put("/api/users").body(Body::empty()).unwrap()).await).await;
+        assert_eq!((parts.status, body), (StatusCode::METHOD_NOT_ALLOWED, serde_json::json!({"error": "Method Not Allowed", "code": 405})));
+        assert!(parts.headers.contains_key("allow"));
+
+        // pages for browsers stay HTML
+        let response = call(state, Request::get("/user/missing_user").body(Body::empty()).unwrap()).await;
+        assert_eq!((response.status(), &response.headers()[CONTENT_TYPE]), (StatusCode::NOT_FOUND, &HeaderValue::from_static("text/html; charset=utf-8")));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_bodies_are_rejected() {
+        let state = test_state().await;
+        // a valid sign-up padded out with whitespace, so only its size is wrong
+        let padded = |size: usize| {
+            let json = serde_json::json!({"username": "padded_user", "password": "correct horse"}).to_string();
+            format!("{json}{}", " ".repeat(size - json.len()))
+        };
+        let sign_up = |body: String, content_length: bool| {
+            let mut request = Request::post("/api/users").header(CONTENT_TYPE, "application/json");
+            if content_length {
+                request = request.header(CONTENT_LENGTH, body.len());
+            }
+            call(state.clone(), request.body(Body::from(body)).unwrap())
+        };
+
+        // refused up front by its Content-Length, and while being read without one
+        for content_length in [true, false] {
+            let response = sign_up(padded(2 * 1024 * 1024), content_length).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), serde_json::json!({"error": "request body too large", "code": 413}));
+        }
+        assert_eq!(sign_up(padded(DEFAULT_MAX_REQUEST_BODY_BYTES), true).await.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_is_redirected() {
+        let state = test_state().await;
+        for (path, location) in [("/users/", "/users"), ("//users", "/users"), ("/posts/?page=2", "/posts?page=2"), ("//", "/")] {
+            let response = call(state.clone(), Request::get(path).body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "{path}");
+            assert_eq!(response.headers()[LOCATION], location, "{path}");
+        }
+        assert_eq!(get_request(state.clone(), "/users").await.0, StatusCode::OK);
+        assert_eq!(get_request(state, "/").await.0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,123 @@
+// OpenAPI 3 description of the JSON API, generated from the handlers' #[utoipa::path] annotations,
+// plus a Swagger UI to browse it with. Neither needs state, so both are served straight from here.
+use super::{api_tokens, audit, bookmarks, comments, error::{AppError, ErrorBody}, follows, impersonation, invites, jwt, maintenance, password_reset, posts, responses::json_response, series, session::CurrentUser, totp, User};
+use anyhow::anyhow;
+use axum::{
+    extract::Path,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use std::sync::{Arc, LazyLock};
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::Config;
+
+pub(super) const OPENAPI_PATH: &str = "/api/openapi.json";
+// Swagger UI links its assets relatively, so it is served from a file under /api/docs/ rather than
+// from /api/docs/ itself, which the trailing slash normalization would turn back into /api/docs.
+const SWAGGER_UI_INDEX: &str = "/api/docs/index.html";
+
+static SWAGGER_CONFIG: LazyLock<Arc<Config<'static>>> = LazyLock::new(|| Arc::new(Config::from(OPENAPI_PATH)));
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Personal Site API", description = "JSON API behind the site's users and posts."),
+    paths(
+        super::get_users, super::post_user, super::search_users, super::patch_user, super::delete_user,
+        super::get_deleted_users, super::restore_user, super::set_role, audit::get_audit_log, maintenance::set_maintenance, super::login, super::logout, super::get_session, super::health, super::get_sbom,
+        impersonation::impersonate, impersonation::active_impersonations, invites::create_invites, invites::list_invites,
+        follows::follow, follows::unfollow, follows::followers, follows::following, follows::feed,
+        bookmarks::bookmark, bookmarks::remove_bookmark, bookmarks::bookmarks,
+        api_tokens::create_token, api_tokens::list_tokens, api_tokens::revoke_token,
+        jwt::issue_token, password_reset::forgot_password, password_reset::reset_password, totp::enable, totp::confirm, totp::disable,
+        posts::list_posts, posts::create_post, posts::get_post, posts::get_post_by_slug, posts::patch_post, posts::delete_post,
+        posts::publish_post, posts::list_drafts, posts::popular_posts, posts::search_posts,
+        series::list_series, series::create_series, series::get_series, series::patch_series, series::delete_series, series::add_post, series::remove_post,
+        comments::list_comments, comments::create_comment, comments::delete_comment, comments::flag_comment
+    ),
+    components(schemas(User, CurrentUser, posts::Post, posts::RenderedPost, posts::ViewedPost, posts::DetailedPost, posts::PostSearchResult, series::Series, series::SeriesWithPosts, series::SeriesPosition, audit::AuditEntry, api_tokens::ApiToken, impersonation::Impersonation, invites::Invite, comments::Comment, comments::CommentThread, totp::TotpSetup, ErrorBody)),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "users", description = "Accounts and profiles"),
+        (name = "auth", description = "Sessions and bearer tokens"),
+        (name = "posts", description = "Blog posts"),
+        (name = "comments", description = "Comments on posts")
+    )
+)]
+pub(super) struct ApiDoc;
+
+/// Registers the three ways of authenticating that the paths' `security` lists refer to.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(
+            HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()));
+        components.add_security_scheme("session", SecurityScheme::ApiKey(
+            ApiKey::Cookie(ApiKeyValue::new(super::session::SESSION_COOKIE))));
+        components.add_security_scheme("api_token", SecurityScheme::ApiKey(
+            ApiKey::Header(ApiKeyValue::with_description("Authorization", "`Token <token>`, with a token from POST /api/users/{username}/tokens"))));
+    }
+}
+
+/// GET request handler returning the OpenAPI document.
+pub(super) async fn openapi_json() -> Response {
+    json_response(StatusCode::OK, ApiDoc::openapi())
+}
+
+/// GET request handler sending /api/docs on to the Swagger UI page.
+pub(super) async fn docs() -> Redirect {
+    Redirect::permanent(SWAGGER_UI_INDEX)
+}
+
+/// GET request handler serving the bundled Swagger UI files, pointed at the OpenAPI document.
+pub(super) async fn docs_file(Path(file): Path<String>) -> Response {
+    match utoipa_swagger_ui::serve(&file, SWAGGER_CONFIG.clone()) {
+        Ok(Some(file)) => ([(CONTENT_TYPE, file.content_type)], file.bytes.into_owned()).into_response(),
+        Ok(None) => AppError::NotFound(format!("No such file '{file}'.")).into_response(),
+        Err(e) => AppError::Internal(anyhow!("Failed to serve Swagger UI file: {e}")).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::{call, get_request, test_state};
+    use axum::{body::Body, extract::Request, http::header::LOCATION};
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn test_openapi_document() {
+        let (status, body) = get_request(test_state().await, OPENAPI_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        let spec: Value = serde_json::from_slice(&body).unwrap();
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        for (path, method) in [("/api/users", "get"), ("/api/users", "post"), ("/api/users/{username}", "patch"),
+                               ("/api/posts", "get"), ("/api/posts/{id}", "delete"), ("/api/auth/token", "post")] {
+            assert!(spec["paths"][path][method].is_object(), "{method} {path}");
+        }
+        for schema in ["User", "Post", "RenderedPost", "ErrorBody"] {
+            assert!(spec["components"]["schemas"][schema].is_object(), "{schema}");
+        }
+        assert!(spec["components"]["schemas"]["User"]["properties"].get("password_hash").is_none());
+        assert_eq!(spec["paths"]["/api/posts"]["post"]["security"], serde_json::json!([{"bearer": []}]));
+    }
+
+    #[tokio::test]
+    async fn test_swagger_ui() {
+        let state = test_state().await;
+        let response = call(state.clone(), Request::get("/api/docs").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], SWAGGER_UI_INDEX);
+
+        let (status, body) = get_request(state.clone(), SWAGGER_UI_INDEX).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(String::from_utf8(body).unwrap().contains("swagger-initializer.js"));
+        let (_, initializer) = get_request(state.clone(), "/api/docs/swagger-initializer.js").await;
+        assert!(String::from_utf8(initializer).unwrap().contains(OPENAPI_PATH));
+        assert_eq!(get_request(state, "/api/docs/no_such_file.js").await.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,40 @@
+// The envelope page numbered JSON listings come in, so clients know how far the listing goes.
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// One page of a listing, along with where it sits in the whole.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub(super) struct Page<T> {
+    pub(super) items: Vec<T>,
+    /// 1-based number of this page.
+    pub(super) page: u32,
+    pub(super) per_page: u32,
+    /// How many items there are across all pages.
+    pub(super) total: u64,
+    /// How many pages `total` items fill. 0 when there are no items at all.
+    pub(super) total_pages: u64
+}
+
+impl<T> Page<T> {
+    /// Wraps the items on `page` of a listing holding `total` items, `per_page` to a page.
+    pub(super) fn new(items: Vec<T>, page: u32, per_page: u32, total: i64) -> Self {
+        let total = total.max(0) as u64;
+        Page { items, page, per_page, total, total_pages: total.div_ceil(u64::from(per_page.max(1))) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_total_pages() {
+        for (total, per_page, total_pages) in [(0, 10, 0), (1, 10, 1), (10, 10, 1), (11, 10, 2), (7, 3, 3), (100, 1, 100)] {
+            let page = Page::<()>::new(Vec::new(), 1, per_page, total);
+            assert_eq!((page.total, page.total_pages), (total as u64, total_pages), "{total} / {per_page}");
+            // every item fits in total_pages pages, and one page fewer wouldn't be enough
+            assert!(page.total_pages * u64::from(per_page) >= page.total);
+            assert!(page.total_pages.saturating_sub(1) * u64::from(per_page) < page.total.max(1));
+        }
+    }
+}
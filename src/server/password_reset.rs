@@ -0,0 +1,209 @@
+// Password resets for users who can't log in. Asking for one with an account's email address hands out a
+// single use token, good for an hour, that can be traded for a new password. There is no mail server yet,
+// so the reset token is printed to stdout for whoever runs the site to pass on.
+use super::{acquire_with_timeout, audit::{append_audit, AuditAction}, error::{AppError, ErrorBody}, forget_cached_user, hash_password,
+            jobs::{Job, JobFuture}, password_check, responses::plain_response, select_by_email, session::to_hex, AppState};
+use anyhow::Error;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{TimeDelta, Utc};
+use serde_json::Value;
+use sqlx::Connection;
+use std::sync::Arc;
+
+// how long a reset token can be used for after it was asked for
+const RESET_TOKEN_LIFETIME: TimeDelta = TimeDelta::hours(1);
+const RESET_TOKEN_BYTES: usize = 64;
+
+/// POST request handler starting a password reset for the account with the given `email`. Responds the same
+/// whether or not any account has it, so it can't be used to find out which addresses are registered.
+#[utoipa::path(post, path = "/api/auth/forgot-password", tag = "auth",
+    request_body(content = Object, description = "`email`"),
+    responses(
+        (status = 202, description = "A reset token is on its way if an account has that email", body = String),
+        (status = 400, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn forgot_password(State(state): State<Arc<AppState>>, result: Result<Json<Value>, JsonRejection>)
+                                    -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let email = json_map.get("email").and_then(Value::as_str)
+        .ok_or(AppError::BadRequest("JSON payload structure invalid.".to_string()))?;
+    if let Some(user) = select_by_email(email, &state).await? {
+        let token = new_reset_token();
+        insert_reset_token(&token, &user.username, &state).await?;
+        tracing::info!(username = user.username, "Issued password reset token");
+        state.jobs.dispatch(ResetTokenJob { username: user.username, token }).await;
+    }
+    Ok(plain_response(StatusCode::ACCEPTED, "If an account has that email, a reset token has been sent to it."))
+}
+
+/// Background job handing a reset token to the user it was issued for. There is no mail server to send it with
+/// yet, so the token is printed to stdout on purpose, along with how to redeem it, for whoever runs the site to
+/// pass on. There is no reset page either, so it's redeemed through the API. It stays out of the tracing logs,
+/// which are more widely shipped and kept than a console, since the token grants the account.
+struct ResetTokenJob {
+    username: String,
+    token: String
+}
+
+impl Job for ResetTokenJob {
+    fn execute(&self, state: Arc<AppState>) -> JobFuture<'_> {
+        Box::pin(async move {
+            println!("Password reset token for {}: {} (POST it as {{\"token\", \"new_password\"}} to {}api/auth/reset-password)",
+                     self.username, self.token, state.base_url);
+        })
+    }
+}
+
+/// POST request handler setting a new password with a reset token. Every session of the account is ended,
+/// so whoever may have been using the old password is logged out.
+#[utoipa::path(post, path = "/api/auth/reset-password", tag = "auth",
+    request_body(content = Object, description = "`token` and `new_password`"),
+    responses(
+        (status = 200, description = "Password changed", body = String),
+        (status = 400, description = "Malformed payload, or an unknown, expired or already used token", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn reset_password(State(state): State<Arc<AppState>>, result: Result<Json<Value>, JsonRejection>)
+                                   -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let token = json_map.get("token").and_then(Value::as_str)
+        .ok_or(AppError::BadRequest("JSON payload structure invalid.".to_string()))?;
+    let password_hash = hash_password(password_check(json_map.get("new_password"))?).await?;
+    let Some(username) = redeem_reset_token(token, &password_hash, &state).await? else {
+        return Err(AppError::BadRequest("Invalid or expired reset token.".to_string()));
+    };
+    forget_cached_user(&username, &state);
+    tracing::info!(username, "Reset password");
+    Ok(plain_response(StatusCode::OK, "Password reset."))
+}
+
+/// RESET_TOKEN_BYTES random bytes from the OS, hex encoded so the token can go in a URL as is.
+fn new_reset_token() -> String {
+    let mut bytes = [0u8; RESET_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Stores a reset token for user `username` that expires RESET_TOKEN_LIFETIME from now.
+async fn insert_reset_token(token: &str, username: &str, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let expires_at = (Utc::now() + RESET_TOKEN_LIFETIME).to_rfc3339();
+    sqlx::query!("INSERT INTO password_reset_table (token, user_id, expires_at) SELECT $1, id, $2 FROM user_table WHERE username = $3",
+        token,
+        expires_at,
+        username)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+/// Uses up an unused, unexpired reset token to set its user's password hash, ends their sessions and audits it.
+/// Returns the user's name, or None if the token can't be used.
+async fn redeem_reset_token(token: &str, password_hash: &str, state: &AppState) -> Result<Option<String>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    let now = Utc::now().to_rfc3339();
+    // checking and using up the token happen in one statement so it can't be redeemed twice at once
+    let Some(user_id) = sqlx::query_scalar!("UPDATE password_reset_table SET used = 1 WHERE token = $1 AND used = 0 AND expires_at > $2
+        RETURNING user_id",
+        token,
+        now)
+        .fetch_optional(&mut *transaction).await? else {
+        return Ok(None);
+    };
+    let Some(username) = sqlx::query_scalar!("UPDATE user_table SET password_hash = $1 WHERE id = $2 AND deleted_at IS NULL RETURNING username",
+        password_hash,
+        user_id)
+        .fetch_optional(&mut *transaction).await? else {
+        return Ok(None);
+    };
+    sqlx::query!("DELETE FROM session_table WHERE user_id = $1", user_id)
+        .execute(&mut *transaction).await?;
+    append_audit(&mut transaction, user_id, AuditAction::Updated, None, Some("password reset")).await?;
+    transaction.commit().await?;
+    Ok(Some(username))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{tests::{post_json, session_cookie, test_state}, update_user_db, Role, UserUpdate};
+
+    async fn reset(state: Arc<AppState>, token: &str, new_password: &str) -> StatusCode {
+        post_json(state, "/api/auth/reset-password", serde_json::json!({"token": token, "new_password": new_password})).await.0
+    }
+
+    async fn login(state: Arc<AppState>, password: &str) -> StatusCode {
+        post_json(state, "/api/login", serde_json::json!({"username": "forgetful_user", "password": password})).await.0
+    }
+
+    /// Signs up forgetful_user with an email address and asks for a reset, returning the token issued.
+    async fn forgotten(state: &Arc<AppState>) -> String {
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "forgetful_user", "password": "correct horse"})).await;
+        let update = UserUpdate { email: Some("forgetful@example.com".to_string()), ..UserUpdate::default() };
+        update_user_db("forgetful_user", &update, None, &State(state.clone())).await.unwrap();
+        let (status, _) = post_json(state.clone(), "/api/auth/forgot-password", serde_json::json!({"email": "Forgetful@Example.com"})).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        sqlx::query_scalar!(r#"SELECT token AS "token!" FROM password_reset_table"#).fetch_one(&state.read_pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_password_reset() {
+        let state = test_state().await;
+        let token = forgotten(&state).await;
+        assert_eq!(token.len(), RESET_TOKEN_BYTES * 2);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+
+        assert_eq!(reset(state.clone(), &token, "short").await, StatusCode::BAD_REQUEST);
+        assert_eq!(reset(state.clone(), &token, "battery staple").await, StatusCode::OK);
+        assert_eq!(login(state.clone(), "correct horse").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(login(state.clone(), "battery staple").await, StatusCode::OK);
+        // a token only works once
+        assert_eq!(reset(state.clone(), &token, "another password").await, StatusCode::BAD_REQUEST);
+        assert_eq!(login(state.clone(), "battery staple").await, StatusCode::OK);
+        assert_eq!(reset(state.clone(), "not a token", "another password").await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_expired_reset_token_is_rejected() {
+        let state = test_state().await;
+        let token = forgotten(&state).await;
+        let expired = (Utc::now() - TimeDelta::seconds(1)).to_rfc3339();
+        sqlx::query!("UPDATE password_reset_table SET expires_at = $1", expired).execute(&state.write_pool).await.unwrap();
+        assert_eq!(reset(state.clone(), &token, "battery staple").await, StatusCode::BAD_REQUEST);
+        assert_eq!(login(state, "correct horse").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_email_looks_the_same() {
+        let state = test_state().await;
+        session_cookie(&state, "emailless_user", Role::User).await;
+        let (status, body) = post_json(state.clone(), "/api/auth/forgot-password", serde_json::json!({"email": "nobody@example.com"})).await;
+        let (known_status, known_body) = {
+            forgotten(&state).await;
+            post_json(state.clone(), "/api/auth/forgot-password", serde_json::json!({"email": "forgetful@example.com"})).await
+        };
+        assert_eq!((status, body), (known_status, known_body));
+        assert_eq!(post_json(state, "/api/auth/forgot-password", serde_json::json!({})).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_reset_ends_sessions() {
+        let state = test_state().await;
+        let token = forgotten(&state).await;
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = 'forgetful_user'"#)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        crate::server::session::create_session(id, &state).await.unwrap();
+        assert_eq!(reset(state.clone(), &token, "battery staple").await, StatusCode::OK);
+        let sessions = sqlx::query_scalar!("SELECT COUNT(*) FROM session_table WHERE user_id = $1", id).fetch_one(&state.read_pool).await.unwrap();
+        assert_eq!(sessions, 0);
+    }
+}
@@ -0,0 +1,1007 @@
+// Blog posts. Anyone can read them, bearer token holders can write them, and only a post's author
+// or an admin may change or remove it.
+use super::{acquire_with_timeout, conditional::conditional_response, error::{AppError, ErrorBody}, error_page, i18n::Locale, jwt::AuthBearer, markdown::render_markdown, page_context, page_offset, page_param, pagination::Page, session::CurrentUser,
+            responses::{html_response, json_response}, series::{select_series_position, SeriesPosition}, templates, text_analysis::estimate_reading_time, AppState, Role, MAX_PER_PAGE};
+use anyhow::Error;
+use axum::{
+    extract::{rejection::{JsonRejection, QueryRejection}, Path, Query, State},
+    http::{header::LOCATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::Utc;
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use sqlx::Connection;
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use utoipa::{openapi::{schema::Schema, RefOr}, IntoParams, PartialSchema, ToSchema};
+
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_BODY_CHARS: usize = 10_000;
+const MAX_TAG_CHARS: usize = 32;
+const DEFAULT_POPULAR_LIMIT: u32 = 10;
+const MAX_SLUG_CHARS: usize = 100;
+const MAX_SEARCH_CHARS: usize = 200;
+// slug of a post whose title has nothing a slug can be made from
+const FALLBACK_SLUG: &str = "post";
+
+/// A row of post_table. Serialized as a `PostJson`, which adds what is worked out from the body.
+#[derive(Debug, sqlx::FromRow)]
+pub(super) struct Post {
+    pub(super) id: i64,
+    pub(super) title: Box<str>,
+    pub(super) body: Box<str>,
+    pub(super) created: Box<str>,
+    pub(super) author_id: i64,
+    // None while the post is a draft, which keeps it out of every public listing
+    pub(super) published_at: Option<String>,
+    // unique, URL safe name of the post, used by /posts/{slug}
+    pub(super) slug: String
+}
+
+/// How a post is serialized: its row, plus statistics computed from the body on the way out.
+#[derive(Serialize, ToSchema)]
+#[schema(as = Post)]
+struct PostJson<'a> {
+    id: i64,
+    title: &'a str,
+    body: &'a str,
+    created: &'a str,
+    author_id: i64,
+    published_at: Option<&'a str>,
+    slug: &'a str,
+    /// Words in the body, leaving out Markdown syntax.
+    word_count: usize,
+    /// Minutes the body takes to read at 200 words a minute, rounded up and at least 1.
+    reading_time_minutes: usize
+}
+
+impl Serialize for Post {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (word_count, reading_time_minutes) = estimate_reading_time(&self.body);
+        PostJson {
+            id: self.id,
+            title: &self.title,
+            body: &self.body,
+            created: &self.created,
+            author_id: self.author_id,
+            published_at: self.published_at.as_deref(),
+            slug: &self.slug,
+            word_count,
+            reading_time_minutes
+        }.serialize(serializer)
+    }
+}
+
+// the schema is PostJson's, so the OpenAPI document describes what is actually sent
+impl PartialSchema for Post {
+    fn schema() -> RefOr<Schema> {
+        PostJson::schema()
+    }
+}
+
+impl ToSchema for Post {
+    fn name() -> Cow<'static, str> {
+        PostJson::name()
+    }
+}
+
+/// A post along with its body rendered from Markdown to sanitised HTML.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct RenderedPost {
+    #[serde(flatten)]
+    pub(super) post: Post,
+    pub(super) rendered_body: String
+}
+
+/// A rendered post along with how many times it has been viewed.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct ViewedPost {
+    #[serde(flatten)]
+    pub(super) post: RenderedPost,
+    pub(super) view_count: i64
+}
+
+/// A viewed post along with where it sits in its series, as returned for a single post.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct DetailedPost {
+    #[serde(flatten)]
+    pub(super) post: ViewedPost,
+    /// None unless the post is part of a series.
+    pub(super) series: Option<SeriesPosition>
+}
+
+/// A post matching a full-text search, with the matches in its body marked.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct PostSearchResult {
+    #[serde(flatten)]
+    pub(super) post: Post,
+    /// The body with every match wrapped in `**`, so it stays Markdown like the body itself.
+    pub(super) highlight: String
+}
+
+/// Query string of GET /api/posts/search.
+#[derive(Debug, Deserialize, IntoParams)]
+pub(super) struct PostSearchParams {
+    /// Words to look for in post titles and bodies. Posts must contain all of them.
+    q: String,
+    page: Option<u32>
+}
+
+/// Query string of GET /api/posts/popular.
+#[derive(Debug, Deserialize, IntoParams)]
+pub(super) struct PopularParams {
+    /// How many posts to return, 10 by default.
+    limit: Option<u32>
+}
+
+impl From<Post> for RenderedPost {
+    fn from(post: Post) -> Self {
+        let rendered_body = render_markdown(&post.body);
+        RenderedPost { post, rendered_body }
+    }
+}
+
+/// HTML page listing posts, newest first.
+#[tracing::instrument(skip(state, current_user, locale))]
+pub(super) async fn posts_route(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, locale: Locale,
+                                Query(params): Query<HashMap<String, String>>) -> Response {
+    let page_no = page_param(&params);
+    let Ok(posts) = get_posts_by_pagination(&state, page_no, None).await else {
+        return error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display posts.");
+    };
+    let mut context = page_context(&state, current_user, locale).await;
+    context.insert("page_no", &page_no);
+    context.insert("total_pages", &posts.total_pages);
+    context.insert("posts", &posts.items.into_iter().map(RenderedPost::from).collect::<Vec<_>>());
+    match templates().render("posts.html", &context) {
+        Ok(page) => html_response(StatusCode::OK, page),
+        Err(_e) => error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display page.")
+    }
+}
+
+/// API endpoint returning a page of published posts, newest first. `?tag=name` keeps only posts with that tag.
+#[utoipa::path(get, path = "/api/posts", tag = "posts",
+    params(("page" = Option<u32>, Query), ("tag" = Option<String>, Query, description = "Only list posts with this tag")),
+    responses(
+        (status = 200, body = Page<Post>),
+        (status = 304, description = "The If-None-Match ETag still matches"),
+        (status = 400, description = "Invalid tag", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, headers))]
+pub(super) async fn list_posts(State(state): State<Arc<AppState>>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>)
+                               -> Result<impl IntoResponse, AppError> {
+    let tag = params.get("tag").map(|tag| tag_check(tag)).transpose()?;
+    conditional_response(&headers, &get_posts_by_pagination(&state, page_param(&params), tag.as_deref()).await?)
+}
+
+/// API endpoint returning a single published post, with its body also rendered as HTML in 'rendered_body'. Drafts are 404s.
+/// Every fetch counts as a view, and the response carries the count including it. A post in a series also says
+/// which posts come before and after it.
+#[utoipa::path(get, path = "/api/posts/{id}", tag = "posts", params(("id" = i64, Path)),
+    responses(
+        (status = 200, body = DetailedPost),
+        (status = 304, description = "The If-None-Match ETag still matches"),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, headers))]
+pub(super) async fn get_post(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    let post = view_post(id, &state).await?
+        .ok_or(AppError::NotFound(format!("Post {id} does not exist.")))?;
+    let series = select_series_position(id, &state).await?;
+    conditional_response(&headers, &DetailedPost { post, series })
+}
+
+/// API endpoint returning a single published post by its slug, like GET /api/posts/{id}. Counts as a view too.
+#[utoipa::path(get, path = "/api/posts/by-slug/{slug}", tag = "posts", params(("slug" = String, Path)),
+    responses(
+        (status = 200, body = DetailedPost),
+        (status = 304, description = "The If-None-Match ETag still matches"),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, headers))]
+pub(super) async fn get_post_by_slug(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(slug): Path<String>)
+                                     -> Result<impl IntoResponse, AppError> {
+    let not_found = || AppError::NotFound(format!("Post '{slug}' does not exist."));
+    let id = select_post_id_by_slug(&slug, true, &state).await?.ok_or_else(not_found)?;
+    let post = view_post(id, &state).await?.ok_or_else(not_found)?;
+    let series = select_series_position(id, &state).await?;
+    conditional_response(&headers, &DetailedPost { post, series })
+}
+
+/// HTML page showing a single published post by its slug. Counts as a view.
+#[tracing::instrument(skip(state, current_user, locale))]
+pub(super) async fn post_route(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, locale: Locale,
+                               Path(slug): Path<String>) -> Response {
+    let post = match select_post_id_by_slug(&slug, true, &state).await {
+        Ok(Some(id)) => view_post(id, &state).await,
+        Ok(None) => Ok(None),
+        Err(e) => Err(e)
+    };
+    let post = match post {
+        Ok(Some(post)) => post,
+        Ok(None) => return error_page(&state, StatusCode::NOT_FOUND, &format!("No post called '{slug}' exists.")),
+        Err(_e) => return error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display post.")
+    };
+    let mut context = page_context(&state, current_user, locale).await;
+    context.insert("post", &post);
+    match templates().render("post.html", &context) {
+        Ok(page) => html_response(StatusCode::OK, page),
+        Err(_e) => error_page(&state, StatusCode::INTERNAL_SERVER_ERROR, "Cannot display page.")
+    }
+}
+
+/// API endpoint returning the most viewed published posts, most viewed first.
+#[utoipa::path(get, path = "/api/posts/popular", tag = "posts", params(PopularParams),
+    responses(
+        (status = 200, body = Vec<ViewedPost>),
+        (status = 304, description = "The If-None-Match ETag still matches"),
+        (status = 400, description = "Invalid query", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, headers))]
+pub(super) async fn popular_posts(State(state): State<Arc<AppState>>, headers: HeaderMap, params: Result<Query<PopularParams>, QueryRejection>)
+                                  -> Result<impl IntoResponse, AppError> {
+    let Query(params) = params?;
+    let limit = params.limit.unwrap_or(DEFAULT_POPULAR_LIMIT).clamp(1, MAX_PER_PAGE);
+    conditional_response(&headers, &select_popular_posts(limit, &state).await?)
+}
+
+/// API endpoint returning a page of published posts containing every word of `q`, best matches first.
+#[utoipa::path(get, path = "/api/posts/search", tag = "posts", params(PostSearchParams),
+    responses(
+        (status = 200, body = Page<PostSearchResult>),
+        (status = 400, description = "Empty or overlong query", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state))]
+pub(super) async fn search_posts(State(state): State<Arc<AppState>>, params: Result<Query<PostSearchParams>, QueryRejection>)
+                                 -> Result<impl IntoResponse, AppError> {
+    let Query(params) = params?;
+    if params.q.chars().count() > MAX_SEARCH_CHARS {
+        return Err(AppError::BadRequest(format!("Search query can't be longer than {MAX_SEARCH_CHARS} characters.")));
+    }
+    let query = fts_query(&params.q).ok_or(AppError::BadRequest("Search query must contain a word.".to_string()))?;
+    Ok(json_response(StatusCode::OK, search_posts_db(&query, params.page.unwrap_or(1).max(1), &state).await?))
+}
+
+/// POST request handler creating a post authored by the caller. New posts are drafts until published.
+#[utoipa::path(post, path = "/api/posts", tag = "posts", security(("bearer" = [])),
+    params(("Idempotency-Key" = Option<String>, Header, description = "A UUID. Retries with the same key get the first response replayed")),
+    request_body(content = Object, description = "`title` and `body`, plus an optional list of `tags` and a `slug`. \
+        Without a slug one is made from the title."),
+    responses(
+        (status = 201, description = "Created, with the post's URL in Location", body = Post),
+        (status = 400, description = "Invalid fields, or the slug is taken", body = ErrorBody),
+        (status = 401, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn create_post(State(state): State<Arc<AppState>>, auth: AuthBearer,
+                                result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let Json(json_map) = result?;
+    let (Some(title), Some(body)) = post_fields_check(&json_map)? else {
+        return Err(AppError::BadRequest("Both 'title' and 'body' are required.".to_string()));
+    };
+    let tags = tags_check(&json_map)?;
+    let slug = json_map.get("slug").map(slug_check).transpose()?;
+    let slug_taken = match &slug {
+        Some(slug) => select_post_id_by_slug(slug, false, &state).await?.is_some(),
+        None => false
+    };
+    if slug_taken {
+        return Err(AppError::BadRequest("That slug is already taken.".to_string()));
+    }
+    let post = insert_post(&title, &body, slug.as_deref(), &tags, auth.user_id, &state).await?;
+    tracing::info!(post_id = post.id, author_id = auth.user_id, "Created post");
+    Ok(([(LOCATION, format!("/api/posts/{}", post.id))], json_response(StatusCode::CREATED, post)))
+}
+
+/// PATCH request handler changing a post's title and/or body. Author or admin only.
+#[utoipa::path(patch, path = "/api/posts/{id}", tag = "posts", security(("bearer" = [])), params(("id" = i64, Path)),
+    request_body(content = Object, description = "`title` and/or `body`"),
+    responses(
+        (status = 200, body = Post),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth, result))]
+pub(super) async fn patch_post(State(state): State<Arc<AppState>>, auth: AuthBearer,
+                               Path(id): Path<i64>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    authorize_post_change(id, &auth, &state).await?;
+    let Json(json_map) = result?;
+    let (title, body) = post_fields_check(&json_map)?;
+    if title.is_none() && body.is_none() {
+        return Err(AppError::BadRequest("No updatable fields supplied.".to_string()));
+    }
+    let post = update_post(id, title.as_deref(), body.as_deref(), &state).await?
+        .ok_or(AppError::NotFound(format!("Post {id} does not exist.")))?;
+    tracing::info!("Updated post");
+    Ok(json_response(StatusCode::OK, post))
+}
+
+/// DELETE request handler removing a post. Author or admin only.
+#[utoipa::path(delete, path = "/api/posts/{id}", tag = "posts", security(("bearer" = [])), params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth))]
+pub(super) async fn delete_post(State(state): State<Arc<AppState>>, auth: AuthBearer,
+                                Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    authorize_post_change(id, &auth, &state).await?;
+    if !delete_post_db(id, &state).await? {
+        return Err(AppError::NotFound(format!("Post {id} does not exist.")));
+    }
+    tracing::info!("Deleted post");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST request handler publishing a draft. Author or admin only. Publishing an already published post changes nothing.
+#[utoipa::path(post, path = "/api/posts/{id}/publish", tag = "posts", security(("bearer" = [])), params(("id" = i64, Path)),
+    responses(
+        (status = 200, body = Post),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth))]
+pub(super) async fn publish_post(State(state): State<Arc<AppState>>, auth: AuthBearer,
+                                 Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    authorize_post_change(id, &auth, &state).await?;
+    let post = publish_post_db(id, &state).await?
+        .ok_or(AppError::NotFound(format!("Post {id} does not exist.")))?;
+    tracing::info!("Published post");
+    Ok(json_response(StatusCode::OK, post))
+}
+
+/// API endpoint listing the caller's unpublished posts, newest first. Admins see every author's drafts.
+#[utoipa::path(get, path = "/api/admin/posts/drafts", tag = "posts", security(("bearer" = [])),
+    responses(
+        (status = 200, body = Vec<Post>),
+        (status = 401, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn list_drafts(State(state): State<Arc<AppState>>, auth: AuthBearer) -> Result<impl IntoResponse, AppError> {
+    let author_id = (auth.claims.role != Role::Admin).then_some(auth.user_id);
+    Ok(json_response(StatusCode::OK, select_drafts(author_id, &state).await?))
+}
+
+/// Checks the token holder may change post `id`: they must be its author or an admin.
+pub(super) async fn authorize_post_change(id: i64, auth: &AuthBearer, state: &AppState) -> Result<(), AppError> {
+    match select_post(id, state).await? {
+        None => Err(AppError::NotFound(format!("Post {id} does not exist."))),
+        Some(post) if post.author_id != auth.user_id && auth.claims.role != Role::Admin => Err(AppError::Forbidden),
+        Some(_) => Ok(())
+    }
+}
+
+/// Validates the optional `title` and `body` fields of a post payload.
+fn post_fields_check(json_map: &Value) -> Result<(Option<String>, Option<String>), AppError> {
+    let field = |name: &str, max_chars: usize| match json_map.get(name) {
+        None => Ok(None),
+        Some(Value::String(value)) if (1..=max_chars).contains(&value.trim().chars().count()) => Ok(Some(value.trim().to_string())),
+        Some(_) => Err(AppError::BadRequest(format!("'{name}' must be a string of 1 to {max_chars} characters."))),
+    };
+    Ok((field("title", MAX_TITLE_CHARS)?, field("body", MAX_BODY_CHARS)?))
+}
+
+/// Validates a tag name: 1 to MAX_TAG_CHARS letters, digits, '-' or '_'. Tags are case insensitive, so it's lowercased.
+fn tag_check(tag: &str) -> Result<String, AppError> {
+    if Regex::new(&format!("^[-_a-zA-Z0-9]{{1,{MAX_TAG_CHARS}}}$")).is_ok_and(|val| val.is_match(tag)) {
+        Ok(tag.to_ascii_lowercase())
+    } else {
+        Err(AppError::BadRequest(format!("Tags must be 1 to {MAX_TAG_CHARS} letters, digits, '-' or '_'.")))
+    }
+}
+
+/// Validates the optional `tags` list of a post payload, dropping duplicates.
+fn tags_check(json_map: &Value) -> Result<Vec<String>, AppError> {
+    let Some(tags) = json_map.get("tags") else {
+        return Ok(Vec::new());
+    };
+    let mut checked = Vec::new();
+    for tag in tags.as_array().ok_or(AppError::BadRequest("'tags' must be a list of strings.".to_string()))? {
+        let tag = tag_check(tag.as_str().ok_or(AppError::BadRequest("'tags' must be a list of strings.".to_string()))?)?;
+        if !checked.contains(&tag) {
+            checked.push(tag);
+        }
+    }
+    Ok(checked)
+}
+
+/// Validates a caller supplied slug: 1 to MAX_SLUG_CHARS lowercase letters and digits in hyphen separated words.
+pub(super) fn slug_check(slug: &Value) -> Result<String, AppError> {
+    match slug.as_str() {
+        Some(slug) if slug.len() <= MAX_SLUG_CHARS && Regex::new("^[a-z0-9]+(-[a-z0-9]+)*$").is_ok_and(|val| val.is_match(slug)) => Ok(slug.to_string()),
+        _ => Err(AppError::BadRequest(format!("'slug' must be 1 to {MAX_SLUG_CHARS} lowercase letters and digits, words separated by '-'.")))
+    }
+}
+
+/// Makes a slug out of a title: lowercased, spaces turned to hyphens and anything but ASCII letters and digits dropped.
+/// A title with nothing usable in it gets FALLBACK_SLUG.
+pub(super) fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if c.is_whitespace() || c == '-' {
+            // runs of separators collapse into one hyphen, and none lead
+            if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+    }
+    slug.truncate(MAX_SLUG_CHARS);
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() { FALLBACK_SLUG.to_string() } else { slug.to_string() }
+}
+
+/// `slug` if it isn't among `taken`, otherwise `slug` with the lowest free `-N` suffix, counting from 2.
+pub(super) fn disambiguate_slug(slug: &str, taken: &[String]) -> String {
+    if !taken.iter().any(|other| other == slug) {
+        return slug.to_string();
+    }
+    (2..).map(|n| format!("{slug}-{n}")).find(|candidate| !taken.contains(candidate)).unwrap_or_default()
+}
+
+/// Turns user input into an FTS5 query matching posts that contain every word of it. Each word is quoted,
+/// so FTS5 operators and syntax in the input are searched for literally instead of failing the query.
+/// None if there are no words.
+fn fts_query(input: &str) -> Option<String> {
+    let words: Vec<String> = input.split_whitespace().map(|word| format!("\"{}\"", word.replace('"', "\"\""))).collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+/// The n=state.per_page published posts on the given 1-based page matching FTS5 `query`, best matches first.
+async fn search_posts_db(query: &str, page: u32, state: &AppState) -> Result<Page<PostSearchResult>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut transaction = read_conn.begin().await?;
+    let offset = page_offset(page, state.per_page);
+    let rows = sqlx::query!(r#"SELECT post_table.id AS "id!", post_table.title AS "title!", post_table.body AS "body!", created AS "created!",
+        author_id AS "author_id!", published_at, slug AS "slug!", highlight(post_fts, 1, '**', '**') AS "highlight!: String"
+        FROM post_fts JOIN post_table ON post_table.id = post_fts.rowid
+        WHERE post_fts MATCH $1 AND published_at IS NOT NULL
+        ORDER BY rank, post_table.id DESC LIMIT $2 OFFSET $3"#,
+        query,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *transaction).await?;
+    let total = sqlx::query_scalar!("SELECT COUNT(*) FROM post_fts JOIN post_table ON post_table.id = post_fts.rowid
+        WHERE post_fts MATCH $1 AND published_at IS NOT NULL",
+        query)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    let results = rows.into_iter().map(|row| PostSearchResult {
+        post: Post {
+            id: row.id,
+            title: row.title.into(),
+            body: row.body.into(),
+            created: row.created.into(),
+            author_id: row.author_id,
+            published_at: row.published_at,
+            slug: row.slug
+        },
+        highlight: row.highlight
+    }).collect();
+    Ok(Page::new(results, page, state.per_page, total))
+}
+
+/// Returns the n=state.per_page published posts on the given 1-based page, newest first, optionally only those tagged `tag`.
+async fn get_posts_by_pagination(state: &AppState, page: u32, tag: Option<&str>) -> Result<Page<Post>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let mut transaction = read_conn.begin().await?;
+    let offset = page_offset(page, state.per_page);
+    let posts = sqlx::query_as!(Post, r#"SELECT id, title, body, created, author_id, published_at, slug AS "slug!" FROM post_table
+        WHERE published_at IS NOT NULL
+        AND ($1 IS NULL OR id IN (SELECT post_id FROM post_tag_table JOIN tag_table ON tag_table.id = post_tag_table.tag_id WHERE tag_table.name = $1))
+        ORDER BY id DESC LIMIT $2 OFFSET $3"#,
+        tag,
+        state.per_page,
+        offset)
+        .fetch_all(&mut *transaction).await?;
+    let total = sqlx::query_scalar!("SELECT COUNT(*) FROM post_table WHERE published_at IS NOT NULL
+        AND ($1 IS NULL OR id IN (SELECT post_id FROM post_tag_table JOIN tag_table ON tag_table.id = post_tag_table.tag_id WHERE tag_table.name = $1))",
+        tag)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Page::new(posts, page, state.per_page, total))
+}
+
+/// Find a given Post in the database by id.
+async fn select_post(id: i64, state: &AppState) -> Result<Option<Post>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Post, r#"SELECT id, title, body, created, author_id, published_at, slug AS "slug!" FROM post_table WHERE id = $1"#, id)
+        .fetch_optional(&mut *read_conn).await?)
+}
+
+/// The id of the post with this slug. When `published_only` is set, drafts count as missing.
+async fn select_post_id_by_slug(slug: &str, published_only: bool, state: &AppState) -> Result<Option<i64>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_scalar!(r#"SELECT id AS "id!" FROM post_table WHERE slug = $1 AND (NOT $2 OR published_at IS NOT NULL)"#, slug, published_only)
+        .fetch_optional(&mut *read_conn).await?)
+}
+
+/// Counts a view of published post `id` and returns the post with its new view count. None if there's no such published post.
+/// The count and the fetch share a transaction, so the count returned is the one this view produced.
+async fn view_post(id: i64, state: &AppState) -> Result<Option<ViewedPost>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    // writing first takes the write lock straight away, so concurrent views queue up instead of failing to upgrade a read lock
+    sqlx::query!("INSERT OR IGNORE INTO post_view_table (post_id) SELECT id FROM post_table WHERE id = $1 AND published_at IS NOT NULL", id)
+        .execute(&mut *transaction).await?;
+    let Some(view_count) = sqlx::query_scalar!("UPDATE post_view_table SET view_count = view_count + 1 WHERE post_id = $1 RETURNING view_count", id)
+        .fetch_optional(&mut *transaction).await? else {
+        return Ok(None);
+    };
+    let post = sqlx::query_as!(Post, r#"SELECT id, title, body, created, author_id, published_at, slug AS "slug!" FROM post_table WHERE id = $1"#, id)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(Some(ViewedPost { post: RenderedPost::from(post), view_count }))
+}
+
+/// The `limit` most viewed published posts, most viewed first. Posts nobody has viewed count as 0 views.
+async fn select_popular_posts(limit: u32, state: &AppState) -> Result<Vec<ViewedPost>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let rows = sqlx::query!(r#"SELECT post_table.id, title, body, created, author_id, published_at, slug AS "slug!", COALESCE(view_count, 0) AS "view_count!: i64"
+        FROM post_table LEFT JOIN post_view_table ON post_view_table.post_id = post_table.id
+        WHERE published_at IS NOT NULL ORDER BY 8 DESC, post_table.id DESC LIMIT $1"#,
+        limit)
+        .fetch_all(&mut *read_conn).await?;
+    Ok(rows.into_iter().map(|row| ViewedPost {
+        post: RenderedPost::from(Post {
+            id: row.id,
+            title: row.title.into(),
+            body: row.body.into(),
+            created: row.created.into(),
+            author_id: row.author_id,
+            published_at: row.published_at,
+            slug: row.slug
+        }),
+        view_count: row.view_count
+    }).collect())
+}
+
+/// Unpublished posts, newest first. Only those by `author_id` unless it's None.
+async fn select_drafts(author_id: Option<i64>, state: &AppState) -> Result<Vec<Post>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Post, r#"SELECT id, title, body, created, author_id, published_at, slug AS "slug!" FROM post_table
+        WHERE published_at IS NULL AND ($1 IS NULL OR author_id = $1) ORDER BY id DESC"#,
+        author_id)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// Inserts a post and its tags into persistent storage, returning the post with its assigned id.
+/// Without a `slug` one is made from the title, with a `-N` suffix if another post has it already.
+/// Tags that don't exist yet are created. Either all of it is stored or, on any error, none of it.
+async fn insert_post(title: &str, body: &str, slug: Option<&str>, tags: &[String], author_id: i64, state: &AppState) -> Result<Post, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    // dropping the transaction without committing rolls it back
+    let mut transaction = write_conn.begin().await?;
+    let slug = match slug {
+        Some(slug) => slug.to_string(),
+        None => {
+            let base = slugify(title);
+            let pattern = format!("{base}-%");
+            let taken = sqlx::query_scalar!(r#"SELECT slug AS "slug!" FROM post_table WHERE slug = $1 OR slug LIKE $2"#, base, pattern)
+                .fetch_all(&mut *transaction).await?;
+            disambiguate_slug(&base, &taken)
+        }
+    };
+    let created = Utc::now().to_rfc3339();
+    // sqlx can't tell RETURNING id is never null, hence the "id!" override
+    let post = sqlx::query_as!(Post, r#"INSERT INTO post_table (title, body, created, author_id, slug) VALUES ($1, $2, $3, $4, $5)
+        RETURNING id AS "id!", title, body, created, author_id, published_at, slug AS "slug!""#,
+        title,
+        body,
+        created,
+        author_id,
+        slug)
+        .fetch_one(&mut *transaction).await?;
+    for tag in tags {
+        // the no-op update makes RETURNING hand back the id of an existing tag too
+        let tag_id = sqlx::query_scalar!(r#"INSERT INTO tag_table (name) VALUES ($1) ON CONFLICT (name) DO UPDATE SET name = excluded.name
+            RETURNING id AS "id!""#, tag)
+            .fetch_one(&mut *transaction).await?;
+        sqlx::query!("INSERT INTO post_tag_table (post_id, tag_id) VALUES ($1, $2)", post.id, tag_id)
+            .execute(&mut *transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(post)
+}
+
+/// Replaces whichever of `title` and `body` are given. None if the post doesn't exist.
+async fn update_post(id: i64, title: Option<&str>, body: Option<&str>, state: &AppState) -> Result<Option<Post>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Post, r#"UPDATE post_table SET title = COALESCE($1, title), body = COALESCE($2, body) WHERE id = $3
+        RETURNING id AS "id!", title, body, created, author_id, published_at, slug AS "slug!""#,
+        title,
+        body,
+        id)
+        .fetch_optional(&mut *write_conn).await?)
+}
+
+/// Marks a post published now, unless it already was. None if the post doesn't exist.
+async fn publish_post_db(id: i64, state: &AppState) -> Result<Option<Post>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let now = Utc::now().to_rfc3339();
+    Ok(sqlx::query_as!(Post, r#"UPDATE post_table SET published_at = COALESCE(published_at, $1) WHERE id = $2
+        RETURNING id AS "id!", title, body, created, author_id, published_at, slug AS "slug!""#,
+        now,
+        id)
+        .fetch_optional(&mut *write_conn).await?)
+}
+
+/// Removes a post from persistent storage. Returns false if there was no such post.
+async fn delete_post_db(id: i64, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let delete_statement = sqlx::query!("DELETE FROM post_table WHERE id = $1", id)
+        .execute(&mut *write_conn).await?;
+    Ok(delete_statement.rows_affected() == 1)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::server::tests::{bearer, call, get_request, session_cookie, test_state};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE}};
+
+    pub(in crate::server) async fn send_json(state: Arc<AppState>, method: &str, uri: &str, authorization: &str, json: Value) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, authorization)
+            .body(Body::from(json.to_string()))
+            .unwrap();
+        let response = call(state, request).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Creates a post from `json` as `token`'s holder and publishes it, returning the published post.
+    pub(in crate::server) async fn published_post(state: &Arc<AppState>, token: &str, json: Value) -> Value {
+        let (status, post) = send_json(state.clone(), "POST", "/api/posts", token, json).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, published) = send_json(state.clone(), "POST", &format!("/api/posts/{}/publish", post["id"]), token, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        published
+    }
+
+    #[test]
+    fn test_post_fields_check() {
+        let fields = post_fields_check(&serde_json::json!({"title": "  Hello ", "body": "World"})).unwrap();
+        assert_eq!(fields, (Some("Hello".to_string()), Some("World".to_string())));
+        assert_eq!(post_fields_check(&serde_json::json!({})).unwrap(), (None, None));
+        assert!(post_fields_check(&serde_json::json!({"title": "   "})).is_err());
+        assert!(post_fields_check(&serde_json::json!({"title": 5})).is_err());
+        assert!(post_fields_check(&serde_json::json!({"title": "t".repeat(MAX_TITLE_CHARS + 1)})).is_err());
+    }
+
+    #[test]
+    fn test_tags_check() {
+        assert_eq!(tags_check(&serde_json::json!({})).unwrap(), Vec::<String>::new());
+        assert_eq!(tags_check(&serde_json::json!({"tags": ["Rust", "web-dev", "rust"]})).unwrap(), vec!["rust", "web-dev"]);
+        assert!(tags_check(&serde_json::json!({"tags": ["t".repeat(MAX_TAG_CHARS)]})).is_ok());
+        assert!(tags_check(&serde_json::json!({"tags": ["t".repeat(MAX_TAG_CHARS + 1)]})).is_err());
+        assert!(tags_check(&serde_json::json!({"tags": ["no spaces"]})).is_err());
+        assert!(tags_check(&serde_json::json!({"tags": ["<b>"]})).is_err());
+        assert!(tags_check(&serde_json::json!({"tags": [""]})).is_err());
+        assert!(tags_check(&serde_json::json!({"tags": "rust"})).is_err());
+        assert!(tags_check(&serde_json::json!({"tags": [1]})).is_err());
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(slugify("  Leading and   trailing  "), "leading-and-trailing");
+        assert_eq!(slugify("Rust 2024 - what's new"), "rust-2024-whats-new");
+        assert_eq!(slugify("     "), FALLBACK_SLUG);
+        assert_eq!(slugify(""), FALLBACK_SLUG);
+        // non-ASCII letters are dropped rather than transliterated
+        assert_eq!(slugify("Café Ünïcode"), "caf-ncode");
+        assert_eq!(slugify("日本語"), FALLBACK_SLUG);
+        assert_eq!(slugify(&"a ".repeat(MAX_SLUG_CHARS)).len(), MAX_SLUG_CHARS - 1);
+    }
+
+    #[test]
+    fn test_disambiguate_slug() {
+        assert_eq!(disambiguate_slug("title", &[]), "title");
+        assert_eq!(disambiguate_slug("title", &["title-2".to_string()]), "title");
+        assert_eq!(disambiguate_slug("title", &["title".to_string()]), "title-2");
+        assert_eq!(disambiguate_slug("title", &["title".to_string(), "title-2".to_string(), "title-4".to_string()]), "title-3");
+    }
+
+    #[test]
+    fn test_slug_check() {
+        assert_eq!(slug_check(&serde_json::json!("my-post-2")).unwrap(), "my-post-2");
+        assert!(slug_check(&serde_json::json!("My-Post")).is_err());
+        assert!(slug_check(&serde_json::json!("-post")).is_err());
+        assert!(slug_check(&serde_json::json!("two--hyphens")).is_err());
+        assert!(slug_check(&serde_json::json!("")).is_err());
+        assert!(slug_check(&serde_json::json!("s".repeat(MAX_SLUG_CHARS + 1))).is_err());
+        assert!(slug_check(&serde_json::json!(5)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_slugs() {
+        let state = test_state().await;
+        let author = bearer(&state, "slug_author", Role::User).await;
+        let mut slugs = Vec::new();
+        for json in [serde_json::json!({"title": "Same Title", "body": "text"}), serde_json::json!({"title": "same title!", "body": "text"}),
+                     serde_json::json!({"title": "¿¡!?", "body": "text"}), serde_json::json!({"title": "Chosen", "body": "text", "slug": "my-slug"})] {
+            let post = published_post(&state, &author, json).await;
+            slugs.push(post["slug"].as_str().unwrap().to_string());
+        }
+        assert_eq!(slugs, ["same-title", "same-title-2", FALLBACK_SLUG, "my-slug"]);
+        let (status, _) = send_json(state.clone(), "POST", "/api/posts", &author,
+                                    serde_json::json!({"title": "Again", "body": "text", "slug": "my-slug"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = get_request(state.clone(), "/api/posts/by-slug/same-title-2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["title"], "same title!");
+        assert_eq!(get_request(state.clone(), "/api/posts/by-slug/no-such-post").await.0, StatusCode::NOT_FOUND);
+        let (status, page) = get_request(state.clone(), "/posts/my-slug").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(String::from_utf8(page).unwrap().contains("<h2>Chosen</h2>"));
+        assert_eq!(get_request(state.clone(), "/posts/no-such-post").await.0, StatusCode::NOT_FOUND);
+
+        // drafts have slugs but aren't public
+        let (_, draft) = send_json(state.clone(), "POST", "/api/posts", &author, serde_json::json!({"title": "Draft", "body": "text"})).await;
+        assert_eq!(get_request(state.clone(), "/api/posts/by-slug/draft").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get_request(state.clone(), "/posts/draft").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(draft["slug"], "draft");
+        let (_, listing) = get_request(state, "/posts").await;
+        // tera escapes the slashes in base_url
+        assert!(String::from_utf8(listing).unwrap().contains(r#"3000&#x2F;posts/my-slug">Chosen</a>"#));
+    }
+
+    #[tokio::test]
+    async fn test_filter_posts_by_tag() {
+        let state = test_state().await;
+        let author = bearer(&state, "tag_author", Role::User).await;
+        for (title, tags) in [("Tagged both", serde_json::json!(["rust", "web"])), ("Tagged rust", serde_json::json!(["Rust"])),
+                              ("Untagged", serde_json::json!([]))] {
+            published_post(&state, &author, serde_json::json!({"title": title, "body": "text", "tags": tags})).await;
+        }
+        let titles = |body: Vec<u8>| serde_json::from_slice::<Value>(&body).unwrap()["items"].as_array().unwrap().iter()
+            .map(|post| post["title"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let (_, body) = get_request(state.clone(), "/api/posts?tag=rust").await;
+        assert_eq!(titles(body), ["Tagged rust", "Tagged both"]);
+        let (_, body) = get_request(state.clone(), "/api/posts?tag=web").await;
+        assert_eq!(titles(body), ["Tagged both"]);
+        let (_, body) = get_request(state.clone(), "/api/posts?tag=unused").await;
+        assert!(titles(body).is_empty());
+        let (_, body) = get_request(state.clone(), "/api/posts").await;
+        assert_eq!(titles(body).len(), 3);
+        let (status, _) = get_request(state.clone(), "/api/posts?tag=not%20valid").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send_json(state, "POST", "/api/posts", &author,
+                                    serde_json::json!({"title": "Bad tag", "body": "text", "tags": ["x".repeat(MAX_TAG_CHARS + 1)]})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_create_post_rolls_back_when_a_tag_fails() {
+        let state = test_state().await;
+        let author = bearer(&state, "rollback_author", Role::User).await;
+        // makes the second of the post's tags fail to insert, after the post and first tag went in
+        sqlx::query!("CREATE TRIGGER fail_tag BEFORE INSERT ON tag_table WHEN NEW.name = 'explode' BEGIN SELECT RAISE(ABORT, 'tag refused'); END")
+            .execute(&state.write_pool).await.unwrap();
+        let (status, _) = send_json(state.clone(), "POST", "/api/posts", &author,
+                                    serde_json::json!({"title": "Doomed", "body": "text", "tags": ["fine", "explode"]})).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let counts = sqlx::query!(r#"SELECT (SELECT COUNT(*) FROM post_table) AS "posts!: i64", (SELECT COUNT(*) FROM tag_table) AS "tags!: i64",
+                                     (SELECT COUNT(*) FROM post_tag_table) AS "post_tags!: i64""#)
+            .fetch_one(&state.read_pool).await.unwrap();
+        assert_eq!((counts.posts, counts.tags, counts.post_tags), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_post_mutations_need_a_bearer_token() {
+        let state = test_state().await;
+        // a browser session alone isn't enough to write posts
+        let cookie = session_cookie(&state, "cookie_author", Role::User).await;
+        let request = Request::post("/api/posts")
+            .header(CONTENT_TYPE, "application/json")
+            .header(COOKIE, cookie)
+            .body(Body::from(serde_json::json!({"title": "a", "body": "b"}).to_string()))
+            .unwrap();
+        assert_eq!(call(state, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_post_lifecycle() {
+        let state = test_state().await;
+        let author = bearer(&state, "post_author", Role::User).await;
+        let stranger = bearer(&state, "post_stranger", Role::User).await;
+        let admin = bearer(&state, "post_admin", Role::Admin).await;
+
+        // create
+        let (status, post) = send_json(state.clone(), "POST", "/api/posts", &author,
+                                       serde_json::json!({"title": "First post", "body": "Hello world"})).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((&post["word_count"], &post["reading_time_minutes"]), (&serde_json::json!(2), &serde_json::json!(1)));
+        let uri = format!("/api/posts/{}", post["id"]);
+        let (status, _) = send_json(state.clone(), "POST", "/api/posts", "Bearer not.a.token", serde_json::json!({"title": "a", "body": "b"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send_json(state.clone(), "POST", "/api/posts", &author, serde_json::json!({"title": "No body"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // publish
+        assert_eq!(post["published_at"], Value::Null);
+        assert_eq!(get_request(state.clone(), &uri).await.0, StatusCode::NOT_FOUND);
+        let (status, _) = send_json(state.clone(), "POST", &format!("{uri}/publish"), &stranger, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, published) = send_json(state.clone(), "POST", &format!("{uri}/publish"), &author, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(published["published_at"].is_string());
+        // publishing again keeps the original date
+        let (_, republished) = send_json(state.clone(), "POST", &format!("{uri}/publish"), &admin, Value::Null).await;
+        assert_eq!(republished["published_at"], published["published_at"]);
+        let post = published;
+
+        // read
+        let (status, body) = get_request(state.clone(), &uri).await;
+        let mut read: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(read.as_object_mut().unwrap().remove("rendered_body"), Some(serde_json::json!("<p>Hello world</p>\n")));
+        assert_eq!(read.as_object_mut().unwrap().remove("view_count"), Some(serde_json::json!(1)));
+        assert_eq!(read.as_object_mut().unwrap().remove("series"), Some(Value::Null));
+        assert_eq!((status, &read), (StatusCode::OK, &post));
+        let (status, body) = get_request(state.clone(), "/api/posts").await;
+        assert_eq!((status, serde_json::from_slice::<Value>(&body).unwrap()),
+                   (StatusCode::OK, serde_json::json!({"items": [post], "page": 1, "per_page": 32, "total": 1, "total_pages": 1})));
+        let (status, body) = get_request(state.clone(), "/posts").await;
+        assert_eq!(status, StatusCode::OK);
+        let page = String::from_utf8(body).unwrap();
+        assert!(page.contains("First post") && page.contains("<p>Hello world</p>") && page.contains("1 min read"));
+
+        // update
+        let (status, _) = send_json(state.clone(), "PATCH", &uri, &stranger, serde_json::json!({"title": "Hijacked"})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, updated) = send_json(state.clone(), "PATCH", &uri, &author, serde_json::json!({"title": "Edited post"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&updated["title"], &updated["body"]), (&serde_json::json!("Edited post"), &serde_json::json!("Hello world")));
+        let (status, _) = send_json(state.clone(), "PATCH", &uri, &admin, serde_json::json!({"body": "Moderated"})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // delete
+        let (status, _) = send_json(state.clone(), "DELETE", &uri, &stranger, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send_json(state.clone(), "DELETE", &uri, &author, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = get_request(state.clone(), &uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send_json(state, "DELETE", &uri, &admin, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_drafts_stay_out_of_public_listings() {
+        let state = test_state().await;
+        let author = bearer(&state, "draft_author", Role::User).await;
+        let other = bearer(&state, "other_author", Role::User).await;
+        let admin = bearer(&state, "draft_admin", Role::Admin).await;
+        let (_, draft) = send_json(state.clone(), "POST", "/api/posts", &author, serde_json::json!({"title": "Work in progress", "body": "tbd"})).await;
+        published_post(&state, &author, serde_json::json!({"title": "Finished", "body": "done"})).await;
+        let (_, other_draft) = send_json(state.clone(), "POST", "/api/posts", &other, serde_json::json!({"title": "Not yours", "body": "tbd"})).await;
+
+        let (_, body) = get_request(state.clone(), "/api/posts").await;
+        let public = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(public["items"].as_array().unwrap().iter().map(|post| &post["title"]).collect::<Vec<_>>(), ["Finished"]);
+        assert_eq!(public["total"], 1);
+        let (_, body) = get_request(state.clone(), "/posts").await;
+        assert!(!String::from_utf8(body).unwrap().contains("Work in progress"));
+
+        let (status, drafts) = send_json(state.clone(), "GET", "/api/admin/posts/drafts", &author, Value::Null).await;
+        assert_eq!((status, drafts), (StatusCode::OK, serde_json::json!([draft])));
+        let (_, drafts) = send_json(state.clone(), "GET", "/api/admin/posts/drafts", &admin, Value::Null).await;
+        assert_eq!(drafts, serde_json::json!([other_draft, draft]));
+        let request = Request::get("/api/admin/posts/drafts").body(Body::empty()).unwrap();
+        assert_eq!(call(state, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_query("quick  fox").as_deref(), Some(r#""quick" "fox""#));
+        assert_eq!(fts_query(r#"say "hi" OR*"#).as_deref(), Some(r#""say" """hi""" "OR*""#));
+        assert_eq!(fts_query("   "), None);
+    }
+
+    #[tokio::test]
+    async fn test_search_posts() {
+        let state = test_state().await;
+        let author = bearer(&state, "search_author", Role::User).await;
+        let (_, post) = send_json(state.clone(), "POST", "/api/posts", &author,
+            serde_json::json!({"title": "Animals", "body": "The quick brown fox jumps"})).await;
+        let id = post["id"].as_i64().unwrap();
+        send_json(state.clone(), "POST", "/api/posts", &author, serde_json::json!({"title": "Draft", "body": "Another fox"})).await;
+        let search = |query: &str| {
+            let (state, uri) = (state.clone(), format!("/api/posts/search?q={query}"));
+            async move {
+                let (status, body) = get_request(state, &uri).await;
+                (status, serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null)["items"].clone())
+            }
+        };
+
+        // only published posts are found
+        assert_eq!(search("fox").await.1, serde_json::json!([]));
+        send_json(state.clone(), "POST", &format!("/api/posts/{id}/publish"), &author, Value::Null).await;
+        let (status, found) = search("fox").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(found.as_array().unwrap().len(), 1);
+        assert_eq!(found[0]["id"], id);
+        assert_eq!(found[0]["highlight"], "The quick brown **fox** jumps");
+        assert_eq!(search("FOX%20quick").await.1[0]["id"], id);
+        assert_eq!(search("zebra").await.1, serde_json::json!([]));
+        assert_eq!(search("fox%20zebra").await.1, serde_json::json!([]));
+        assert_eq!(search("animals").await.1[0]["id"], id);
+        assert_eq!(search("fox&page=2").await.1, serde_json::json!([]));
+        let (_, body) = get_request(state.clone(), "/api/posts/search?q=fox&page=2").await;
+        let page = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!((&page["page"], &page["total"], &page["total_pages"]), (&Value::from(2), &Value::from(1), &Value::from(1)));
+
+        // edits and deletes reach the index
+        send_json(state.clone(), "PATCH", &format!("/api/posts/{id}"), &author, serde_json::json!({"body": "A lazy zebra"})).await;
+        assert_eq!(search("fox").await.1, serde_json::json!([]));
+        assert_eq!(search("zebra").await.1[0]["id"], id);
+        send_json(state.clone(), "DELETE", &format!("/api/posts/{id}"), &author, Value::Null).await;
+        assert_eq!(search("zebra").await.1, serde_json::json!([]));
+
+        // FTS5 syntax is searched for rather than failing the query
+        assert_eq!(search("%22zebra%20OR").await.0, StatusCode::OK);
+        assert_eq!(search("%20%20").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(search(&"z".repeat(MAX_SEARCH_CHARS + 1)).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(get_request(state, "/api/posts/search").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_view_counts() {
+        let state = test_state().await;
+        let author = bearer(&state, "viewed_author", Role::User).await;
+        let mut ids = Vec::new();
+        for title in ["Quiet", "Popular", "Draft"] {
+            let json = serde_json::json!({"title": title, "body": "text"});
+            let post = match title {
+                "Draft" => send_json(state.clone(), "POST", "/api/posts", &author, json).await.1,
+                _ => published_post(&state, &author, json).await
+            };
+            ids.push(post["id"].as_i64().unwrap());
+        }
+        let view = |id: i64| {
+            let state = state.clone();
+            async move {
+                let (status, body) = get_request(state, &format!("/api/posts/{id}")).await;
+                (status, serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null)["view_count"].as_i64())
+            }
+        };
+
+        assert_eq!(view(ids[0]).await, (StatusCode::OK, Some(1)));
+        // simultaneous views are each counted once
+        let (first, second) = tokio::join!(view(ids[1]), view(ids[1]));
+        let mut counts = [first.1.unwrap(), second.1.unwrap()];
+        counts.sort();
+        assert_eq!(counts, [1, 2]);
+        assert_eq!(view(ids[1]).await.1, Some(3));
+        // drafts and missing posts aren't counted
+        assert_eq!(view(ids[2]).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(view(9999).await.0, StatusCode::NOT_FOUND);
+        let rows = sqlx::query_scalar!("SELECT COUNT(*) FROM post_view_table").fetch_one(&state.read_pool).await.unwrap();
+        assert_eq!(rows, 2);
+
+        let (status, body) = get_request(state.clone(), "/api/posts/popular").await;
+        assert_eq!(status, StatusCode::OK);
+        let popular = serde_json::from_slice::<Vec<Value>>(&body).unwrap().into_iter()
+            .map(|post| (post["title"].as_str().unwrap().to_string(), post["view_count"].as_i64().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(popular, [("Popular".to_string(), 3), ("Quiet".to_string(), 1)]);
+        let (_, body) = get_request(state.clone(), "/api/posts/popular?limit=1").await;
+        assert_eq!(serde_json::from_slice::<Vec<Value>>(&body).unwrap().len(), 1);
+        assert_eq!(get_request(state, "/api/posts/popular?limit=lots").await.0, StatusCode::BAD_REQUEST);
+    }
+}
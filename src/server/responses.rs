@@ -0,0 +1,79 @@
+// Response constructors. Handlers build their bodies through these so every response gets its
+// Content-Type from one place instead of from whichever wrapper type the handler happened to use.
+use axum::{
+    body::Body,
+    http::{header::{CONTENT_DISPOSITION, CONTENT_TYPE}, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+pub(super) const HTML: &str = "text/html; charset=utf-8";
+pub(super) const JSON: &str = "application/json";
+pub(super) const PLAIN: &str = "text/plain; charset=utf-8";
+pub(super) const CSV: &str = "text/csv; charset=utf-8";
+
+/// `body` as an HTML page.
+pub(super) fn html_response(status: StatusCode, body: impl Into<Body>) -> Response {
+    (status, [(CONTENT_TYPE, HTML)], body.into()).into_response()
+}
+
+/// `body` serialized as JSON. Falls back to a plain 500 if it can't be serialized.
+pub(super) fn json_response(status: StatusCode, body: impl Serialize) -> Response {
+    match serde_json::to_vec(&body) {
+        Ok(bytes) => (status, [(CONTENT_TYPE, JSON)], bytes).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to serialize response body: {e}");
+            // written out by hand, since serializing is what just failed
+            (StatusCode::INTERNAL_SERVER_ERROR, [(CONTENT_TYPE, JSON)], r#"{"error":"Internal server error.","code":500}"#).into_response()
+        }
+    }
+}
+
+/// `body`, already serialized JSON, sent as is.
+pub(super) fn raw_json_response(status: StatusCode, body: impl Into<Body>) -> Response {
+    (status, [(CONTENT_TYPE, JSON)], body.into()).into_response()
+}
+
+/// `body` as plain UTF-8 text.
+pub(super) fn plain_response(status: StatusCode, body: impl Into<Body>) -> Response {
+    (status, [(CONTENT_TYPE, PLAIN)], body.into()).into_response()
+}
+
+/// `body` as a CSV file that browsers save as `filename` rather than display.
+pub(super) fn csv_response(status: StatusCode, filename: &str, body: impl Into<Body>) -> Response {
+    (status, [(CONTENT_TYPE, CSV.to_string()), (CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\""))], body.into()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::HeaderValue};
+
+    async fn parts(response: Response) -> (StatusCode, HeaderValue, String) {
+        let status = response.status();
+        let content_type = response.headers()[CONTENT_TYPE].clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_content_types() {
+        assert_eq!(parts(html_response(StatusCode::OK, "<p>hi</p>")).await,
+                   (StatusCode::OK, HeaderValue::from_static("text/html; charset=utf-8"), "<p>hi</p>".to_string()));
+        assert_eq!(parts(json_response(StatusCode::CREATED, serde_json::json!({"a": 1}))).await,
+                   (StatusCode::CREATED, HeaderValue::from_static("application/json"), r#"{"a":1}"#.to_string()));
+        assert_eq!(parts(raw_json_response(StatusCode::OK, "[1,2]")).await,
+                   (StatusCode::OK, HeaderValue::from_static("application/json"), "[1,2]".to_string()));
+        assert_eq!(parts(plain_response(StatusCode::NOT_FOUND, "gone")).await,
+                   (StatusCode::NOT_FOUND, HeaderValue::from_static("text/plain; charset=utf-8"), "gone".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_unserializable_json_is_a_json_500() {
+        // JSON object keys must be strings
+        let body = std::collections::HashMap::from([((1, 2), "value")]);
+        let (status, content_type, body) = parts(json_response(StatusCode::OK, body)).await;
+        assert_eq!((status, content_type), (StatusCode::INTERNAL_SERVER_ERROR, HeaderValue::from_static(JSON)));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["code"], 500);
+    }
+}
@@ -0,0 +1,402 @@
+// Series, for grouping multi-part posts in reading order. Admins manage the series themselves, while a post's
+// author (or an admin) decides which series the post belongs to and where it goes in it.
+use super::{acquire_with_timeout, error::{AppError, ErrorBody}, jwt::AuthBearer,
+            posts::{authorize_post_change, disambiguate_slug, slug_check, slugify, Post}, responses::json_response, AppState, Role};
+use anyhow::Error;
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::{header::LOCATION, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::Connection;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 1000;
+
+/// A row of series_table.
+#[derive(Serialize, Debug, PartialEq, sqlx::FromRow, ToSchema)]
+pub(super) struct Series {
+    pub(super) id: i64,
+    pub(super) title: String,
+    // unique, URL safe name of the series, used by /api/series/{slug}
+    pub(super) slug: String,
+    pub(super) description: Option<String>
+}
+
+/// A series with its published posts in reading order.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct SeriesWithPosts {
+    #[serde(flatten)]
+    pub(super) series: Series,
+    pub(super) posts: Vec<Post>
+}
+
+/// Where a post sits in its series, by the ids of the published posts either side of it.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub(super) struct SeriesPosition {
+    pub(super) title: String,
+    pub(super) slug: String,
+    /// None for the first post of the series.
+    pub(super) prev_post_id: Option<i64>,
+    /// None for the last post of the series.
+    pub(super) next_post_id: Option<i64>
+}
+
+/// API endpoint listing every series, oldest first.
+#[utoipa::path(get, path = "/api/series", tag = "posts",
+    responses(
+        (status = 200, body = Vec<Series>)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn list_series(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    Ok(json_response(StatusCode::OK, select_all_series(&state).await?))
+}
+
+/// POST request handler creating a series. Admin only.
+#[utoipa::path(post, path = "/api/series", tag = "posts", security(("bearer" = [])),
+    request_body(content = Object, description = "`title`, plus an optional `description` and `slug`. Without a slug one is made from the title."),
+    responses(
+        (status = 201, description = "Created, with the series' URL in Location", body = Series),
+        (status = 400, description = "Invalid fields, or the slug is taken", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn create_series(State(state): State<Arc<AppState>>, auth: AuthBearer,
+                                  result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    admin_check(&auth)?;
+    let Json(json_map) = result?;
+    let (Some(title), description) = series_fields_check(&json_map)? else {
+        return Err(AppError::BadRequest("'title' is required.".to_string()));
+    };
+    let slug = json_map.get("slug").map(slug_check).transpose()?;
+    let slug_taken = match &slug {
+        Some(slug) => select_series(slug, &state).await?.is_some(),
+        None => false
+    };
+    if slug_taken {
+        return Err(AppError::BadRequest("That slug is already taken.".to_string()));
+    }
+    let series = insert_series(&title, description.as_deref(), slug.as_deref(), &state).await?;
+    tracing::info!(series_id = series.id, "Created series");
+    Ok(([(LOCATION, format!("/api/series/{}", series.slug))], json_response(StatusCode::CREATED, series)))
+}
+
+/// API endpoint returning a series with its published posts in reading order.
+#[utoipa::path(get, path = "/api/series/{slug}", tag = "posts", params(("slug" = String, Path)),
+    responses(
+        (status = 200, body = SeriesWithPosts),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state))]
+pub(super) async fn get_series(State(state): State<Arc<AppState>>, Path(slug): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let series = select_series(&slug, &state).await?
+        .ok_or(AppError::NotFound(format!("Series '{slug}' does not exist.")))?;
+    let posts = select_series_posts(series.id, &state).await?;
+    Ok(json_response(StatusCode::OK, SeriesWithPosts { series, posts }))
+}
+
+/// PATCH request handler changing a series' title and/or description. Admin only.
+#[utoipa::path(patch, path = "/api/series/{slug}", tag = "posts", security(("bearer" = [])), params(("slug" = String, Path)),
+    request_body(content = Object, description = "`title` and/or `description`"),
+    responses(
+        (status = 200, body = Series),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth, result))]
+pub(super) async fn patch_series(State(state): State<Arc<AppState>>, auth: AuthBearer, Path(slug): Path<String>,
+                                 result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    admin_check(&auth)?;
+    let Json(json_map) = result?;
+    let (title, description) = series_fields_check(&json_map)?;
+    if title.is_none() && description.is_none() {
+        return Err(AppError::BadRequest("No updatable fields supplied.".to_string()));
+    }
+    let series = update_series(&slug, title.as_deref(), description.as_deref(), &state).await?
+        .ok_or(AppError::NotFound(format!("Series '{slug}' does not exist.")))?;
+    tracing::info!("Updated series");
+    Ok(json_response(StatusCode::OK, series))
+}
+
+/// DELETE request handler removing a series. Its posts stay, just no longer in a series. Admin only.
+#[utoipa::path(delete, path = "/api/series/{slug}", tag = "posts", security(("bearer" = [])), params(("slug" = String, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth))]
+pub(super) async fn delete_series(State(state): State<Arc<AppState>>, auth: AuthBearer, Path(slug): Path<String>) -> Result<impl IntoResponse, AppError> {
+    admin_check(&auth)?;
+    if !delete_series_db(&slug, &state).await? {
+        return Err(AppError::NotFound(format!("Series '{slug}' does not exist.")));
+    }
+    tracing::info!("Deleted series");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// PUT request handler putting post `id` into a series at position `order`, moving it out of any other series.
+/// Posts are read in ascending `order`. The post's author or an admin only.
+#[utoipa::path(put, path = "/api/series/{slug}/posts/{id}", tag = "posts", security(("bearer" = [])),
+    params(("slug" = String, Path), ("id" = i64, Path)),
+    request_body(content = Object, description = "`order`, an integer"),
+    responses(
+        (status = 204, description = "The post is in the series"),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth, result))]
+pub(super) async fn add_post(State(state): State<Arc<AppState>>, auth: AuthBearer, Path((slug, id)): Path<(String, i64)>,
+                             result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    authorize_post_change(id, &auth, &state).await?;
+    let Json(json_map) = result?;
+    let order = json_map.get("order").and_then(Value::as_i64)
+        .ok_or(AppError::BadRequest("'order' must be an integer.".to_string()))?;
+    let series = select_series(&slug, &state).await?
+        .ok_or(AppError::NotFound(format!("Series '{slug}' does not exist.")))?;
+    set_post_series(id, Some((series.id, order)), &state).await?;
+    tracing::info!("Added post to series");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE request handler taking post `id` out of a series. The post's author or an admin only.
+#[utoipa::path(delete, path = "/api/series/{slug}/posts/{id}", tag = "posts", security(("bearer" = [])),
+    params(("slug" = String, Path), ("id" = i64, Path)),
+    responses(
+        (status = 204, description = "The post is no longer in the series"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, description = "No such post, or it isn't in the series", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, auth))]
+pub(super) async fn remove_post(State(state): State<Arc<AppState>>, auth: AuthBearer, Path((slug, id)): Path<(String, i64)>)
+                                -> Result<impl IntoResponse, AppError> {
+    authorize_post_change(id, &auth, &state).await?;
+    let not_in_series = || AppError::NotFound(format!("Post {id} is not in series '{slug}'."));
+    let series = select_series(&slug, &state).await?.ok_or_else(not_in_series)?;
+    if select_series_id_of_post(id, &state).await? != Some(series.id) {
+        return Err(not_in_series());
+    }
+    set_post_series(id, None, &state).await?;
+    tracing::info!("Removed post from series");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Series are managed by admins only.
+fn admin_check(auth: &AuthBearer) -> Result<(), AppError> {
+    if auth.claims.role != Role::Admin {
+        return Err(AppError::Forbidden);
+    }
+    Ok(())
+}
+
+/// Validates the optional `title` and `description` fields of a series payload.
+fn series_fields_check(json_map: &Value) -> Result<(Option<String>, Option<String>), AppError> {
+    let field = |name: &str, max_chars: usize| match json_map.get(name) {
+        None => Ok(None),
+        Some(Value::String(value)) if (1..=max_chars).contains(&value.trim().chars().count()) => Ok(Some(value.trim().to_string())),
+        Some(_) => Err(AppError::BadRequest(format!("'{name}' must be a string of 1 to {max_chars} characters."))),
+    };
+    Ok((field("title", MAX_TITLE_CHARS)?, field("description", MAX_DESCRIPTION_CHARS)?))
+}
+
+/// Every series, oldest first.
+async fn select_all_series(state: &AppState) -> Result<Vec<Series>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Series, "SELECT id, title, slug, description FROM series_table ORDER BY id")
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// The series with this slug, if there is one.
+async fn select_series(slug: &str, state: &AppState) -> Result<Option<Series>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Series, "SELECT id, title, slug, description FROM series_table WHERE slug = $1", slug)
+        .fetch_optional(&mut *read_conn).await?)
+}
+
+/// The published posts of series `series_id` in reading order. Posts sharing an order go oldest first.
+async fn select_series_posts(series_id: i64, state: &AppState) -> Result<Vec<Post>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Post, r#"SELECT id, title, body, created, author_id, published_at, slug AS "slug!" FROM post_table
+        WHERE series_id = $1 AND published_at IS NOT NULL ORDER BY series_order, id"#,
+        series_id)
+        .fetch_all(&mut *read_conn).await?)
+}
+
+/// The id of the series post `id` is in, if any.
+async fn select_series_id_of_post(id: i64, state: &AppState) -> Result<Option<i64>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    Ok(sqlx::query_scalar!("SELECT series_id FROM post_table WHERE id = $1", id)
+        .fetch_optional(&mut *read_conn).await?.flatten())
+}
+
+/// Where published post `id` sits in its series. None if it isn't in one, or isn't published.
+pub(super) async fn select_series_position(id: i64, state: &AppState) -> Result<Option<SeriesPosition>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    // drafts are left out before numbering, so they never show up as anyone's neighbour
+    Ok(sqlx::query_as!(SeriesPosition, r#"SELECT title AS "title!", slug AS "slug!", prev_post_id AS "prev_post_id?: i64", next_post_id AS "next_post_id?: i64"
+        FROM (
+            SELECT post_table.id, series_table.title, series_table.slug,
+                LAG(post_table.id) OVER series_posts AS prev_post_id, LEAD(post_table.id) OVER series_posts AS next_post_id
+            FROM post_table JOIN series_table ON series_table.id = post_table.series_id
+            WHERE post_table.series_id = (SELECT series_id FROM post_table WHERE id = $1) AND post_table.published_at IS NOT NULL
+            WINDOW series_posts AS (ORDER BY post_table.series_order, post_table.id)
+        )
+        WHERE id = $1"#,
+        id)
+        .fetch_optional(&mut *read_conn).await?)
+}
+
+/// Inserts a series, returning it with its assigned id. Without a `slug` one is made from the title, with a `-N` suffix
+/// if another series has it already.
+async fn insert_series(title: &str, description: Option<&str>, slug: Option<&str>, state: &AppState) -> Result<Series, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    let slug = match slug {
+        Some(slug) => slug.to_string(),
+        None => {
+            let base = slugify(title);
+            let pattern = format!("{base}-%");
+            let taken = sqlx::query_scalar!("SELECT slug FROM series_table WHERE slug = $1 OR slug LIKE $2", base, pattern)
+                .fetch_all(&mut *transaction).await?;
+            disambiguate_slug(&base, &taken)
+        }
+    };
+    let series = sqlx::query_as!(Series, r#"INSERT INTO series_table (title, slug, description) VALUES ($1, $2, $3)
+        RETURNING id AS "id!", title, slug, description"#,
+        title,
+        slug,
+        description)
+        .fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(series)
+}
+
+/// Replaces whichever of `title` and `description` are given. None if there's no such series.
+async fn update_series(slug: &str, title: Option<&str>, description: Option<&str>, state: &AppState) -> Result<Option<Series>, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    Ok(sqlx::query_as!(Series, r#"UPDATE series_table SET title = COALESCE($1, title), description = COALESCE($2, description) WHERE slug = $3
+        RETURNING id AS "id!", title, slug, description"#,
+        title,
+        description,
+        slug)
+        .fetch_optional(&mut *write_conn).await?)
+}
+
+/// Deletes a series after taking its posts out of it. Returns false if there was no such series.
+async fn delete_series_db(slug: &str, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    sqlx::query!("UPDATE post_table SET series_id = NULL, series_order = NULL WHERE series_id = (SELECT id FROM series_table WHERE slug = $1)", slug)
+        .execute(&mut *transaction).await?;
+    let delete_statement = sqlx::query!("DELETE FROM series_table WHERE slug = $1", slug)
+        .execute(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(delete_statement.rows_affected() == 1)
+}
+
+/// Puts post `id` into the series with the given id at the given order, or takes it out of its series with None.
+async fn set_post_series(id: i64, series: Option<(i64, i64)>, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let (series_id, series_order) = series.unzip();
+    sqlx::query!("UPDATE post_table SET series_id = $1, series_order = $2 WHERE id = $3", series_id, series_order, id)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{posts::tests::{published_post, send_json}, tests::{bearer, get_request, test_state}};
+
+    #[tokio::test]
+    async fn test_series_crud() {
+        let state = test_state().await;
+        let admin = bearer(&state, "admin_user", Role::Admin).await;
+        let author = bearer(&state, "author_user", Role::User).await;
+        let create = serde_json::json!({"title": "Writing a web server", "description": "In three parts"});
+        assert_eq!(send_json(state.clone(), "POST", "/api/series", &author, create.clone()).await.0, StatusCode::FORBIDDEN);
+        let (status, series) = send_json(state.clone(), "POST", "/api/series", &admin, create.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(series["slug"], "writing-a-web-server");
+        // the same title again gets a slug of its own
+        assert_eq!(send_json(state.clone(), "POST", "/api/series", &admin, create).await.1["slug"], "writing-a-web-server-2");
+        let taken = serde_json::json!({"title": "Other", "slug": "writing-a-web-server"});
+        assert_eq!(send_json(state.clone(), "POST", "/api/series", &admin, taken).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send_json(state.clone(), "POST", "/api/series", &admin, serde_json::json!({"description": "untitled"})).await.0,
+                   StatusCode::BAD_REQUEST);
+
+        let (status, series) = send_json(state.clone(), "PATCH", "/api/series/writing-a-web-server", &admin,
+                                         serde_json::json!({"description": "In four parts"})).await;
+        assert_eq!((status, &series["title"], &series["description"]),
+                   (StatusCode::OK, &Value::from("Writing a web server"), &Value::from("In four parts")));
+        let (_, listed) = get_request(state.clone(), "/api/series").await;
+        assert_eq!(serde_json::from_slice::<Value>(&listed).unwrap().as_array().unwrap().len(), 2);
+
+        let id = published_post(&state, &author, serde_json::json!({"title": "Part one", "body": "text"})).await["id"].as_i64().unwrap();
+        let uri = format!("/api/series/writing-a-web-server/posts/{id}");
+        assert_eq!(send_json(state.clone(), "PUT", &uri, &author, serde_json::json!({"order": 1})).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send_json(state.clone(), "DELETE", "/api/series/writing-a-web-server", &admin, Value::Null).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(get_request(state.clone(), "/api/series/writing-a-web-server").await.0, StatusCode::NOT_FOUND);
+        // the post outlives its series
+        let (status, post) = get_request(state.clone(), &format!("/api/posts/{id}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&post).unwrap()["series"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_series_neighbours() {
+        let state = test_state().await;
+        let admin = bearer(&state, "admin_user", Role::Admin).await;
+        let author = bearer(&state, "author_user", Role::User).await;
+        send_json(state.clone(), "POST", "/api/series", &admin, serde_json::json!({"title": "Trilogy"})).await;
+        let mut ids = Vec::new();
+        for title in ["One", "Two", "Three"] {
+            ids.push(published_post(&state, &author, serde_json::json!({"title": title, "body": "text"})).await["id"].as_i64().unwrap());
+        }
+        let (first, second, third) = (ids[0], ids[1], ids[2]);
+        // added out of order, placed by 'order'
+        for (id, order) in [(third, 30), (first, 10), (second, 20)] {
+            let (status, _) = send_json(state.clone(), "PUT", &format!("/api/series/trilogy/posts/{id}"), &author,
+                                        serde_json::json!({"order": order})).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+        }
+        let series_of = |id: i64| {
+            let state = state.clone();
+            async move {
+                let (_, post) = get_request(state, &format!("/api/posts/{id}")).await;
+                serde_json::from_slice::<Value>(&post).unwrap()["series"].clone()
+            }
+        };
+        assert_eq!(series_of(first).await, serde_json::json!({"title": "Trilogy", "slug": "trilogy", "prev_post_id": null, "next_post_id": second}));
+        assert_eq!(series_of(second).await, serde_json::json!({"title": "Trilogy", "slug": "trilogy", "prev_post_id": first, "next_post_id": third}));
+        assert_eq!(series_of(third).await, serde_json::json!({"title": "Trilogy", "slug": "trilogy", "prev_post_id": second, "next_post_id": null}));
+
+        let (_, series) = get_request(state.clone(), "/api/series/trilogy").await;
+        let titles: Vec<Value> = serde_json::from_slice::<Value>(&series).unwrap()["posts"].as_array().unwrap().iter()
+            .map(|post| post["title"].clone()).collect();
+        assert_eq!(titles, ["One", "Two", "Three"]);
+
+        // only the post's author or an admin can move it, and only out of the series it's in
+        let stranger = bearer(&state, "stranger_user", Role::User).await;
+        assert_eq!(send_json(state.clone(), "DELETE", &format!("/api/series/trilogy/posts/{second}"), &stranger, Value::Null).await.0,
+                   StatusCode::FORBIDDEN);
+        send_json(state.clone(), "POST", "/api/series", &admin, serde_json::json!({"title": "Other"})).await;
+        assert_eq!(send_json(state.clone(), "DELETE", &format!("/api/series/other/posts/{second}"), &author, Value::Null).await.0,
+                   StatusCode::NOT_FOUND);
+        assert_eq!(send_json(state.clone(), "DELETE", &format!("/api/series/trilogy/posts/{second}"), &author, Value::Null).await.0,
+                   StatusCode::NO_CONTENT);
+        assert_eq!(series_of(first).await["next_post_id"], third);
+    }
+}
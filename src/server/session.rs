@@ -0,0 +1,245 @@
+// Cookie based sessions. Sessions live entirely in session_table, so AppState carries nothing extra
+// and a session survives server restarts until it expires.
+use super::{acquire_with_timeout, role_from_db, AppState, Role};
+use anyhow::Error;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{extract::{Request, State}, middleware::Next, response::Response};
+use axum_extra::{headers::Cookie, TypedHeader};
+use chrono::{TimeDelta, Utc};
+use serde::Serialize;
+use std::{fmt::Write, sync::Arc, time::Duration};
+use utoipa::ToSchema;
+
+pub(super) const SESSION_COOKIE: &str = "session";
+// sessions expire a day after login, and expired rows are swept on this interval
+pub(super) const SESSION_LIFETIME_SECS: i64 = 24 * 60 * 60;
+// sessions admins start as someone else only last long enough to reproduce a problem
+pub(super) const IMPERSONATION_LIFETIME_SECS: i64 = 5 * 60;
+const SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A row of session_table.
+pub(super) struct Session {
+    pub(super) id: String,
+    pub(super) user_id: i64,
+    pub(super) created: String,
+    pub(super) expires: String,
+    // the admin who started the session as user_id, if one did
+    pub(super) impersonated_by: Option<i64>
+}
+
+/// The user a request's session cookie belongs to. Inserted into request extensions by `auth_session`.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(super) struct CurrentUser {
+    pub(super) id: i64,
+    pub(super) username: String,
+    pub(super) role: Role,
+    /// Id of the admin acting as this user, if the session is an impersonation.
+    pub(super) impersonated_by: Option<i64>,
+    #[serde(skip)]
+    pub(super) session_id: String,
+    // None until a page first asks for it through 'csrf::generate_csrf_token'
+    #[serde(skip)]
+    pub(super) csrf_token: Option<String>
+}
+
+impl Session {
+    /// `Set-Cookie` value handing this session to the client.
+    pub(super) fn cookie(&self, state: &AppState) -> String {
+        format!("{SESSION_COOKIE}={}; HttpOnly; SameSite=Lax; Path=/; Max-Age={}{}", self.id, lifetime_secs(self.impersonated_by),
+                secure_attribute(state))
+    }
+}
+
+/// `; Secure` when the site is reached over HTTPS, either served by this process or by a proxy in front of it,
+/// so the browser never sends the session cookie in the clear.
+fn secure_attribute(state: &AppState) -> &'static str {
+    if state.tls.is_some() || state.base_url.starts_with("https://") { "; Secure" } else { "" }
+}
+
+/// How long a session lasts, depending on whether an admin started it as someone else.
+fn lifetime_secs(impersonated_by: Option<i64>) -> i64 {
+    match impersonated_by {
+        Some(_) => IMPERSONATION_LIFETIME_SECS,
+        None => SESSION_LIFETIME_SECS
+    }
+}
+
+/// `Set-Cookie` value telling the client to drop its session cookie.
+pub(super) fn expired_cookie(state: &AppState) -> String {
+    format!("{SESSION_COOKIE}=; HttpOnly; SameSite=Lax; Path=/; Max-Age=0{}", secure_attribute(state))
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub(super) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// 32 random bytes from the OS, hex encoded.
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Starts a new session for `user_id`.
+pub(super) async fn create_session(user_id: i64, state: &AppState) -> Result<Session, Error> {
+    insert_session(user_id, None, state).await
+}
+
+/// Starts a short session for `user_id` on behalf of admin `admin_id`, expiring IMPERSONATION_LIFETIME_SECS from now.
+pub(super) async fn create_impersonation_session(user_id: i64, admin_id: i64, state: &AppState) -> Result<Session, Error> {
+    insert_session(user_id, Some(admin_id), state).await
+}
+
+/// Stores a new session for `user_id`, lasting as long as its kind of session does.
+async fn insert_session(user_id: i64, impersonated_by: Option<i64>, state: &AppState) -> Result<Session, Error> {
+    let now = Utc::now();
+    let session = Session {
+        id: new_session_id(),
+        user_id,
+        created: now.to_rfc3339(),
+        expires: (now + TimeDelta::seconds(lifetime_secs(impersonated_by))).to_rfc3339(),
+        impersonated_by
+    };
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    sqlx::query!("INSERT INTO session_table (id, user_id, created, expires, impersonated_by) VALUES ($1, $2, $3, $4, $5)",
+        session.id,
+        session.user_id,
+        session.created,
+        session.expires,
+        session.impersonated_by)
+        .execute(&mut *write_conn).await?;
+    Ok(session)
+}
+
+/// Resolves an unexpired session id to the user it belongs to.
+pub(super) async fn lookup_session(session_id: &str, state: &AppState) -> Result<Option<CurrentUser>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let now = Utc::now().to_rfc3339();
+    let row = sqlx::query!(r#"SELECT user_table.id AS "id!", user_table.username, user_table.role, session_table.csrf_token, session_table.impersonated_by FROM session_table
+        JOIN user_table ON user_table.id = session_table.user_id
+        WHERE session_table.id = $1 AND session_table.expires > $2 AND user_table.deleted_at IS NULL"#,
+        session_id,
+        now)
+        .fetch_optional(&mut *read_conn).await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    Ok(Some(CurrentUser {
+        id: row.id,
+        username: row.username,
+        role: role_from_db(row.role)?,
+        impersonated_by: row.impersonated_by,
+        session_id: session_id.to_string(),
+        csrf_token: row.csrf_token
+    }))
+}
+
+/// Ends a session immediately. Returns false if there was no such session.
+pub(super) async fn expire_session(session_id: &str, state: &AppState) -> Result<bool, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let delete_statement = sqlx::query!("DELETE FROM session_table WHERE id = $1", session_id)
+        .execute(&mut *write_conn).await?;
+    Ok(delete_statement.rows_affected() == 1)
+}
+
+/// Deletes every session that has passed its expiry, returning how many were removed.
+pub(super) async fn delete_expired_sessions(state: &AppState) -> Result<u64, Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let now = Utc::now().to_rfc3339();
+    let delete_statement = sqlx::query!("DELETE FROM session_table WHERE expires <= $1", now)
+        .execute(&mut *write_conn).await?;
+    Ok(delete_statement.rows_affected())
+}
+
+/// Background task sweeping expired sessions out of session_table. Spawned once at startup.
+pub(super) async fn expire_sessions_task(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SESSION_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match delete_expired_sessions(&state).await {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!("Removed {} expired sessions", removed),
+            Err(e) => tracing::error!("Failed to remove expired sessions: {}", e)
+        }
+    }
+}
+
+/// Middleware resolving the session cookie (if any) into a `CurrentUser` request extension.
+/// Requests without a valid session pass through anonymously.
+pub(super) async fn auth_session(State(state): State<Arc<AppState>>, cookies: Option<TypedHeader<Cookie>>,
+                                 mut request: Request, next: Next) -> Response {
+    if let Some(session_id) = cookies.as_ref().and_then(|TypedHeader(cookies)| cookies.get(SESSION_COOKIE)) {
+        match lookup_session(session_id, &state).await {
+            Ok(Some(user)) => {
+                request.extensions_mut().insert(user);
+            }
+            Ok(None) => {}
+            // a broken session lookup shouldn't take the whole site down, so carry on logged out
+            Err(e) => tracing::error!("Failed to look up session: {}", e)
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::{test_app_state, test_state};
+    use crate::server::{insert_user, Role, User};
+
+    async fn user_id(state: &AppState, username: &str) -> i64 {
+        sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = $1"#, username)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cookie_secure_over_https() {
+        let session = Session { id: "cookie_session".to_string(), user_id: 1, created: String::new(), expires: String::new(), impersonated_by: None };
+        let plain = test_app_state().await;
+        assert!(!session.cookie(&plain).contains("Secure") && !expired_cookie(&plain).contains("Secure"));
+        let behind_proxy = AppState { base_url: "https://example.com/".to_string(), ..test_app_state().await };
+        assert!(session.cookie(&behind_proxy).ends_with("; Secure"));
+        assert!(expired_cookie(&behind_proxy).ends_with("; Secure"));
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() {
+        let state = test_state().await;
+        insert_user(&User::new("session_user".to_string(), Role::Mod), &State(state.clone())).await.unwrap();
+        let id = user_id(&state, "session_user").await;
+
+        let session = create_session(id, &state).await.unwrap();
+        assert_eq!(session.id.len(), 64);
+        let current = lookup_session(&session.id, &state).await.unwrap().unwrap();
+        assert_eq!((current.id, current.username.as_str(), current.role), (id, "session_user", Role::Mod));
+        assert!(lookup_session("not_a_session", &state).await.unwrap().is_none());
+
+        assert!(expire_session(&session.id, &state).await.unwrap());
+        assert!(lookup_session(&session.id, &state).await.unwrap().is_none());
+        assert!(!expire_session(&session.id, &state).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions_are_ignored_and_cleaned_up() {
+        let state = test_state().await;
+        insert_user(&User::new("stale_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        let id = user_id(&state, "stale_user").await;
+        let live = create_session(id, &state).await.unwrap();
+        let stale = create_session(id, &state).await.unwrap();
+        let expired = (Utc::now() - TimeDelta::hours(1)).to_rfc3339();
+        sqlx::query!("UPDATE session_table SET expires = $1 WHERE id = $2", expired, stale.id)
+            .execute(&state.write_pool)
+            .await
+            .unwrap();
+
+        assert!(lookup_session(&stale.id, &state).await.unwrap().is_none());
+        assert_eq!(delete_expired_sessions(&state).await.unwrap(), 1);
+        assert!(lookup_session(&live.id, &state).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,78 @@
+// Graceful shutdown. On SIGINT or SIGTERM the server stops accepting connections, gives the requests already
+// in flight SHUTDOWN_TIMEOUT_SECS to finish, and then closes the database pools before the process exits.
+use anyhow::Error;
+use std::{env, time::Duration};
+use tokio::{signal, sync::watch};
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long in-flight requests get to finish once shutdown starts: SHUTDOWN_TIMEOUT_SECS if it is set.
+pub(super) fn shutdown_timeout_from_env() -> Result<Duration, Error> {
+    let Ok(secs) = env::var("SHUTDOWN_TIMEOUT_SECS") else {
+        return Ok(DEFAULT_SHUTDOWN_TIMEOUT);
+    };
+    Ok(Duration::from_secs(secs.parse()?))
+}
+
+/// Resolves once the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let terminate = async {
+        #[cfg(unix)]
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            // still stoppable with SIGINT, so this isn't worth refusing to start over
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    };
+    tokio::select! {
+        result = signal::ctrl_c() => match result {
+            Ok(()) => tracing::info!("Received SIGINT, shutting down"),
+            Err(e) => tracing::error!("Failed to listen for SIGINT, shutting down: {}", e)
+        },
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down")
+    }
+}
+
+/// Spawns a task waiting for a shutdown signal. The returned receiver turns true once one arrives.
+pub(super) fn listen_for_shutdown() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        sender.send_replace(true);
+    });
+    receiver
+}
+
+/// Resolves once `shutdown` turns true.
+pub(super) async fn shutdown_started(mut shutdown: watch::Receiver<bool>) {
+    // an error means the sender is gone without ever signalling, which can only happen if its task panicked
+    if shutdown.wait_for(|stopping| *stopping).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_timeout_from_env() {
+        // no other test reads SHUTDOWN_TIMEOUT_SECS, so changing it here can't race with them
+        for (value, expected) in [(None, Some(DEFAULT_SHUTDOWN_TIMEOUT)), (Some("5"), Some(Duration::from_secs(5))), (Some("0"), Some(Duration::ZERO)),
+                                  (Some("-1"), None), (Some("thirty"), None)] {
+            match value {
+                Some(value) => unsafe { env::set_var("SHUTDOWN_TIMEOUT_SECS", value) },
+                None => unsafe { env::remove_var("SHUTDOWN_TIMEOUT_SECS") }
+            }
+            assert_eq!(shutdown_timeout_from_env().ok(), expected, "{value:?}");
+        }
+        unsafe { env::remove_var("SHUTDOWN_TIMEOUT_SECS") };
+    }
+}
@@ -0,0 +1,112 @@
+// XML sitemap for search engines, listing every published post and every user's profile page.
+// Built with quick-xml's writer like the feed, so the URLs are escaped properly.
+use super::{acquire_with_timeout, error::AppError, AppState};
+use anyhow::Error;
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+use quick_xml::{events::{BytesDecl, BytesText, Event}, Writer};
+use std::{io, sync::Arc};
+
+// the sitemap protocol allows at most this many URLs in one file
+const MAX_URLS: i64 = 50_000;
+const SITEMAP_NAMESPACE: &str = "http://www.sitemaps.org/schemas/sitemap/0.9";
+
+/// One `<url>` of the sitemap.
+#[derive(Debug, PartialEq)]
+struct SitemapEntry {
+    // path below base_url
+    path: String,
+    lastmod: Option<String>
+}
+
+/// GET request handler serving the sitemap.
+#[tracing::instrument(skip(state))]
+pub(super) async fn sitemap(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    let entries = get_sitemap_entries(&state).await?;
+    let xml = build_sitemap(&state.base_url, &entries).map_err(Error::from)?;
+    Ok(([(CONTENT_TYPE, "application/xml")], xml))
+}
+
+/// Renders `entries` as a sitemap `<urlset>` with every location prefixed by `base_url`.
+fn build_sitemap(base_url: &str, entries: &[SitemapEntry]) -> io::Result<String> {
+    let mut writer = Writer::new(Vec::new());
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer.create_element("urlset").with_attribute(("xmlns", SITEMAP_NAMESPACE)).write_inner_content(|writer| {
+        for entry in entries {
+            writer.create_element("url").write_inner_content(|writer| {
+                writer.create_element("loc").write_text_content(BytesText::new(&format!("{base_url}{}", entry.path)))?;
+                if let Some(lastmod) = &entry.lastmod {
+                    writer.create_element("lastmod").write_text_content(BytesText::new(lastmod))?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    })?;
+    String::from_utf8(writer.into_inner()).map_err(io::Error::other)
+}
+
+/// Published posts, newest first, then undeleted users by name, MAX_URLS at most between them.
+async fn get_sitemap_entries(state: &AppState) -> Result<Vec<SitemapEntry>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let posts = sqlx::query!(r#"SELECT slug AS "slug!", published_at AS "published_at!" FROM post_table WHERE published_at IS NOT NULL
+        ORDER BY published_at DESC, id DESC LIMIT $1"#,
+        MAX_URLS)
+        .fetch_all(&mut *read_conn).await?;
+    let remaining = MAX_URLS - posts.len() as i64;
+    let usernames = sqlx::query_scalar!("SELECT username FROM user_table WHERE deleted_at IS NULL ORDER BY username LIMIT $1", remaining)
+        .fetch_all(&mut *read_conn).await?;
+    Ok(posts.into_iter()
+        .map(|post| SitemapEntry { path: format!("posts/{}", post.slug), lastmod: Some(post.published_at) })
+        .chain(usernames.into_iter().map(|username| SitemapEntry { path: format!("user/{username}"), lastmod: None }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{feed::tests::texts_of, insert_user, tests::{call, test_state}, Role, User};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::StatusCode};
+
+    #[tokio::test]
+    async fn test_sitemap() {
+        let state = test_state().await;
+        insert_user(&User::new("site_author".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        insert_user(&User::new("gone_user".to_string(), Role::User), &State(state.clone())).await.unwrap();
+        sqlx::query!("UPDATE user_table SET deleted_at = '2025-06-03T00:00:00+00:00' WHERE username = 'gone_user'")
+            .execute(&state.write_pool).await.unwrap();
+        for day in 1..=3 {
+            let published_at = format!("2025-06-0{day}T12:00:00+00:00");
+            let slug = if day == 1 { "title".to_string() } else { format!("title-{day}") };
+            sqlx::query!("INSERT INTO post_table (title, body, created, author_id, published_at, slug)
+                         SELECT 'Title', 'text', '2025-05-01T12:00:00+00:00', id, $1, $2 FROM user_table WHERE username = 'site_author'",
+                         published_at,
+                         slug)
+                .execute(&state.write_pool).await.unwrap();
+        }
+        // drafts aren't public, so they stay out
+        sqlx::query!("INSERT INTO post_table (title, body, created, author_id, slug) SELECT 'Draft', 'tbd', '2025-06-04T12:00:00+00:00', id, 'draft'
+                     FROM user_table WHERE username = 'site_author'")
+            .execute(&state.write_pool).await.unwrap();
+
+        let response = call(state, Request::get("/sitemap.xml").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/xml");
+        let xml = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(xml.contains(r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#));
+        assert_eq!(texts_of(&xml, b"loc"), [
+            "http://0.0.0.0:3000/posts/title-3",
+            "http://0.0.0.0:3000/posts/title-2",
+            "http://0.0.0.0:3000/posts/title",
+            "http://0.0.0.0:3000/user/site_author"
+        ]);
+        assert_eq!(texts_of(&xml, b"lastmod"), ["2025-06-03T12:00:00+00:00", "2025-06-02T12:00:00+00:00", "2025-06-01T12:00:00+00:00"]);
+    }
+
+    #[test]
+    fn test_build_sitemap_escapes_locations() {
+        let entries = [SitemapEntry { path: "user/a&b".to_string(), lastmod: None }];
+        let xml = build_sitemap("https://example.com/", &entries).unwrap();
+        assert!(xml.contains("<loc>https://example.com/user/a&amp;b</loc>"));
+        assert_eq!(texts_of(&xml, b"loc"), ["https://example.com/user/a&b"]);
+    }
+}
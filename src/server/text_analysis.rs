@@ -0,0 +1,50 @@
+// Statistics about post bodies. Bodies are Markdown, so words are counted in the text pulldown-cmark finds
+// rather than the raw source, which keeps link targets, image URLs and formatting characters out of the count.
+use pulldown_cmark::{Event, Parser, TagEnd};
+
+// an average adult's silent reading speed
+const WORDS_PER_MINUTE: usize = 200;
+
+/// The number of words in Markdown `body` and how many minutes it takes to read, rounded up. Everything takes
+/// at least a minute, even an empty post.
+pub(super) fn estimate_reading_time(body: &str) -> (usize, usize) {
+    let words = word_count(body);
+    (words, words.div_ceil(WORDS_PER_MINUTE).max(1))
+}
+
+/// Counts the words in the text of Markdown `body`, code included. HTML is left out.
+fn word_count(body: &str) -> usize {
+    let mut text = String::with_capacity(body.len());
+    for event in Parser::new(body) {
+        match event {
+            // a word can be split over several text events, so they're joined before splitting on whitespace
+            Event::Text(chunk) | Event::Code(chunk) => text.push_str(&chunk),
+            Event::SoftBreak | Event::HardBreak | Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item
+                                                              | TagEnd::CodeBlock | TagEnd::TableCell) => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_reading_time() {
+        assert_eq!(estimate_reading_time(&"word ".repeat(400)), (400, 2));
+        assert_eq!(estimate_reading_time(""), (0, 1));
+        assert_eq!(estimate_reading_time(&"word ".repeat(201)), (201, 2));
+        assert_eq!(estimate_reading_time(&"word ".repeat(200)), (200, 1));
+    }
+
+    #[test]
+    fn test_markdown_is_not_counted() {
+        assert_eq!(word_count("# A title\n\nSome **bold** and _italic_ text."), 7);
+        assert_eq!(word_count("[a link](https://example.com/a/long/path) here"), 3);
+        assert_eq!(word_count("- one\n- two\n\n```\nlet x = 1;\n```"), 6);
+        assert_eq!(word_count("end of one paragraph\n\nstart of another"), 7);
+        assert_eq!(word_count("<div>markup</div>"), 0);
+    }
+}
@@ -0,0 +1,282 @@
+// Two-factor authentication with time-based one-time passwords (RFC 6238). Users turn it on for their own
+// account in two steps, enable then confirm, and from then on logging in takes a code from their authenticator app too.
+use super::{acquire_with_timeout, audit::{append_audit, AuditAction}, csrf::ValidCsrf, error::{AppError, ErrorBody}, forget_cached_user,
+            responses::json_response, session::CurrentUser, AppState};
+use anyhow::{anyhow, Error};
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::Connection;
+use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use totp_rs::{Algorithm, Secret, TOTP};
+use utoipa::ToSchema;
+
+// what authenticator apps list the account under
+const ISSUER: &str = "Personal Site";
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+// codes from one step either side of the current one are accepted too, to allow for clock drift
+const SKEW_STEPS: u8 = 1;
+
+/// A freshly generated TOTP secret, for the user to add to their authenticator app.
+#[derive(Serialize, Debug, ToSchema)]
+pub(super) struct TotpSetup {
+    /// The secret, base32 encoded, for typing in by hand.
+    secret: String,
+    /// `otpauth://` URI of the secret, usually shown as a QR code.
+    otpauth_uri: String
+}
+
+/// POST request handler generating a new TOTP secret for the caller's own account. 2FA stays off until the secret is confirmed
+/// with a code from it, so calling this again before confirming just replaces the secret.
+#[utoipa::path(post, path = "/api/users/{username}/2fa/enable", tag = "auth", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    responses(
+        (status = 200, body = TotpSetup),
+        (status = 400, description = "2FA is already enabled", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Not the caller's own account, or a missing or wrong CSRF token", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user))]
+pub(super) async fn enable(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                           Path(username): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let user = own_account(current_user, &username)?;
+    if select_totp(user.id, &state).await?.is_some_and(|(_, enabled)| enabled) {
+        return Err(AppError::BadRequest("Two-factor authentication is already enabled.".to_string()));
+    }
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let otpauth_uri = totp(&secret, &username)?.get_url();
+    store_pending_secret(user.id, &secret, &state).await?;
+    forget_cached_user(&username, &state);
+    Ok(json_response(StatusCode::OK, TotpSetup { secret, otpauth_uri }))
+}
+
+/// POST request handler turning 2FA on for the caller's own account, once they show they can produce a `code` from the secret
+/// POST /api/users/{username}/2fa/enable gave them.
+#[utoipa::path(post, path = "/api/users/{username}/2fa/confirm", tag = "auth", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "`code`, the current code from the authenticator app"),
+    responses(
+        (status = 204, description = "2FA enabled"),
+        (status = 400, description = "Wrong code, or no secret waiting to be confirmed", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Not the caller's own account, or a missing or wrong CSRF token", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user, result))]
+pub(super) async fn confirm(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                            Path(username): Path<String>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let user = own_account(current_user, &username)?;
+    let Json(json_map) = result?;
+    let code = code_field(&json_map, "code")?;
+    let Some((secret, false)) = select_totp(user.id, &state).await? else {
+        return Err(AppError::BadRequest("There is no two-factor secret waiting to be confirmed.".to_string()));
+    };
+    if !verify_code(&secret, &username, &code, unix_time()?)? {
+        return Err(AppError::BadRequest("Invalid two-factor code.".to_string()));
+    }
+    set_enabled(user.id, true, &state).await?;
+    forget_cached_user(&username, &state);
+    tracing::info!("Enabled two-factor authentication");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST request handler turning 2FA off for the caller's own account. Takes a current `code`, so a stolen session alone can't do it.
+#[utoipa::path(post, path = "/api/users/{username}/2fa/disable", tag = "auth", security(("session" = [])),
+    params(("username" = String, Path), ("x-csrf-token" = String, Header, description = "The session's CSRF token")),
+    request_body(content = Object, description = "`code`, the current code from the authenticator app"),
+    responses(
+        (status = 204, description = "2FA disabled"),
+        (status = 400, description = "Wrong code, or 2FA isn't enabled", body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Not the caller's own account, or a missing or wrong CSRF token", body = ErrorBody)
+    ))]
+#[tracing::instrument(skip(state, current_user, result))]
+pub(super) async fn disable(State(state): State<Arc<AppState>>, current_user: Option<Extension<CurrentUser>>, _csrf: ValidCsrf,
+                            Path(username): Path<String>, result: Result<Json<Value>, JsonRejection>) -> Result<impl IntoResponse, AppError> {
+    let user = own_account(current_user, &username)?;
+    let Json(json_map) = result?;
+    let code = code_field(&json_map, "code")?;
+    let Some((secret, true)) = select_totp(user.id, &state).await? else {
+        return Err(AppError::BadRequest("Two-factor authentication isn't enabled.".to_string()));
+    };
+    if !verify_code(&secret, &username, &code, unix_time()?)? {
+        return Err(AppError::BadRequest("Invalid two-factor code.".to_string()));
+    }
+    set_enabled(user.id, false, &state).await?;
+    forget_cached_user(&username, &state);
+    tracing::info!("Disabled two-factor authentication");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The second step of logging in as user `id` with `username`: passes if they have no 2FA enabled, or the
+/// login payload's `totp_code` is a current code.
+pub(super) async fn check_login(id: i64, username: &str, json_map: &Value, state: &AppState) -> Result<(), AppError> {
+    let Some((secret, true)) = select_totp(id, state).await? else {
+        return Ok(());
+    };
+    let code = code_field(json_map, "totp_code")?;
+    match verify_code(&secret, username, &code, unix_time()?)? {
+        true => Ok(()),
+        false => Err(AppError::Unauthorized)
+    }
+}
+
+/// Only lets users manage 2FA on their own account, not even admins on someone else's.
+fn own_account(current_user: Option<Extension<CurrentUser>>, username: &str) -> Result<CurrentUser, AppError> {
+    match current_user {
+        None => Err(AppError::Unauthorized),
+        Some(Extension(user)) if user.username != username => Err(AppError::Forbidden),
+        Some(Extension(user)) => Ok(user)
+    }
+}
+
+/// Reads the string field `name` holding a code out of a JSON payload.
+fn code_field(json_map: &Value, name: &str) -> Result<String, AppError> {
+    json_map.get(name).and_then(Value::as_str).map(str::to_string)
+        .ok_or(AppError::BadRequest(format!("'{name}' is required.")))
+}
+
+/// Seconds since the Unix epoch, the clock TOTP codes are computed from.
+fn unix_time() -> Result<u64, Error> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// The TOTP generator for a base32 encoded `secret`, labelled with `username`.
+fn totp(secret: &str, username: &str) -> Result<TOTP, Error> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| anyhow!("{e}"))?;
+    Ok(TOTP::new(Algorithm::SHA1, DIGITS, SKEW_STEPS, STEP_SECONDS, secret, Some(ISSUER.to_string()), username.to_string())?)
+}
+
+/// Whether `code` is valid for `secret` at Unix time `time`, give or take SKEW_STEPS steps.
+fn verify_code(secret: &str, username: &str, code: &str, time: u64) -> Result<bool, Error> {
+    Ok(totp(secret, username)?.check(code.trim(), time))
+}
+
+/// User `id`'s TOTP secret and whether it has been confirmed. None if they have never started enabling 2FA, or disabled it since.
+async fn select_totp(id: i64, state: &AppState) -> Result<Option<(String, bool)>, Error> {
+    let mut read_conn = acquire_with_timeout(&state.read_pool, "read", state.acquire_timeout).await?;
+    let row = sqlx::query!(r#"SELECT totp_secret, totp_enabled AS "totp_enabled: bool" FROM user_table WHERE id = $1"#, id)
+        .fetch_optional(&mut *read_conn).await?;
+    Ok(row.and_then(|row| row.totp_secret.map(|secret| (secret, row.totp_enabled))))
+}
+
+/// Stores a secret for user `id` that isn't used to log in until confirmed.
+async fn store_pending_secret(id: i64, secret: &str, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    sqlx::query!("UPDATE user_table SET totp_secret = $1, totp_enabled = 0 WHERE id = $2", secret, id)
+        .execute(&mut *write_conn).await?;
+    Ok(())
+}
+
+/// Turns 2FA on with the stored secret, or off and forgets the secret, auditing either way.
+async fn set_enabled(id: i64, enabled: bool, state: &AppState) -> Result<(), Error> {
+    let mut write_conn = acquire_with_timeout(&state.write_pool, "write", state.acquire_timeout).await?;
+    let mut transaction = write_conn.begin().await?;
+    // disabling clears the secret, enabling keeps the one just confirmed
+    sqlx::query!("UPDATE user_table SET totp_enabled = $1, totp_secret = CASE WHEN $1 THEN totp_secret END WHERE id = $2", enabled, id)
+        .execute(&mut *transaction).await?;
+    let detail = if enabled { "2fa enabled" } else { "2fa disabled" };
+    append_audit(&mut transaction, id, AuditAction::Updated, Some(id), Some(detail)).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{csrf::CSRF_HEADER, session::{create_session, SESSION_COOKIE}, tests::{call, csrf_token, post_json, send, session_cookie, test_state}, Role};
+    use axum::{body::{to_bytes, Body}, extract::Request, http::header::{CONTENT_TYPE, COOKIE}};
+
+    // the RFC 6238 test key, "12345678901234567890", base32 encoded
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    async fn send_code(state: Arc<AppState>, uri: &str, cookie: &str, code: &str) -> (StatusCode, Value) {
+        let request = Request::post(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .header(COOKIE, cookie)
+            .header(CSRF_HEADER, csrf_token(&state, cookie).await)
+            .body(Body::from(serde_json::json!({"code": code}).to_string()))
+            .unwrap();
+        let response = call(state, request).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn enable_2fa(state: Arc<AppState>, cookie: &str) -> (StatusCode, Value) {
+        let (status, body) = send(state, "POST", "/api/users/totp_user/2fa/enable", Some(cookie)).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn current_code(secret: &str) -> String {
+        totp(secret, "totp_user").unwrap().generate(unix_time().unwrap())
+    }
+
+    #[test]
+    fn test_verify_code() {
+        // the last six digits of the SHA1 codes listed in RFC 6238's appendix B
+        for (time, code) in [(59, "287082"), (1111111109, "081804"), (1111111111, "050471"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert!(verify_code(RFC_SECRET, "totp_user", code, time).unwrap(), "{time}");
+        }
+        // one step of drift either way is allowed, two isn't
+        assert!(verify_code(RFC_SECRET, "totp_user", "287082", 59 + STEP_SECONDS).unwrap());
+        assert!(!verify_code(RFC_SECRET, "totp_user", "287082", 59 + 2 * STEP_SECONDS).unwrap());
+        assert!(!verify_code(RFC_SECRET, "totp_user", "287083", 59).unwrap());
+        assert!(!verify_code(RFC_SECRET, "totp_user", "", 59).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_two_factor_lifecycle() {
+        let state = test_state().await;
+        post_json(state.clone(), "/api/users", serde_json::json!({"username": "totp_user", "password": "correct horse"})).await;
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM user_table WHERE username = 'totp_user'"#)
+            .fetch_one(&state.read_pool)
+            .await
+            .unwrap();
+        let cookie = format!("{SESSION_COOKIE}={}", create_session(id, &state).await.unwrap().id);
+        let login = |code: Option<&str>| {
+            let mut payload = serde_json::json!({"username": "totp_user", "password": "correct horse"});
+            if let Some(code) = code {
+                payload["totp_code"] = Value::from(code);
+            }
+            post_json(state.clone(), "/api/login", payload)
+        };
+
+        let (status, setup) = enable_2fa(state.clone(), &cookie).await;
+        assert_eq!(status, StatusCode::OK);
+        let secret = setup["secret"].as_str().unwrap().to_string();
+        assert!(setup["otpauth_uri"].as_str().unwrap().starts_with("otpauth://totp/Personal%20Site:totp_user?secret="));
+        // not enabled until confirmed
+        assert_eq!(login(None).await.0, StatusCode::OK);
+        assert_eq!(send_code(state.clone(), "/api/users/totp_user/2fa/confirm", &cookie, "000000").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send_code(state.clone(), "/api/users/totp_user/2fa/confirm", &cookie, &current_code(&secret)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(enable_2fa(state.clone(), &cookie).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(login(None).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(login(Some("000000")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(login(Some(&current_code(&secret))).await.0, StatusCode::OK);
+        // bearer tokens are issued through the same check
+        let token = post_json(state.clone(), "/api/auth/token", serde_json::json!({"username": "totp_user", "password": "correct horse"})).await;
+        assert_eq!(token.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(send_code(state.clone(), "/api/users/totp_user/2fa/disable", &cookie, "000000").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send_code(state.clone(), "/api/users/totp_user/2fa/disable", &cookie, &current_code(&secret)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(login(None).await.0, StatusCode::OK);
+        assert_eq!(select_totp(id, &state).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_two_factor_is_only_for_your_own_account() {
+        let state = test_state().await;
+        let admin = session_cookie(&state, "admin_user", Role::Admin).await;
+        session_cookie(&state, "totp_user", Role::User).await;
+        assert_eq!(enable_2fa(state.clone(), &admin).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state.clone(), "POST", "/api/users/totp_user/2fa/confirm", Some(&admin)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(state, "POST", "/api/users/totp_user/2fa/enable", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,121 @@
+// End to end tests: the real bootstrap, router and schema, served over TCP and called with reqwest.
+use checkout_webserver::server::{app, bootstrap, AppState, ServeArgs};
+use axum::{extract::Request, ServiceExt};
+use reqwest::{redirect::Policy, StatusCode};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, sync::Arc};
+
+/// Bootstraps against a fresh in-memory database and serves the full router on a free port.
+/// The server task belongs to the calling test's runtime, so it's torn down when the test ends.
+async fn spawn_test_server() -> (SocketAddr, Arc<AppState>) {
+    // SAFETY: every test sets the same value, and nothing reads it except 'bootstrap'
+    unsafe { std::env::set_var("DATABASE_URL", ":memory:") };
+    let state = bootstrap(&ServeArgs::default()).await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app(state.clone()));
+    tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+    (addr, state)
+}
+
+fn client() -> reqwest::Client {
+    // redirects are asserted on rather than followed
+    reqwest::Client::builder().redirect(Policy::none()).build().unwrap()
+}
+
+#[tokio::test]
+async fn test_create_list_and_search_users() {
+    let (addr, _state) = spawn_test_server().await;
+    let client = client();
+    let url = |path: &str| format!("http://{addr}{path}");
+
+    for username in ["alpha_user", "bravo_user", "charlie_mod"] {
+        let response = client.post(url("/api/users"))
+            .json(&json!({"username": username, "password": "hunter2_hunter2"}))
+            .send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers()["location"].to_str().unwrap().ends_with(&format!("/user/{username}")));
+    }
+    let response = client.post(url("/api/users"))
+        .json(&json!({"username": "alpha_user", "password": "hunter2_hunter2"}))
+        .send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert!(response.json::<Value>().await.unwrap()["error"].is_string());
+
+    let response = client.get(url("/api/users")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>().await.unwrap(),
+               json!({"items": ["alpha_user", "bravo_user", "charlie_mod"], "page": 1, "per_page": 32, "total": 3, "total_pages": 1}));
+
+    let response = client.get(url("/api/users/search?q=USER")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let users = response.json::<Vec<Value>>().await.unwrap();
+    assert_eq!(users.iter().map(|user| user["username"].as_str().unwrap()).collect::<Vec<_>>(), ["alpha_user", "bravo_user"]);
+    assert!(users.iter().all(|user| user["role"] == "user" && user.get("password_hash").is_none()));
+    let response = client.get(url("/api/users/search?q=x")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_unknown_paths_fall_back_to_the_index() {
+    let (addr, _state) = spawn_test_server().await;
+    let client = client();
+    for path in ["/no/such/page", "/api/nothing_here"] {
+        let response = client.get(format!("http://{addr}{path}")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER, "{path}");
+        assert_eq!(response.headers()["location"], "/");
+    }
+    let response = reqwest::get(format!("http://{addr}/no/such/page")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("<html"));
+}
+
+#[tokio::test]
+async fn test_plain_http_without_tls_configuration() {
+    // SAFETY: no test sets either variable, so removing them can't race with a reader expecting them
+    unsafe {
+        std::env::remove_var("TLS_CERT_PATH");
+        std::env::remove_var("TLS_KEY_PATH");
+    }
+    let (addr, _state) = spawn_test_server().await;
+    let response = client().get(format!("http://{addr}/health")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("strict-transport-security").is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sigterm_shuts_down_cleanly() {
+    use std::{net::TcpStream, process::Command, thread, time::{Duration, Instant}};
+
+    // a port nothing else is using, freed again for the server to take
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_Checkout_Webserver"))
+        .args(["serve", "--host", "127.0.0.1", "--port", &port.to_string()])
+        .env("DATABASE_URL", ":memory:")
+        .env_remove("TLS_CERT_PATH")
+        .env_remove("TLS_KEY_PATH")
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(30), "the server never started listening");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let killed = Command::new("kill").args(["-TERM", &server.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+    let stopping = Instant::now();
+    let status = loop {
+        if let Some(status) = server.try_wait().unwrap() {
+            break status;
+        }
+        if stopping.elapsed() > Duration::from_secs(10) {
+            server.kill().unwrap();
+            panic!("the server was still running 10 seconds after SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "{status}");
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}